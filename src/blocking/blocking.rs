@@ -0,0 +1,132 @@
+//! Synchronous wrappers for callers without an async runtime (CLI tools, sync
+//! FFI consumers). Each wrapper owns a multi-thread Tokio runtime, so relay
+//! notifications and downloads keep running in the background between calls.
+//!
+//! Every method here uses `Runtime::block_on` and therefore **must not be
+//! called from within an async context** (Tokio panics if you do). From async
+//! code use `ContentDiscovery` / `DownloadManager` directly.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use anyhow::Result;
+use nostr_sdk::{Client, Keys};
+use tokio::runtime::Runtime;
+
+use crate::discovery::fetchers::{ContentDiscovery, RelayAuthStatus};
+use crate::discovery::filters::DiscoveryFilter;
+use crate::discovery::models::NostrVideo;
+use crate::download::manager::DownloadManager;
+use crate::models::models::VideoDownload;
+use crate::service::state::{AppState, DEFAULT_TARGET_MINUTES_AHEAD, DEFAULT_TARGET_VIDEOS_AHEAD};
+
+fn build_runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+/// Blocking front-end to `ContentDiscovery`.
+pub struct BlockingContentDiscovery {
+    runtime: Runtime,
+    inner: ContentDiscovery,
+}
+
+impl BlockingContentDiscovery {
+    /// Connect to `relays` and subscribe to videos. See `ContentDiscovery::new`.
+    pub fn new(relays: Vec<String>, keys: Option<Keys>, filter: DiscoveryFilter) -> Result<Self> {
+        let runtime = build_runtime()?;
+        let inner =
+            runtime.block_on(ContentDiscovery::new(relays, Arc::new(Client::default()), keys, filter))?;
+        Ok(Self { runtime, inner })
+    }
+
+    /// Videos received since the last call.
+    pub fn fetch_new_videos(&self) -> Vec<NostrVideo> {
+        self.runtime.block_on(self.inner.fetch_new_videos())
+    }
+
+    /// Ask the relays for the latest videos now. Returns false if debounced.
+    pub fn refresh(&self) -> bool {
+        self.runtime.block_on(self.inner.refresh())
+    }
+
+    /// NIP-42 auth state per relay.
+    pub fn auth_status(&self) -> HashMap<String, RelayAuthStatus> {
+        self.runtime.block_on(self.inner.auth_status())
+    }
+}
+
+/// Blocking front-end to a `DownloadManager` and the `AppState` it drives.
+pub struct BlockingDownloadManager {
+    runtime: Runtime,
+    state: Arc<AppState>,
+    manager: Arc<DownloadManager>,
+}
+
+impl BlockingDownloadManager {
+    /// Connect to `relays` and set up a manager. Nothing is downloaded until
+    /// `start_download` is called.
+    pub fn new(
+        relays: Vec<String>,
+        keys: Option<Keys>,
+        filter: DiscoveryFilter,
+        max_parallel_downloads: usize,
+        max_storage_bytes: u64,
+    ) -> Result<Self> {
+        let runtime = build_runtime()?;
+        let content_discovery =
+            runtime.block_on(ContentDiscovery::new(relays, Arc::new(Client::default()), keys, filter))?;
+        let state = Arc::new(AppState::new(
+            content_discovery,
+            max_parallel_downloads,
+            60,
+            max_storage_bytes,
+            DEFAULT_TARGET_MINUTES_AHEAD,
+            DEFAULT_TARGET_VIDEOS_AHEAD,
+        ));
+        let manager = Arc::new(DownloadManager::new(state.clone()));
+        Ok(Self { runtime, state, manager })
+    }
+
+    /// Shared state, for reading settings or wiring up other components.
+    pub fn state(&self) -> Arc<AppState> {
+        self.state.clone()
+    }
+
+    /// Start the manager loop on the owned runtime. Returns immediately; the
+    /// loop keeps discovering and downloading in the background.
+    pub fn start_download(&self) {
+        self.runtime.spawn(self.manager.clone().run());
+    }
+
+    /// Stop a queued or running download. Returns false if it wasn't queued.
+    pub fn stop_download(&self, video_id: &str) -> bool {
+        self.runtime.block_on(self.manager.stop_download(video_id))
+    }
+
+    /// Re-run metadata extraction on a downloaded file.
+    pub fn reparse_metadata(&self, video_id: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.runtime.block_on(self.manager.reparse_metadata(video_id))
+    }
+
+    /// Snapshot of every known video.
+    pub fn discovered_videos(&self) -> Vec<VideoDownload> {
+        self.runtime
+            .block_on(self.state.discovered_videos.read())
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Move the watch position, waking the manager to re-prioritise. Returns
+    /// `false` if the active playlist has no item at `index`.
+    pub fn set_index(&self, index: usize) -> bool {
+        if !self.runtime.block_on(self.state.set_index(index)) {
+            return false;
+        }
+        self.state.manager_wake.notify_one();
+        true
+    }
+}
@@ -0,0 +1 @@
+pub mod blocking;
\ No newline at end of file
@@ -1,3 +1,4 @@
+use std::time::Duration;
 use std::sync::Arc;
 use once_cell::sync::OnceCell;
 
@@ -5,12 +6,15 @@ use flutter_rust_bridge::frb;
 use log::{info, warn};
 use tokio::sync::Mutex;
 use crate::discovery::models::NostrVideo;
+use crate::download::manager::DownloadManager;
 use crate::service::main_axum::start_axum_server;
 use crate::models::models::VideoDownload;
+use crate::service::config::Config;
 use crate::service::state::AppState;
 
 // 1) A global static for storing the Arc<AppState>
 static GLOBAL_STATE: OnceCell<Arc<AppState>> = OnceCell::new();
+static GLOBAL_MANAGER: OnceCell<Arc<DownloadManager>> = OnceCell::new();
 
 // 2) Define an FFI-safe struct that mirrors `VideoDownload`
 
@@ -29,6 +33,9 @@ pub struct FfiNostrVideo {
     pub likes: String,
     pub comments: String,
     pub url: String,
+    pub source_relays: Vec<String>,
+    pub summary: Option<String>,
+    pub published_at: Option<i64>,
 }
 #[derive(Debug, Clone)]
 pub struct FfiVideoDownload {
@@ -36,18 +43,33 @@ pub struct FfiVideoDownload {
     pub url: String,
     pub title: Option<String>,
     pub local_path: Option<String>,
+    pub favorited: bool,
     pub nostr: FfiNostrVideo
 }
 
 /// Start the Axum server and store the AppState in GLOBAL_STATE.
 /// Return the bound address as a String.
+///
+/// `secret_key` (nsec or hex) lets the client authenticate to NIP-42 relays.
+/// `download_dir` is where videos are kept (e.g. the app's files dir, which
+/// unlike the cache dir isn't cleared by the OS); `None` uses the temp dir.
 #[frb]
 pub async fn ffi_start_server(
     max_parallel_downloads: usize,
-    max_storage_bytes: u64) -> String {
-    match start_axum_server(max_parallel_downloads, max_storage_bytes).await {
-        Ok((addr, state)) => {
+    max_storage_bytes: u64,
+    secret_key: Option<String>,
+    download_dir: Option<String>) -> String {
+    let config = Config {
+        max_parallel_downloads,
+        max_storage_bytes,
+        secret_key,
+        download_dir: download_dir.map(std::path::PathBuf::from),
+        ..Config::default()
+    };
+    match start_axum_server(config).await {
+        Ok((addr, state, manager)) => {
             GLOBAL_STATE.set(state).ok();
+            GLOBAL_MANAGER.set(manager).ok();
             addr
         }
         Err(e) => format!("Error starting server: {e}"),
@@ -63,7 +85,8 @@ pub async fn ffi_get_discovered_videos() -> Vec<FfiVideoDownload> {
         .expect("Axum server not started or state not set");
 
     // Lock the discovered_videos
-    let discovered = app_state.playlist.lock().await.new_content();
+    let favorites = app_state.favorites.lock().await.clone();
+    let discovered = app_state.playlist().await.new_content();
     warn!("Discovered videos: {:?}", discovered);
 
     discovered
@@ -82,6 +105,7 @@ pub async fn ffi_get_discovered_videos() -> Vec<FfiVideoDownload> {
                 url: vid.url.clone(),
                 title: Some(vid.nostr.title.clone()),
                 local_path,
+                favorited: favorites.contains(&vid.id),
                 nostr: FfiNostrVideo{
                     id: vid.nostr.id.to_string(),
                     user: FfiUserData{
@@ -94,12 +118,117 @@ pub async fn ffi_get_discovered_videos() -> Vec<FfiVideoDownload> {
                     likes: vid.nostr.likes.clone(),
                     comments: vid.nostr.comments.clone(),
                     url: vid.nostr.url.clone(),
+                    source_relays: vid.nostr.source_relays.clone(),
+                    summary: vid.nostr.summary.clone(),
+                    published_at: vid.nostr.published_at,
                 },
             }
         })
         .collect()
 }
 
+/// Return the names of all playlists.
+#[frb]
+pub async fn ffi_list_playlists() -> Vec<String> {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.playlist_names().await
+}
+
+/// Return the name of the active playlist.
+#[frb]
+pub async fn ffi_active_playlist() -> String {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.active_playlist.lock().await.clone()
+}
+
+/// Create a new, empty playlist. Returns false if the name is taken.
+#[frb]
+pub async fn ffi_create_playlist(name: String) -> bool {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.create_playlist(&name).await
+}
+
+/// Switch the active playlist. Returns false if it doesn't exist.
+#[frb]
+pub async fn ffi_switch_playlist(name: String) -> bool {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.switch_playlist(&name).await
+}
+
+/// Fetch the latest videos from the relays now (pull-to-refresh).
+/// Returns false if debounced because a refresh just happened.
+#[frb]
+pub async fn ffi_refresh_discovery() -> bool {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.content_discovery.refresh().await
+}
+
+/// Go idle after `seconds` without user interaction; `None` disables it.
+#[frb]
+pub async fn ffi_set_idle_timeout(seconds: Option<u64>) {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    *app_state.idle_timeout.lock().await = seconds.map(Duration::from_secs);
+}
+
+/// Reconnect to relays and resume downloading (e.g. app came to the foreground).
+#[frb]
+pub async fn ffi_go_active() {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.go_active().await;
+}
+
+/// Disconnect from relays and stop starting downloads (e.g. app went to the background).
+#[frb]
+pub async fn ffi_go_idle() {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.go_idle().await;
+}
 
+/// Pin or unpin a video. Favorites are downloaded first and never evicted.
+/// Returns whether the video is now a favorite.
+#[frb]
+pub async fn ffi_toggle_favorite(id: String) -> bool {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.toggle_favorite(&id).await
+}
 
+/// Cancel every running download, clear the queue and pause downloading until
+/// resumed. Partial files are kept.
+/// Returns the ids that were stopped.
+#[frb]
+pub async fn ffi_stop_all_downloads() -> Vec<String> {
+    let manager = GLOBAL_MANAGER
+        .get()
+        .expect("Axum server not started or manager not set");
+    let stopped = manager.active_downloads().await;
+    manager.stop_all().await;
+    stopped
+}
 
+/// Save the session and active playlist (e.g. the app is about to be
+/// terminated), so playback resumes at the same place next start.
+#[frb]
+pub async fn ffi_shutdown() {
+    let app_state = GLOBAL_STATE
+        .get()
+        .expect("Axum server not started or state not set");
+    app_state.shutdown().await;
+}
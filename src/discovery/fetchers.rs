@@ -1,31 +1,112 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use nostr_sdk::{Client, Filter, FromBech32, Kind, PublicKey, RelayPoolNotification, SubscriptionId, ToBech32};
+use nostr_sdk::{Client, Event, EventId, Filter, FromBech32, Keys, Kind, PublicKey, RelayMessage, RelayPoolNotification, SubscriptionId, ToBech32};
+use serde::Serialize;
 use nostr_sdk::client::Error;
 use nostr_sdk::pool::Output;
-use tokio::sync::{mpsc::{self, UnboundedReceiver}, Mutex, MutexGuard};
+use tokio::sync::{mpsc::{self, UnboundedReceiver, UnboundedSender}, Mutex, MutexGuard, Semaphore};
 
-use crate::discovery::models::{UserData, NostrVideo};
-use crate::discovery::parsers::{parse_event_as_video, parse_user_metadata};
+use crate::discovery::filters::DiscoveryFilter;
+use crate::discovery::models::{UserData, NostrVideo, RelaySighting, VideoRemoval};
+use crate::discovery::parsers::{parse_deletion, parse_event_as_video, parse_user_metadata, DEFAULT_PREFERRED_HEIGHT};
+
+/// Minimum time between two `ContentDiscovery::refresh` calls hitting the relays.
+const REFRESH_DEBOUNCE: Duration = Duration::from_secs(10);
+/// How many recent videos a refresh asks for.
+const REFRESH_LIMIT: usize = 100;
+/// Default time an author-metadata query may wait on the relays.
+const DEFAULT_METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default number of author-metadata queries allowed in flight at once.
+const DEFAULT_METADATA_CONCURRENCY: usize = 8;
+/// Default age after which cached author metadata is fetched again.
+const DEFAULT_METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+/// Most authors kept in the metadata cache; the least recently used go first.
+const MAX_KNOWN_AUTHORS: usize = 10_000;
+/// How many event ids are remembered for dropping relay duplicates.
+const MAX_SEEN_EVENTS: usize = 50_000;
+/// How often relay connections are checked, and dropped ones reconnected.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// How many deletions are remembered for dropping videos that arrive after them.
+const MAX_DELETIONS: usize = 10_000;
+
+/// NIP-42 authentication state of a relay, as far as we can observe it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum RelayAuthStatus {
+    /// The relay sent an AUTH challenge (answered automatically if we have keys).
+    Challenged,
+    /// The relay refused us because authentication is required or insufficient.
+    Rejected(String),
+}
+
+/// Health of one relay in the pool, as reported by `ContentDiscovery::relays`.
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayStatus {
+    pub url: String,
+    /// The SDK's connection state (e.g. "Connected", "Disconnected")
+    pub status: String,
+    pub connected: bool,
+    /// NIP-42 auth state, if the relay challenged or refused us
+    pub auth: Option<RelayAuthStatus>,
+}
 
 #[derive(Debug, Clone)]
 pub struct ContentDiscovery {
     _client: Arc<Client>,
-    _video_subscription_id: SubscriptionId,
+    /// Id of the live video subscription, `None` after `unsubscribe`. Replaced
+    /// on every (re)subscribe; the background task matches events against it.
+    video_subscription_id: Arc<Mutex<Option<SubscriptionId>>>,
     video_receiver: Arc<Mutex<UnboundedReceiver<NostrVideo>>>,
+    /// Videos their authors deleted, see `fetch_removed_videos`.
+    removed_receiver: Arc<Mutex<UnboundedReceiver<VideoRemoval>>>,
+    /// Relays repeating events already handed out, see `fetch_relay_sightings`.
+    sighting_receiver: Arc<Mutex<UnboundedReceiver<RelaySighting>>>,
+    /// Turns events into videos and removals; shared with the background task and `refresh`.
+    pipeline: EventPipeline,
+    /// When `refresh` last hit the relays, for debouncing.
+    last_refresh: Arc<Mutex<Option<Instant>>>,
+    /// The resolved subscription filters; `refresh` re-queries with these.
+    filters: Arc<Mutex<Vec<Filter>>>,
 
-    /// In-memory map of "author bech32 => user metadata".
-    /// We store it so we only fetch each author’s metadata once.
-    known_authors: Arc<Mutex<HashMap<String, UserData>>>,
+    /// In-memory cache of "author bech32 => user metadata", refreshed once
+    /// entries are older than the metadata TTL.
+    known_authors: Arc<Mutex<AuthorCache>>,
+
+    /// Author metadata lookups, run off the video path.
+    author_lookup: AuthorLookup,
+    /// Author metadata that arrived after their videos were already sent.
+    author_updates: Arc<Mutex<UnboundedReceiver<UserData>>>,
+
+    /// Per-relay NIP-42 auth state, keyed by relay URL.
+    auth_status: Arc<Mutex<HashMap<String, RelayAuthStatus>>>,
+
+    /// Target height when picking one of an event's variants, see `parse_event_as_video`.
+    preferred_height: Arc<Mutex<u32>>,
+
+    /// Set by `pause`, so the heartbeat doesn't undo a deliberate disconnect.
+    paused: Arc<AtomicBool>,
 }
 
 impl ContentDiscovery {
-    /// Creates a `ContentDiscovery`, connects to given relays, subscribes to video kinds, and
+    /// Creates a `ContentDiscovery`, connects to given relays, subscribes to `filter`, and
     /// spawns a background task that automatically enriches each video with author
     /// metadata. The final `Video` (with metadata) is then queued in `video_receiver`.
-    pub async fn new(relays: Vec<String>, client: Arc<Client>) -> Result<Self, Error> {
+    ///
+    /// If `keys` are given they become the client's signer, so relays that require
+    /// NIP-42 AUTH can be answered. For a topic feed pass `DiscoveryFilter::hashtags`.
+    pub async fn new(
+        relays: Vec<String>,
+        client: Arc<Client>,
+        keys: Option<Keys>,
+        filter: DiscoveryFilter,
+    ) -> Result<Self, Error> {
+        // 1) Set a signer so the SDK can answer AUTH challenges
+        if let Some(keys) = keys {
+            client.set_signer(keys).await;
+        }
+
         // 2) Add and connect to relays
         let _cloned = client.clone();
 
@@ -34,68 +115,342 @@ impl ContentDiscovery {
         }
         client.connect().await;
 
-        // 3) Subscribe to the “video” kinds (34235 & 34236).
-        let filter = Filter::new().kinds(vec![Kind::Custom(34235), Kind::Custom(34236)]);
-        let subscription_output: Output<SubscriptionId> = client.subscribe(vec![filter], None).await?;
-        let video_subscription_id = subscription_output.val;
-
-        // 4) Set up a channel for “finished” videos
-        let (video_sender, video_receiver_) = mpsc::unbounded_channel::<NostrVideo>();
-
-        let video_receiver = Arc::new(Mutex::new(video_receiver_));
+        // 3) Subscribe to the requested videos (all of kinds 34235 & 34236 by default).
+        let filters = filter.resolve(&client).await?;
+        let subscription_output: Output<SubscriptionId> = client.subscribe(filters.clone(), None).await?;
+        let discovery = Self::with_client(client, filters, Some(subscription_output.val), filter);
 
-        // 5) Shared cache for metadata
-        let known_authors = Arc::new(Mutex::new(HashMap::new()));
-
-        // 6) Spawn a background task that:
+        // 4) Spawn a background task that:
         //    - continuously reads from `client.notifications()`
-        //    - for each “video” event, fetches the metadata (if needed),
-        //    - enriches the `Video`,
-        //    - sends it into `video_sender`.
-        let known_authors_bg = Arc::clone(&known_authors);
+        //    - for each “video” event, enriches the `Video` with cached metadata,
+        //    - sends it into `video_sender` right away,
+        //    - passes deletion requests on to `removed_receiver`,
+        //    - reports copies of known events to `sighting_receiver`,
+        //    - looks up unknown authors separately (see `fetch_author_updates`).
+        let video_subscription_id_bg = Arc::clone(&discovery.video_subscription_id);
+        let auth_status_bg = Arc::clone(&discovery.auth_status);
+        let preferred_height_bg = Arc::clone(&discovery.preferred_height);
+        let pipeline_bg = discovery.pipeline.clone();
 
-        let cloned_ = client.clone();
+        let cloned_ = discovery._client.clone();
         tokio::spawn(async move {
             let mut notifications = cloned_.notifications();
             while let Ok(notification) = notifications.recv().await {
                 match notification {
                     RelayPoolNotification::Event {
-                        relay_url: _relay_url,
+                        relay_url,
                         subscription_id,
                         event,
+                    } => {
+                        let current = video_subscription_id_bg.lock().await.clone();
+                        if current.as_ref() == Some(&subscription_id) {
+                            let preferred_height = *preferred_height_bg.lock().await;
+                            pipeline_bg
+                                .process(&event, Some(relay_url.to_string()), preferred_height)
+                                .await;
+                        }
                     }
-                    if matches!(event.kind, Kind::Custom(34235) | Kind::Custom(34236)) =>
-                        {
-                            // Parse into zero or more Videos
-                            let videos = parse_event_as_video(&event);
-                            for mut video in videos {
-                                // Pull out the npub into a separate variable so we don’t keep an immutable reference to `video`
-                                let npub_opt = video.user.npub.clone();
-
-                                if let Some(npub_str) = npub_opt {
-                                    maybe_fetch_and_set_metadata(
-                                        cloned_.clone(),
-                                        &npub_str,
-                                        &known_authors_bg,
-                                        &mut video,
-                                    ).await;
-                                }
-
-                                // Now the immutable borrow is gone, so we can safely send `video`
-                                let _ = video_sender.send(video);
+                    RelayPoolNotification::Message { relay_url, message } => {
+                        match message {
+                            RelayMessage::Auth { .. } => {
+                                auth_status_bg
+                                    .lock()
+                                    .await
+                                    .insert(relay_url.to_string(), RelayAuthStatus::Challenged);
                             }
+                            RelayMessage::Closed { message, .. }
+                                if message.starts_with("auth-required:")
+                                    || message.starts_with("restricted:") =>
+                            {
+                                tracing::warn!("Relay {} refused subscription: {}", relay_url, message);
+                                auth_status_bg
+                                    .lock()
+                                    .await
+                                    .insert(relay_url.to_string(), RelayAuthStatus::Rejected(message));
+                            }
+                            _ => {}
                         }
+                    }
                     _ => { /* ignore other events */ }
                 }
             }
         });
 
-        Ok(Self {
-            _client: client.clone(),
-            _video_subscription_id: video_subscription_id,
-            video_receiver,
+        discovery.spawn_heartbeat();
+        Ok(discovery)
+    }
+
+    /// The channels, caches and event pipeline around `client`, with nothing
+    /// spawned yet. `subscription` is the live video subscription, if any.
+    fn with_client(
+        client: Arc<Client>,
+        filters: Vec<Filter>,
+        subscription: Option<SubscriptionId>,
+        filter: DiscoveryFilter,
+    ) -> Self {
+        // Channels for “finished” videos, removals and relay sightings
+        let (video_sender, video_receiver) = mpsc::unbounded_channel::<NostrVideo>();
+        let (removed_sender, removed_receiver) = mpsc::unbounded_channel::<VideoRemoval>();
+        let (sighting_sender, sighting_receiver) = mpsc::unbounded_channel::<RelaySighting>();
+
+        // Shared cache for metadata, and the channel late metadata arrives on
+        let known_authors = Arc::new(Mutex::new(AuthorCache::new(DEFAULT_METADATA_TTL, MAX_KNOWN_AUTHORS)));
+        let (author_sender, author_receiver) = mpsc::unbounded_channel::<UserData>();
+        let author_lookup = AuthorLookup {
+            client: client.clone(),
+            known_authors: Arc::clone(&known_authors),
+            pending: Arc::new(Mutex::new(HashSet::new())),
+            timeout: Arc::new(Mutex::new(DEFAULT_METADATA_TIMEOUT)),
+            concurrency: Arc::new(Mutex::new(Arc::new(Semaphore::new(DEFAULT_METADATA_CONCURRENCY)))),
+            updates: author_sender,
+        };
+
+        let pipeline = EventPipeline {
+            author_lookup: author_lookup.clone(),
+            video_sender,
+            removed_sender,
+            sighting_sender,
+            seen_events: Arc::new(Mutex::new(SeenEvents::new(MAX_SEEN_EVENTS))),
+            deletions: Arc::new(Mutex::new(VecDeque::new())),
+            filter: Arc::new(Mutex::new(filter)),
+        };
+
+        Self {
+            _client: client,
+            video_subscription_id: Arc::new(Mutex::new(subscription)),
+            video_receiver: Arc::new(Mutex::new(video_receiver)),
+            removed_receiver: Arc::new(Mutex::new(removed_receiver)),
+            sighting_receiver: Arc::new(Mutex::new(sighting_receiver)),
+            pipeline,
+            last_refresh: Arc::new(Mutex::new(None)),
+            filters: Arc::new(Mutex::new(filters)),
             known_authors,
-        })
+            author_lookup,
+            author_updates: Arc::new(Mutex::new(author_receiver)),
+            preferred_height: Arc::new(Mutex::new(DEFAULT_PREFERRED_HEIGHT)),
+            auth_status: Arc::new(Mutex::new(HashMap::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Every `HEARTBEAT_INTERVAL`, reconnect relays whose connection dropped
+    /// (e.g. on a network switch) and, once they are back, send them the
+    /// live video subscription again.
+    fn spawn_heartbeat(&self) {
+        let client = self._client.clone();
+        let video_subscription_id = Arc::clone(&self.video_subscription_id);
+        let filters = Arc::clone(&self.filters);
+        let paused = Arc::clone(&self.paused);
+        tokio::spawn(async move {
+            let mut reconnecting: HashSet<String> = HashSet::new();
+            loop {
+                tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+                if paused.load(Ordering::Relaxed) {
+                    reconnecting.clear();
+                    continue;
+                }
+                for (url, relay) in client.relays().await {
+                    let url = url.to_string();
+                    let status = relay.status();
+                    if relay.is_connected() {
+                        if !reconnecting.remove(&url) {
+                            continue;
+                        }
+                        let id = video_subscription_id.lock().await.clone();
+                        let Some(id) = id else {
+                            continue;
+                        };
+                        let filters = filters.lock().await.clone();
+                        match client.subscribe_with_id_to([url.as_str()], id, filters, None).await {
+                            Ok(_) => tracing::info!("Relay {} reconnected; subscription restored", url),
+                            Err(e) => tracing::warn!("Relay {} reconnected but resubscribing failed: {}", url, e),
+                        }
+                    } else if matches!(
+                        status,
+                        nostr_sdk::RelayStatus::Disconnected | nostr_sdk::RelayStatus::Terminated
+                    ) {
+                        tracing::warn!("Relay {} is {}; reconnecting", url, status);
+                        reconnecting.insert(url.clone());
+                        if let Err(e) = client.connect_relay(&url).await {
+                            tracing::warn!("Reconnecting to relay {} failed: {}", url, e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// How long an author-metadata query may wait on the relays.
+    pub async fn set_metadata_timeout(&self, timeout: Duration) {
+        *self.author_lookup.timeout.lock().await = timeout;
+    }
+
+    /// How long cached author metadata is trusted before it is fetched again
+    /// (picking up changed names and avatars).
+    pub async fn set_metadata_ttl(&self, ttl: Duration) {
+        self.known_authors.lock().await.ttl = ttl;
+    }
+
+    /// Target video height when an event offers several variants (e.g. lower
+    /// on a metered connection). Applies to events received from now on.
+    pub async fn set_preferred_height(&self, preferred_height: u32) {
+        *self.preferred_height.lock().await = preferred_height;
+    }
+
+    /// How many author-metadata queries may run at once (at least one). A
+    /// burst of new authors queues up behind this instead of flooding the
+    /// relays. Lookups already waiting keep the previous limit.
+    pub async fn set_metadata_concurrency(&self, metadata_concurrency: usize) {
+        *self.author_lookup.concurrency.lock().await =
+            Arc::new(Semaphore::new(metadata_concurrency.max(1)));
+    }
+
+    /// Author metadata that arrived since the last call, for videos that were
+    /// already handed out without it.
+    pub async fn fetch_author_updates(&self) -> Vec<UserData> {
+        let mut result = Vec::new();
+        while let Ok(user) = self.author_updates.lock().await.try_recv() {
+            result.push(user);
+        }
+        result
+    }
+
+    /// Cached metadata for an author, if we have it.
+    pub async fn known_author(&self, npub: &str) -> Option<UserData> {
+        self.known_authors.lock().await.get(npub).map(|(user, _)| user)
+    }
+
+    /// Close the video subscription on the relays. No more videos arrive until
+    /// `resubscribe`.
+    pub async fn unsubscribe(&self) {
+        if let Some(id) = self.video_subscription_id.lock().await.take() {
+            self._client.unsubscribe(id).await;
+        }
+    }
+
+    /// Replace the video subscription with one for `filter`. The new
+    /// subscription is opened before the old one is closed, so nothing is
+    /// missed in between.
+    pub async fn resubscribe(&self, filter: DiscoveryFilter) -> Result<(), Error> {
+        let filters = filter.resolve(&self._client).await?;
+        let output: Output<SubscriptionId> = self._client.subscribe(filters.clone(), None).await?;
+        let previous = self.video_subscription_id.lock().await.replace(output.val);
+        *self.filters.lock().await = filters;
+        *self.pipeline.filter.lock().await = filter;
+        if let Some(id) = previous {
+            self._client.unsubscribe(id).await;
+        }
+        Ok(())
+    }
+
+    /// Pull the most recent videos from the relays right now (e.g. on
+    /// pull-to-refresh) and feed them through the normal pipeline. Calls within
+    /// `REFRESH_DEBOUNCE` of the previous one are ignored; returns whether a
+    /// refresh was started.
+    pub async fn refresh(&self) -> bool {
+        {
+            let mut last = self.last_refresh.lock().await;
+            if last.is_some_and(|at| at.elapsed() < REFRESH_DEBOUNCE) {
+                return false;
+            }
+            *last = Some(Instant::now());
+        }
+
+        let client = self._client.clone();
+        let pipeline = self.pipeline.clone();
+        let preferred_height = *self.preferred_height.lock().await;
+        let filters: Vec<Filter> = self
+            .filters
+            .lock()
+            .await
+            .iter()
+            .cloned()
+            .map(|f| f.limit(REFRESH_LIMIT))
+            .collect();
+        tokio::spawn(async move {
+            match client.fetch_events(filters, Duration::from_secs(10)).await {
+                Ok(events) => {
+                    for event in events.iter() {
+                        pipeline.process(event, None, preferred_height).await;
+                    }
+                }
+                Err(e) => tracing::warn!("Discovery refresh failed: {e}"),
+            }
+        });
+        true
+    }
+
+    /// Drop all relay connections (e.g. while the app is idle). The
+    /// subscription is kept and sent again by `resume`.
+    pub async fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        let _ = self._client.disconnect().await;
+    }
+
+    /// Reconnect to the relays after `pause`.
+    pub async fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self._client.connect().await;
+    }
+
+    /// Whether at least one relay currently has an open connection.
+    pub async fn has_connected_relay(&self) -> bool {
+        self._client
+            .relays()
+            .await
+            .values()
+            .any(|relay| relay.is_connected())
+    }
+
+    /// NIP-42 auth state of each relay that has challenged or refused us.
+    pub async fn auth_status(&self) -> HashMap<String, RelayAuthStatus> {
+        self.auth_status.lock().await.clone()
+    }
+
+    /// Add a relay at runtime, connect to it and send it the live video
+    /// subscription (same id, so the background task picks up its events).
+    pub async fn add_relay(&self, url: String) -> Result<(), Error> {
+        self._client.add_relay(&url).await?;
+        self._client.connect_relay(&url).await?;
+
+        let id = self.video_subscription_id.lock().await.clone();
+        if let Some(id) = id {
+            let filters = self.filters.lock().await.clone();
+            self._client
+                .subscribe_with_id_to([url.as_str()], id, filters, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect from and forget a relay. Its subscriptions go with it; the
+    /// other relays keep serving the live one.
+    pub async fn remove_relay(&self, url: String) -> Result<(), Error> {
+        self._client.remove_relay(&url).await?;
+        self.auth_status.lock().await.remove(&url);
+        Ok(())
+    }
+
+    /// Connection state of every relay in the pool.
+    pub async fn relays(&self) -> Vec<RelayStatus> {
+        let auth_status = self.auth_status.lock().await.clone();
+        let mut relays: Vec<RelayStatus> = self
+            ._client
+            .relays()
+            .await
+            .into_iter()
+            .map(|(url, relay)| {
+                let url = url.to_string();
+                RelayStatus {
+                    status: relay.status().to_string(),
+                    connected: relay.is_connected(),
+                    auth: auth_status.get(&url).cloned(),
+                    url,
+                }
+            })
+            .collect();
+        relays.sort_by(|a, b| a.url.cmp(&b.url));
+        relays
     }
 
     /// Fetch newly discovered “videos” that have *already* been enriched
@@ -109,53 +464,262 @@ impl ContentDiscovery {
         result
     }
 
+    /// Deletion requests received since the last call. Videos they cover
+    /// that haven't been handed out yet are dropped here already.
+    pub async fn fetch_removed_videos(&self) -> Vec<VideoRemoval> {
+        let mut result = Vec::new();
+        while let Ok(removal) = self.removed_receiver.lock().await.try_recv() {
+            result.push(removal);
+        }
+        result
+    }
+
+    /// Relays that delivered an already handed out event since the last call,
+    /// for merging into the video's `source_relays`.
+    pub async fn fetch_relay_sightings(&self) -> Vec<RelaySighting> {
+        let mut result = Vec::new();
+        while let Ok(sighting) = self.sighting_receiver.lock().await.try_recv() {
+            result.push(sighting);
+        }
+        result
+    }
+
 
 }
 
 
-/// Called by the background task to fetch metadata for a given author
-/// if we don’t already have it in `known_authors_bg`.
-/// Then we update the `video.user` field.
-async fn maybe_fetch_and_set_metadata(
-    client: Arc<Client>,
-    npub_str: &str,
-    known_authors_bg: &Arc<Mutex<HashMap<String, UserData>>>,
-    video: &mut NostrVideo,
-) {
-    // Already have user in cache?
-    let cached = {
-        let map = known_authors_bg.lock().await;
-        map.get(npub_str).cloned()
-    };
-
-    if let Some(user_data) = cached {
-        video.user = user_data;
-        return;
-    }
-
-    let pubkey = match PublicKey::from_bech32(npub_str).ok()
-
-    {
-        Some(pk) => pk,
-        None => return
-    };
-
-    // Ephemeral fetch of kind = Metadata for that author, with 10s timeout
-    let filter = Filter::new().kind(Kind::Metadata).author(pubkey);
-    if let Ok(events) = client.fetch_events(vec![filter], Duration::from_secs(10)).await {
-        // If we found something, parse user metadata
-        let user_data_map = parse_user_metadata(&events);
-        if let Ok(pubkey_bech32) = pubkey.to_bech32() {
-            if let Some(user_data) = user_data_map.get(&pubkey_bech32) {
-                // Cache it
-                let user_data_cloned = user_data.clone();
-                {
-                    let mut map = known_authors_bg.lock().await;
-                    map.insert(pubkey_bech32.clone(), user_data_cloned.clone());
+#[cfg(test)]
+impl ContentDiscovery {
+    /// Connected to no relay and subscribed to nothing, so it never yields a
+    /// video; for tests that only need an `AppState` around them.
+    pub(crate) fn offline() -> Self {
+        Self::with_client(Arc::new(Client::default()), Vec::new(), None, DiscoveryFilter::default())
+    }
+}
+
+
+/// Everything an incoming event is handled with: dedup, author lookups and
+/// the channels videos and removals are handed out on.
+#[derive(Debug, Clone)]
+struct EventPipeline {
+    author_lookup: AuthorLookup,
+    /// Feeds `ContentDiscovery::video_receiver`
+    video_sender: UnboundedSender<NostrVideo>,
+    /// Feeds `ContentDiscovery::removed_receiver`
+    removed_sender: UnboundedSender<VideoRemoval>,
+    /// Feeds `ContentDiscovery::sighting_receiver`
+    sighting_sender: UnboundedSender<RelaySighting>,
+    /// Events already handled, so copies arriving from other relays skip the
+    /// parsing and author lookups
+    seen_events: Arc<Mutex<SeenEvents>>,
+    /// Recent deletions, so a deleted video arriving late is never emitted
+    deletions: Arc<Mutex<VecDeque<VideoRemoval>>>,
+    /// The current feed, for dropping videos relays sent despite the filter
+    filter: Arc<Mutex<DiscoveryFilter>>,
+}
+
+impl EventPipeline {
+    /// Handle one event from the video subscription. `relay_url` is the
+    /// delivering relay, if known.
+    async fn process(&self, event: &Event, relay_url: Option<String>, preferred_height: u32) {
+        // The same event arrives once per relay; only the first copy is
+        // parsed, later ones just add their relay.
+        if !self.seen_events.lock().await.insert(event.id) {
+            if let Some(relay_url) = relay_url {
+                if event.kind != Kind::EventDeletion {
+                    let _ = self.sighting_sender.send(RelaySighting { event_id: event.id.to_hex(), relay_url });
+                }
+            }
+            return;
+        }
+        if event.kind == Kind::EventDeletion {
+            self.process_deletion(event).await;
+        } else {
+            self.process_video(event, relay_url, preferred_height).await;
+        }
+    }
+
+    /// Remember a deletion and pass it on to the manager.
+    async fn process_deletion(&self, event: &Event) {
+        let Some(removal) = parse_deletion(event) else {
+            return;
+        };
+        let mut deletions = self.deletions.lock().await;
+        deletions.push_back(removal.clone());
+        if deletions.len() > MAX_DELETIONS {
+            deletions.pop_front();
+        }
+        drop(deletions);
+        let _ = self.removed_sender.send(removal);
+    }
+
+    /// Turn one video event into a `NostrVideo`, fill in cached author metadata and
+    /// queue it on `video_sender`. Unknown authors are looked up in the background
+    /// rather than holding the video back.
+    async fn process_video(&self, event: &Event, relay_url: Option<String>, preferred_height: u32) {
+        // Parse into at most one Video (the preferred variant)
+        let Some(mut video) = parse_event_as_video(event, preferred_height) else {
+            return;
+        };
+        if !self.filter.lock().await.accepts(&video) {
+            return;
+        }
+        if self.deletions.lock().await.iter().any(|removal| removal.matches(&video)) {
+            return;
+        }
+        if let Some(relay_url) = &relay_url {
+            video.source_relays.push(relay_url.clone());
+        }
+
+        if let Some(npub_str) = video.user.npub.clone() {
+            let cached = self.author_lookup.known_authors.lock().await.get(&npub_str);
+            match cached {
+                Some((user_data, fresh)) => {
+                    video.user = user_data;
+                    // Stale entries are still used; the refreshed data follows as an update.
+                    if !fresh {
+                        self.author_lookup.spawn_fetch(npub_str);
+                    }
                 }
-                // Update the video
-                video.user = user_data_cloned;
+                None => self.author_lookup.spawn_fetch(npub_str),
+            }
+        }
+
+        let _ = self.video_sender.send(video);
+    }
+}
+
+/// Ids of recently handled events, oldest forgotten first once `capacity` is reached.
+#[derive(Debug)]
+struct SeenEvents {
+    ids: HashSet<EventId>,
+    order: VecDeque<EventId>,
+    capacity: usize,
+}
+
+impl SeenEvents {
+    fn new(capacity: usize) -> Self {
+        Self { ids: HashSet::new(), order: VecDeque::new(), capacity }
+    }
+
+    /// Record `id`; returns false if it was already seen.
+    fn insert(&mut self, id: EventId) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
             }
         }
+        true
+    }
+}
+
+/// Author metadata with the time it was fetched and last used.
+#[derive(Debug)]
+struct CachedAuthor {
+    user: UserData,
+    fetched_at: Instant,
+    last_used: Instant,
+}
+
+/// Author metadata cache with a TTL and a size cap (least recently used
+/// entries are evicted first).
+#[derive(Debug)]
+struct AuthorCache {
+    entries: HashMap<String, CachedAuthor>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl AuthorCache {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { entries: HashMap::new(), ttl, capacity }
+    }
+
+    /// Cached metadata for `npub` and whether it is still within the TTL.
+    fn get(&mut self, npub: &str) -> Option<(UserData, bool)> {
+        let entry = self.entries.get_mut(npub)?;
+        entry.last_used = Instant::now();
+        Some((entry.user.clone(), entry.fetched_at.elapsed() < self.ttl))
+    }
+
+    fn insert(&mut self, npub: String, user: UserData) {
+        let now = Instant::now();
+        self.entries.insert(npub, CachedAuthor { user, fetched_at: now, last_used: now });
+        while self.entries.len() > self.capacity {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(npub, _)| npub.clone())
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
     }
-}
\ No newline at end of file
+
+    /// Treat `npub`'s entry as fresh again without new data, so a failed
+    /// refresh isn't retried on every video until the next TTL.
+    fn touch(&mut self, npub: &str) {
+        if let Some(entry) = self.entries.get_mut(npub) {
+            entry.fetched_at = Instant::now();
+        }
+    }
+}
+
+/// Author metadata lookups, kept off the video path.
+#[derive(Debug, Clone)]
+struct AuthorLookup {
+    client: Arc<Client>,
+    known_authors: Arc<Mutex<AuthorCache>>,
+    /// Authors with a lookup in progress, so each is only queried once at a time
+    pending: Arc<Mutex<HashSet<String>>>,
+    timeout: Arc<Mutex<Duration>>,
+    /// Bounds concurrent lookups, see `ContentDiscovery::set_metadata_concurrency`
+    concurrency: Arc<Mutex<Arc<Semaphore>>>,
+    /// Where found metadata is announced, see `ContentDiscovery::fetch_author_updates`
+    updates: UnboundedSender<UserData>,
+}
+
+impl AuthorLookup {
+    /// Fetch `npub_str`'s metadata in the background, cache it and announce it
+    /// on `updates`. Also used to refresh stale entries.
+    fn spawn_fetch(&self, npub_str: String) {
+        let lookup = self.clone();
+        tokio::spawn(async move {
+            if !lookup.pending.lock().await.insert(npub_str.clone()) {
+                return;
+            }
+            let semaphore = lookup.concurrency.lock().await.clone();
+            let _permit = semaphore.acquire_owned().await;
+            match lookup.fetch(&npub_str).await {
+                Some(user_data) => {
+                    lookup
+                        .known_authors
+                        .lock()
+                        .await
+                        .insert(npub_str.clone(), user_data.clone());
+                    let _ = lookup.updates.send(user_data);
+                }
+                None => lookup.known_authors.lock().await.touch(&npub_str),
+            }
+            lookup.pending.lock().await.remove(&npub_str);
+        });
+    }
+
+    /// Ephemeral fetch of kind = Metadata for one author.
+    async fn fetch(&self, npub_str: &str) -> Option<UserData> {
+        let pubkey = PublicKey::from_bech32(npub_str).ok()?;
+        let timeout = *self.timeout.lock().await;
+        let filter = Filter::new().kind(Kind::Metadata).author(pubkey);
+        let events = self.client.fetch_events(vec![filter], timeout).await.ok()?;
+        // If we found something, parse user metadata
+        let user_data_map = parse_user_metadata(&events);
+        let pubkey_bech32 = pubkey.to_bech32().ok()?;
+        user_data_map.get(&pubkey_bech32).cloned()
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use nostr_sdk::{Alphabet, Client, Filter, Keys, Kind, PublicKey, SingleLetterTag};
+use nostr_sdk::client::Error;
+use tracing::warn;
+
+use crate::discovery::models::NostrVideo;
+
+/// The NIP-71 video kinds (normal and short-form).
+pub const VIDEO_KINDS: [Kind; 2] = [Kind::Custom(34235), Kind::Custom(34236)];
+
+/// What `ContentDiscovery` subscribes to. Resolved into concrete `Filter`s once
+/// the relays are connected, since `Following` has to look up a contact list.
+/// Whatever the filter, only events carrying a usable imeta video end up as videos.
+/// Deletions of video events (kind 5 with a video `k` tag) are always included.
+#[derive(Debug, Clone)]
+pub enum DiscoveryFilter {
+    /// Every video event (the default).
+    Videos,
+    /// Video events tagged with any of these hashtags (`#t`, without the `#`).
+    /// Relays are asked for matching events only, and whatever they send anyway
+    /// is dropped by `accepts`, so each relay added contributes just its share
+    /// of these topics rather than its whole video firehose. An empty list
+    /// matches every video.
+    Hashtags(Vec<String>),
+    /// Video events from the authors in this user's kind-3 contact list.
+    Following(PublicKey),
+    /// Caller-supplied filters, e.g. for additional custom kinds.
+    Custom(Vec<Filter>),
+}
+
+impl Default for DiscoveryFilter {
+    fn default() -> Self {
+        Self::Videos
+    }
+}
+
+impl DiscoveryFilter {
+    /// Videos from the accounts `keys` follows.
+    pub fn following(keys: &Keys) -> Self {
+        Self::Following(keys.public_key())
+    }
+
+    /// Videos tagged with any of `tags`.
+    pub fn hashtags(tags: Vec<String>) -> Self {
+        Self::Hashtags(tags.into_iter().map(|t| t.trim_start_matches('#').to_lowercase()).collect())
+    }
+
+    /// Turn this into subscription filters, fetching the contact list if needed.
+    pub async fn resolve(&self, client: &Client) -> Result<Vec<Filter>, Error> {
+        let videos = Filter::new().kinds(VIDEO_KINDS);
+        let mut filters = match self {
+            Self::Videos => vec![videos],
+            Self::Hashtags(tags) if tags.is_empty() => vec![videos],
+            Self::Hashtags(tags) => vec![videos.hashtags(tags.clone())],
+            Self::Following(public_key) => {
+                let follows = fetch_follows(client, *public_key).await?;
+                if follows.is_empty() {
+                    // An empty author list would match everyone; match nobody instead.
+                    warn!("Contact list of {} is empty; following feed will be empty", public_key);
+                    vec![videos.author(*public_key)]
+                } else {
+                    vec![videos.authors(follows)]
+                }
+            }
+            Self::Custom(filters) => filters.clone(),
+        };
+        filters.push(deletions());
+        Ok(filters)
+    }
+
+    /// Whether a parsed video belongs in this feed. Relays don't always honour
+    /// tag filters, so topic feeds are checked again here.
+    pub fn accepts(&self, video: &NostrVideo) -> bool {
+        match self {
+            Self::Hashtags(tags) if !tags.is_empty() => {
+                video.hashtags.iter().any(|t| tags.contains(t))
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Deletion requests (NIP-09) that say they target a video kind.
+fn deletions() -> Filter {
+    Filter::new().kind(Kind::EventDeletion).custom_tag(
+        SingleLetterTag::lowercase(Alphabet::K),
+        VIDEO_KINDS.map(|kind| kind.as_u16().to_string()),
+    )
+}
+
+/// Public keys in the newest kind-3 contact list of `public_key`.
+async fn fetch_follows(client: &Client, public_key: PublicKey) -> Result<Vec<PublicKey>, Error> {
+    let filter = Filter::new().kind(Kind::ContactList).author(public_key);
+    let events = client.fetch_events(vec![filter], Duration::from_secs(10)).await?;
+    let Some(contact_list) = events.iter().max_by_key(|e| e.created_at) else {
+        return Ok(Vec::new());
+    };
+    Ok(contact_list
+        .tags
+        .iter()
+        .filter_map(|tag| match tag.as_slice() {
+            [name, hex, ..] if name == "p" => PublicKey::from_hex(hex).ok(),
+            _ => None,
+        })
+        .collect())
+}
@@ -1,3 +1,4 @@
 pub mod parsers;
 pub mod fetchers;
+pub mod filters;
 pub mod models;
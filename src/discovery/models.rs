@@ -4,12 +4,74 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NostrVideo {
     pub id: String,
+    /// Id of the Nostr event this came from; variants of one event share it
+    #[serde(default)]
+    pub event_id: String,
     pub user: UserData,
     pub title: String,
     pub song_name: String,
     pub likes: String,
     pub comments: String,
     pub url: String,
+    /// Width/Height from the imeta `dim` field, if present
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Relays this video was received from
+    pub source_relays: Vec<String>,
+    /// Media host named by the imeta `service` field, selects a download `HostAdapter`
+    #[serde(default)]
+    pub service: Option<String>,
+    /// Alternate URLs from the imeta `fallback` fields
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// MIME type from the imeta `m` field, e.g. "video/webm"
+    #[serde(default)]
+    pub mime_type: Option<String>,
+    /// NIP-71 `summary` tag
+    #[serde(default)]
+    pub summary: Option<String>,
+    /// Unix seconds from the NIP-71 `published_at` tag, else the event's `created_at`
+    #[serde(default)]
+    pub published_at: Option<i64>,
+    /// Seconds, from the imeta `duration` field or the event's `duration` tag
+    #[serde(default)]
+    pub duration: Option<f64>,
+    /// Lowercased `t` tags (topics), without the `#`
+    #[serde(default)]
+    pub hashtags: Vec<String>,
+    /// `kind:pubkey:d` coordinate of the (addressable) event, if it has a `d` tag
+    #[serde(default)]
+    pub address: Option<String>,
+}
+
+/// A NIP-09 deletion request: the author wants these events gone.
+#[derive(Debug, Clone)]
+pub struct VideoRemoval {
+    /// npub of the deletion's author; only their own events are affected
+    pub author: Option<String>,
+    /// Hex ids from the `e` tags
+    pub event_ids: Vec<String>,
+    /// Coordinates from the `a` tags (already limited to the author's own)
+    pub addresses: Vec<String>,
+}
+
+impl VideoRemoval {
+    /// Whether this deletion covers `video`.
+    pub fn matches(&self, video: &NostrVideo) -> bool {
+        if self.author.is_none() || video.user.npub != self.author {
+            return false;
+        }
+        self.event_ids.contains(&video.event_id)
+            || video.address.as_ref().is_some_and(|a| self.addresses.contains(a))
+    }
+}
+
+/// Another relay delivered an event that was already handed out as a video.
+#[derive(Debug, Clone)]
+pub struct RelaySighting {
+    /// Hex id of the event, matches `NostrVideo::event_id`
+    pub event_id: String,
+    pub relay_url: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,4 +91,5 @@ pub struct VideoVariant {
     pub images: Vec<String>,
     pub fallbacks: Vec<String>,
     pub service: Option<String>,
+    pub duration: Option<f64>,
 }
@@ -2,37 +2,127 @@ use std::collections::HashMap;
 use nostr_sdk::prelude::*;
 use url::Url;
 
-use crate::discovery::models::{UserData, NostrVideo, VideoVariant};
+use crate::discovery::models::{UserData, NostrVideo, VideoRemoval, VideoVariant};
+
+/// Default target for `parse_event_as_video`'s variant choice.
+pub const DEFAULT_PREFERRED_HEIGHT: u32 = 720;
 
 /// A module containing all parsing-related code.
 /// We could also structure it as a struct with methods, but here's a simple approach.
-pub fn parse_event_as_video(event: &Event) -> Vec<NostrVideo> {
+///
+/// An event can carry several `imeta` variants (480p, 1080p, ...) of the same
+/// video. One `NostrVideo` is made per event, from the variant whose `dim`
+/// height is closest to `preferred_height` (the smaller on a tie, variants
+/// without `dim` last); the other variants' URLs become its fallbacks.
+/// Variants whose `m` names something other than a video (a poster image, an
+/// HLS playlist) are skipped.
+pub fn parse_event_as_video(event: &Event, preferred_height: u32) -> Option<NostrVideo> {
     // 1) Gather all video variants from the event tags
     let video_variants = parse_video_variants(event);
+    let summary = tag_value(event, "summary");
+    let published_at = tag_value(event, "published_at")
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .unwrap_or(event.created_at.as_u64() as i64);
+    let event_duration = tag_value(event, "duration").and_then(|d| parse_duration(&d));
+    let address = tag_value(event, "d")
+        .map(|d| format!("{}:{}:{}", event.kind.as_u16(), event.pubkey.to_hex(), d));
 
-    // 2) Filter them to only valid (hash + URL) combos and build `Video`.
-    let mut videos = Vec::new();
-    for variant in video_variants {
-        if let (Some(hash), Some(url)) = (&variant.hash, &variant.url) {
-            if is_valid_http_url(url) {
-                let user_npub = event.pubkey.to_bech32().ok();
-                videos.push(NostrVideo {
-                    id: hash.clone(),
-                    user: UserData {
-                        npub: user_npub,
-                        name: None,
-                        profile_picture: None,
-                    },
-                    title: variant.title.clone().unwrap_or_default(),
-                    song_name: "Unknown".to_string(),
-                    comments: "".to_string(),
-                    likes: "".to_string(),
-                    url: url.clone(),
-                });
+    // 2) Keep only valid (hash + URL) combos and pick the preferred one.
+    let mut candidates: Vec<(VideoVariant, Option<(u32, u32)>)> = video_variants
+        .into_iter()
+        .filter(|v| v.hash.is_some() && v.url.as_deref().is_some_and(is_valid_http_url))
+        .filter(|v| v.mime_type.as_deref().map_or(true, |m| m.starts_with("video/")))
+        .map(|v| {
+            let dims = v.resolution.as_deref().and_then(parse_dimensions);
+            (v, dims)
+        })
+        .collect();
+    candidates.sort_by_key(|(_, dims)| match dims {
+        Some((_, h)) => (0, h.abs_diff(preferred_height), *h),
+        None => (1, 0, 0),
+    });
+    let mut candidates = candidates.into_iter();
+    let (variant, dims) = candidates.next()?;
+    let (Some(hash), Some(url)) = (variant.hash.clone(), variant.url.clone()) else {
+        return None;
+    };
+
+    // 3) Mirrors of this variant first, then the other variants.
+    let mut fallbacks: Vec<String> = Vec::new();
+    let others = candidates.flat_map(|(other, _)| other.url.into_iter().chain(other.fallbacks));
+    for fallback in variant.fallbacks.iter().cloned().chain(others) {
+        if fallback != url && is_valid_http_url(&fallback) && !fallbacks.contains(&fallback) {
+            fallbacks.push(fallback);
+        }
+    }
+
+    let (width, height) = dims.map_or((None, None), |(w, h)| (Some(w), Some(h)));
+    Some(NostrVideo {
+        id: hash,
+        event_id: event.id.to_hex(),
+        user: UserData {
+            npub: event.pubkey.to_bech32().ok(),
+            name: None,
+            profile_picture: None,
+        },
+        title: variant.title.clone().unwrap_or_default(),
+        song_name: "Unknown".to_string(),
+        comments: "".to_string(),
+        likes: "".to_string(),
+        url,
+        width,
+        height,
+        source_relays: Vec::new(),
+        service: variant.service.clone(),
+        fallbacks,
+        mime_type: variant.mime_type.clone(),
+        summary,
+        published_at: Some(published_at),
+        duration: variant.duration.or(event_duration),
+        hashtags: parse_hashtags(event),
+        address,
+    })
+}
+
+/// The event's `t` tags, lowercased and without a leading `#`.
+pub fn parse_hashtags(event: &Event) -> Vec<String> {
+    event
+        .tags
+        .iter()
+        .filter_map(|tag| match tag.as_slice() {
+            [key, value, ..] if key == "t" => {
+                Some(value.trim().trim_start_matches('#').to_lowercase())
+            }
+            _ => None,
+        })
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Parse a kind-5 deletion event (NIP-09). `a` tags naming another author's
+/// events are ignored, since only the author may delete them. Returns `None`
+/// if nothing is referenced.
+pub fn parse_deletion(event: &Event) -> Option<VideoRemoval> {
+    let own_prefix = format!(":{}:", event.pubkey.to_hex());
+    let mut event_ids = Vec::new();
+    let mut addresses = Vec::new();
+    for tag in event.tags.iter() {
+        match tag.as_slice() {
+            [key, id, ..] if key == "e" => event_ids.push(id.clone()),
+            [key, address, ..] if key == "a" && address.contains(&own_prefix) => {
+                addresses.push(address.clone())
             }
+            _ => {}
         }
     }
-    videos
+    if event_ids.is_empty() && addresses.is_empty() {
+        return None;
+    }
+    Some(VideoRemoval {
+        author: event.pubkey.to_bech32().ok(),
+        event_ids,
+        addresses,
+    })
 }
 
 
@@ -49,15 +139,19 @@ pub fn parse_video_variants(event: &Event) -> Vec<VideoVariant> {
         if !slices.is_empty() && slices[0] == "imeta" {
             let mut fields: HashMap<String, Vec<String>> = HashMap::new();
 
-            // Skip the first item ("imeta"), and parse the rest
+            // Skip the first item ("imeta"). Each remaining element is one
+            // "key value" pair: split on the first space only, so values keep
+            // their own spaces (titles) and repeated keys (image, fallback)
+            // each add a value.
             for chunk in slices.iter().skip(1) {
-                let parts: Vec<&str> = chunk.split_whitespace().collect();
-                if parts.is_empty() {
+                let Some((key, value)) = chunk.trim_start().split_once(' ') else {
+                    continue;
+                };
+                let value = value.trim();
+                if key.is_empty() || value.is_empty() {
                     continue;
                 }
-                let key = parts[0].trim().to_string();
-                let value = parts[1..].join(" ").trim().to_string();
-                fields.entry(key).or_default().push(value);
+                fields.entry(key.to_string()).or_default().push(value.to_string());
             }
 
             // Extract fields
@@ -69,6 +163,7 @@ pub fn parse_video_variants(event: &Event) -> Vec<VideoVariant> {
             let service   = fields.get("service").and_then(|v| v.first()).cloned();
             let images    = fields.get("image").cloned().unwrap_or_default();
             let fallbacks = fields.get("fallback").cloned().unwrap_or_default();
+            let duration  = fields.get("duration").and_then(|v| v.first()).and_then(|d| parse_duration(d));
 
             variants.push(VideoVariant {
                 title,
@@ -79,6 +174,7 @@ pub fn parse_video_variants(event: &Event) -> Vec<VideoVariant> {
                 images,
                 fallbacks,
                 service,
+                duration,
             });
         }
     }
@@ -109,6 +205,26 @@ pub fn parse_user_metadata(metadata_events: &Events) -> HashMap<String, UserData
     map
 }
 
+/// Value of the first `[name, value, ..]` tag on the event.
+fn tag_value(event: &Event, name: &str) -> Option<String> {
+    event.tags.iter().find_map(|tag| match tag.as_slice() {
+        [key, value, ..] if key == name => Some(value.clone()),
+        _ => None,
+    })
+}
+
+/// Parse an imeta `dim` value of the form "WxH" (e.g. "1920x1080").
+pub fn parse_dimensions(dim: &str) -> Option<(u32, u32)> {
+    let (w, h) = dim.trim().split_once('x')?;
+    Some((w.trim().parse().ok()?, h.trim().parse().ok()?))
+}
+
+/// Parse a duration in (possibly fractional) seconds; rejects non-positive
+/// and non-finite values.
+fn parse_duration(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|d| d.is_finite() && *d > 0.0)
+}
+
 pub fn is_valid_http_url(url: &str) -> bool {
     if let Ok(parsed) = Url::parse(url) {
         let scheme = parsed.scheme();
@@ -118,3 +234,81 @@ pub fn is_valid_http_url(url: &str) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(imetas: &[&[&str]]) -> Event {
+        let tags = imetas.iter().map(|fields| {
+            Tag::parse(std::iter::once("imeta").chain(fields.iter().copied())).unwrap()
+        });
+        EventBuilder::new(Kind::from(21), "")
+            .tags(tags)
+            .sign_with_keys(&Keys::generate())
+            .unwrap()
+    }
+
+    const SD: &[&str] = &["url https://example.com/480.mp4", "x aaa", "dim 854x480", "m video/mp4"];
+    const HD: &[&str] = &["url https://example.com/720.mp4", "x bbb", "dim 1280x720", "m video/mp4"];
+    const FHD: &[&str] = &["url https://example.com/1080.mp4", "x ccc", "dim 1920x1080", "m video/mp4"];
+
+    #[test]
+    fn picks_variant_closest_to_preferred_height() {
+        let event = event(&[SD, HD, FHD]);
+
+        let video = parse_event_as_video(&event, 1080).unwrap();
+        assert_eq!(video.id, "ccc");
+        assert_eq!((video.width, video.height), (Some(1920), Some(1080)));
+        assert_eq!(video.fallbacks, ["https://example.com/720.mp4", "https://example.com/480.mp4"]);
+
+        assert_eq!(parse_event_as_video(&event, 500).unwrap().id, "aaa");
+        assert_eq!(parse_event_as_video(&event, 720).unwrap().id, "bbb");
+        // A tie goes to the smaller one.
+        assert_eq!(parse_event_as_video(&event, 900).unwrap().id, "bbb");
+    }
+
+    #[test]
+    fn variant_without_dim_goes_last() {
+        let no_dim: &[&str] = &["url https://example.com/any.mp4", "x ddd"];
+        let video = parse_event_as_video(&event(&[no_dim, SD]), 1080).unwrap();
+        assert_eq!(video.id, "aaa");
+
+        let video = parse_event_as_video(&event(&[no_dim]), 1080).unwrap();
+        assert_eq!(video.id, "ddd");
+        assert_eq!((video.width, video.height), (None, None));
+    }
+
+    #[test]
+    fn variant_without_url_is_skipped() {
+        let no_url: &[&str] = &["x eee", "dim 1920x1080"];
+        assert_eq!(parse_event_as_video(&event(&[no_url, SD]), 1080).unwrap().id, "aaa");
+        assert!(parse_event_as_video(&event(&[no_url]), 1080).is_none());
+
+        let bad_url: &[&str] = &["url ftp://example.com/a.mp4", "x fff"];
+        assert!(parse_event_as_video(&event(&[bad_url]), 1080).is_none());
+    }
+
+    #[test]
+    fn non_video_variant_is_skipped() {
+        let poster: &[&str] = &["url https://example.com/poster.jpg", "x ggg", "dim 1920x1080", "m image/jpeg"];
+        let video = parse_event_as_video(&event(&[poster, SD]), 1080).unwrap();
+        assert_eq!(video.id, "aaa");
+        assert!(video.fallbacks.is_empty());
+        assert!(parse_event_as_video(&event(&[poster]), 1080).is_none());
+    }
+
+    #[test]
+    fn imeta_values_keep_their_spaces() {
+        let fields: &[&str] = &[
+            "url https://example.com/a.mp4",
+            "title A title  with spaces",
+            "image https://example.com/1.jpg",
+            "image https://example.com/2.jpg",
+        ];
+        let variants = parse_video_variants(&event(&[fields]));
+        assert_eq!(variants.len(), 1);
+        assert_eq!(variants[0].title.as_deref(), Some("A title  with spaces"));
+        assert_eq!(variants[0].images, ["https://example.com/1.jpg", "https://example.com/2.jpg"]);
+    }
+}
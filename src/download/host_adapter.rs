@@ -0,0 +1,24 @@
+use std::fmt::Debug;
+
+use reqwest::header::HeaderMap;
+
+/// Per-service quirks applied right before a download starts, selected by the
+/// imeta `service` field (e.g. a CDN that wants a `Referer`, or a host whose
+/// URLs need rewriting to a direct-download form).
+pub trait HostAdapter: Send + Sync + Debug {
+    /// URL to actually fetch for `url`.
+    fn rewrite_url(&self, url: &str) -> String {
+        url.to_string()
+    }
+
+    /// Extra headers to send with the request for `url` (the rewritten one).
+    fn headers(&self, _url: &str) -> HeaderMap {
+        HeaderMap::new()
+    }
+}
+
+/// Adapter used when no service-specific one is registered: fetches the URL as is.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopHostAdapter;
+
+impl HostAdapter for NoopHostAdapter {}
@@ -1,18 +1,30 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::fs::{remove_file, File};
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
+use bytes::Bytes;
 use futures::stream::{self, StreamExt};
 use uuid::Uuid;
-use tracing::{debug, error, info, warn};
-use reqwest::header::CONTENT_LENGTH;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, RANGE};
+use reqwest::StatusCode;
 
 use mp4parse::{read_mp4, Error as Mp4Error, TrackType};
-use crate::models::models::VideoDownload;
-use crate::service::state::AppState;
+use crate::discovery::models::UserData;
+use crate::download::host_adapter::{HostAdapter, NoopHostAdapter};
+use crate::models::models::{mime_for_extension, StateEvent, VideoDownload};
+use crate::service::snapshot::QueueSnapshot;
+use crate::service::state::{AppState, DEFAULT_PLAYLIST};
+use crate::storage::store::VideoWriter;
+use crate::storage::thumbnails;
+use crate::utils::frames;
+use crate::utils::utils::write_image;
 
 
 /// A simple struct that holds the final MP4 metadata for demonstration.
@@ -27,6 +39,68 @@ pub struct VideoMetadata {
 // Download Manager
 // ===========================
 
+/// Loop interval while downloads are running or the queue is changing.
+const ACTIVE_LOOP_INTERVAL: Duration = Duration::from_millis(200);
+/// Loop interval when nothing is happening.
+const IDLE_LOOP_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the session snapshot is written.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+/// Backoff before the first retry of a failed download; doubles per retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Minimum growth of the parse buffer between two attempts to read the MP4
+/// metadata from it mid-download.
+const PARSE_RETRY_BYTES: usize = 256 * 1024;
+/// Minimum time between two progress events for the same download.
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A download failure on the host's or network's side, worth retrying.
+#[derive(Debug)]
+enum TransientError {
+    Status(StatusCode),
+    Stalled(Duration),
+    /// The body ended short of (or past) its Content-Length.
+    Truncated { expected: u64, received: u64 },
+}
+
+impl std::fmt::Display for TransientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransientError::Status(status) => write!(f, "HTTP request failed with status: {}", status),
+            TransientError::Stalled(timeout) => write!(f, "Download stalled: no data for {:?}", timeout),
+            TransientError::Truncated { expected, received } => {
+                write!(f, "Content-Length mismatch: expected {expected} bytes, received {received}")
+            }
+        }
+    }
+}
+
+impl Error for TransientError {}
+
+/// A download stopped because `max_storage_bytes` is used up. Not the video's
+/// fault: it's tried again once eviction has made room.
+#[derive(Debug)]
+struct StorageBudgetExceeded;
+
+impl std::fmt::Display for StorageBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Storage budget exceeded")
+    }
+}
+
+impl Error for StorageBudgetExceeded {}
+
+fn is_transient(e: &(dyn Error + Send + Sync + 'static)) -> bool {
+    if e.is::<TransientError>() {
+        return true;
+    }
+    // Decompression is off (see `build_download_client`), so a decode error
+    // is the connection dropping mid-body.
+    e.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+        e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() || e.is_decode()
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct DownloadManager {
     state: Arc<AppState>,
@@ -36,61 +110,298 @@ pub struct DownloadManager {
     /// which can hold a larger set of known videos (including completed).
     download_queue: Arc<Mutex<Vec<VideoDownload>>>,
     client: Arc<reqwest::Client>,
+
+    /// Transfers currently running, keyed by URL. The value lists every video id
+    /// attached to that transfer; the first one started it. A second request for
+    /// the same URL attaches here instead of starting a duplicate download.
+    in_flight: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Cancels the running transfer for a URL (same keys as `in_flight`).
+    cancellations: Arc<Mutex<HashMap<String, CancellationToken>>>,
+
+    /// Per-service request tweaks, keyed by the imeta `service` name. Videos
+    /// without a service, or with an unregistered one, use `default_host_adapter`.
+    host_adapters: HashMap<String, Arc<dyn HostAdapter>>,
+    default_host_adapter: Arc<dyn HostAdapter>,
+
+    /// While set, no new downloads start (e.g. "download only on wifi").
+    /// Discovery and queue upkeep carry on, and running downloads finish.
+    paused: Arc<AtomicBool>,
+    /// Videos stopped with `stop_download`, kept out of the queue until
+    /// `restart_download`.
+    stopped: Arc<Mutex<HashSet<String>>>,
+    /// Videos whose download failed for good (every URL, after its retries),
+    /// likewise kept out of the queue until `restart_download`.
+    failed: Arc<Mutex<HashSet<String>>>,
 }
 
 impl DownloadManager {
     pub fn new(state: Arc<AppState>) -> Self {
-        let client = Arc::new(reqwest::Client::new());
+        let client = Arc::new(build_download_client());
         let download_queue = Arc::new(Mutex::new(Vec::new()));
-        Self { state, download_queue, client }
+        let in_flight = Arc::new(Mutex::new(HashMap::new()));
+        Self {
+            state,
+            download_queue,
+            client,
+            in_flight,
+            cancellations: Arc::new(Mutex::new(HashMap::new())),
+            host_adapters: HashMap::new(),
+            default_host_adapter: Arc::new(NoopHostAdapter),
+            paused: Arc::new(AtomicBool::new(false)),
+            stopped: Arc::new(Mutex::new(HashSet::new())),
+            failed: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Stop starting downloads until `resume`. In-flight ones are left to finish.
+    pub fn pause(&self) {
+        if !self.paused.swap(true, Ordering::Relaxed) {
+            info!("Downloads paused");
+        }
+    }
+
+    /// Start downloading again after `pause`.
+    pub fn resume(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            info!("Downloads resumed");
+            self.state.manager_wake.notify_one();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Use `adapter` for every video whose imeta `service` equals `service`.
+    pub fn register_host_adapter(&mut self, service: impl Into<String>, adapter: Arc<dyn HostAdapter>) {
+        self.host_adapters.insert(service.into(), adapter);
+    }
+
+    /// The adapter for a video's service, falling back to the default.
+    fn host_adapter_for(&self, video: &VideoDownload) -> Arc<dyn HostAdapter> {
+        video
+            .nostr
+            .service
+            .as_ref()
+            .and_then(|service| self.host_adapters.get(service))
+            .cloned()
+            .unwrap_or_else(|| Arc::clone(&self.default_host_adapter))
     }
 
     /// Main loop for scheduling new downloads, removing old content, etc.
+    ///
+    /// The loop ticks quickly while there is work in flight and slowly when idle,
+    /// and wakes immediately whenever `state.manager_wake` is notified.
     pub async fn run(self: Arc<Self>) {
+        self.state.resume_position().await;
+        // Get downloads going from last session's queue before relays even answer,
+        // with the warm-start picks in front so the first swipe is ready.
+        let primed = self.prime_queue_from_snapshot().await;
+        let warm = self.warm_start().await;
+        if primed + warm > 0 && !self.is_paused() {
+            self.download_videos().await;
+        }
+        self.reparse_missing_metadata().await;
+        let mut last_snapshot = std::time::Instant::now();
+
         loop {
+            // 0) Power management: with nobody watching, stay disconnected and
+            //    start no downloads until a handler calls `touch()`.
+            if self.state.idle_timed_out().await {
+                self.state.go_idle().await;
+            }
+            if self.state.is_idle() {
+                self.state.manager_wake.notified().await;
+                continue;
+            }
+
             // 1) Fetch new videos & gather HEAD content_length, add them to discovered
-            self.discovery_new_videos().await;
+            let connected = self.state.ready.load(Ordering::Relaxed)
+                || self.state.content_discovery.has_connected_relay().await;
+            let discovered = self.discovery_new_videos().await;
+            // Ready once a discovery pass has run with at least one relay connected
+            if connected && !self.state.ready.swap(true, Ordering::Relaxed) {
+                info!("Discovery ready ({} new videos in first batch)", discovered);
+            }
 
             // 2) Re-sort the entire discovered set according to your multi-criteria
             //    then push the next candidates to the `download_queue`.
-            self.update_download_queue().await;
+            let queue_changed = self.update_download_queue().await;
 
-            // 3) Enforce behind-limit, removing old files
+            // 3) Enforce behind-limit and max file age, removing old files
             self.enforce_behind_limit().await;
+            self.enforce_max_file_age().await;
 
-            // 4) Trigger actual downloads if below concurrency limit
-            self.download_videos().await;
+            // 4) Trigger actual downloads if below concurrency limit (and not paused)
+            if !self.is_paused() {
+                self.download_videos().await;
+            }
+
+            // 5) Persist the session every so often
+            if last_snapshot.elapsed() >= SNAPSHOT_INTERVAL {
+                self.state.save_snapshot().await;
+                self.state.save_playlist().await;
+                last_snapshot = std::time::Instant::now();
+            }
+
+            let interval = if discovered > 0 || queue_changed || self.has_active_downloads().await {
+                ACTIVE_LOOP_INTERVAL
+            } else {
+                IDLE_LOOP_INTERVAL
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = self.state.manager_wake.notified() => {}
+            }
+        }
+    }
 
-            tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    /// Re-read a completed download from storage and re-extract its metadata.
+    /// Returns whether metadata was found.
+    pub async fn reparse_metadata(&self, video_id: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        reparse_metadata(&self.state, video_id).await
+    }
+
+    /// Reparse every completed download whose container metadata was never
+    /// parsed, e.g. because the moov box wasn't parseable mid-download. (The
+    /// duration alone may come from the event, so check `format`.)
+    async fn reparse_missing_metadata(&self) {
+        let ids: Vec<String> = {
+            let discovered = self.state.discovered_videos.read().await;
+            discovered
+                .values()
+                .filter(|v| has_local_file(v) && !v.downloading && v.format.is_none())
+                .map(|v| v.id.clone())
+                .collect()
+        };
+        for id in ids {
+            if let Err(e) = self.reparse_metadata(&id).await {
+                warn!("Could not reparse metadata for {}: {e}", id);
+            }
         }
     }
 
+    async fn has_active_downloads(&self) -> bool {
+        self.download_queue.lock().await.iter().any(|v| v.downloading)
+    }
+
     /// Method to stop/drop a given download in progress or queued.
-    /// This removes it from the `download_queue`, and marks it as not `downloading`.
-    /// If you want to actually remove partial data from disk, do so here as well.
+    /// This removes it from the `download_queue`, cancels its transfer, marks it
+    /// as not `downloading` and deletes the partial file. A transfer other
+    /// coalesced videos still wait on carries on, file and all. The video is then left
+    /// out of the queue until `restart_download`. Returns `false` if it wasn't
+    /// queued.
     pub async fn stop_download(&self, video_id: &str) -> bool {
-        let mut queue = self.download_queue.lock().await;
-        if let Some(pos) = queue.iter().position(|v| v.id == video_id) {
-            let removed = queue.remove(pos);
+        self.stop_and_discard(video_id).await.is_some()
+    }
+
+    /// Body of `stop_download`. Returns the bytes of the partial file it
+    /// deleted, or `None` if the video wasn't queued.
+    async fn stop_and_discard(&self, video_id: &str) -> Option<u64> {
+        // Lock order is `discovered_videos` -> queue, so release the queue
+        // before touching anything else.
+        let stopped = {
+            let mut queue = self.download_queue.lock().await;
+            match queue.iter().position(|v| v.id == video_id) {
+                Some(pos) => queue.remove(pos),
+                None => return None,
+            }
+        };
+        self.stopped.lock().await.insert(video_id.to_string());
+
+        // Cancel the transfer unless coalesced videos still wait on it. Then
+        // the file is theirs too, and stays.
+        let shared = self.detach_from_transfer(&stopped.url, &[video_id.to_string()], false).await;
+
+        // Mark as not downloading in discovered_videos as well. The queue
+        // holds a copy from before the download started, so the file path
+        // and byte count come from here.
+        let mut discovered = self.state.discovered_videos.write().await;
+        let mut removed = None;
+        if let Some(dv) = discovered.get_mut(video_id) {
+            dv.downloading = false;
+            if !shared {
+                removed = dv.local_path.take().map(|path| (path, dv.downloaded_bytes));
+                dv.downloaded_bytes = 0;
+                dv.synced_bytes = 0;
+            }
+            self.state.publish_progress(dv);
+        }
+        drop(discovered);
+
+        // Optionally remove partial file from disk:
+        let mut freed = 0;
+        if let Some((local_path, bytes)) = removed {
+            let _ = self.state.store.delete(&local_path).await;
+            let mut storage = self.state.current_storage_bytes.lock().await;
+            *storage = storage.saturating_sub(bytes);
+            freed = bytes;
+        }
+        Some(freed)
+    }
 
-            // Mark as not downloading in discovered_videos as well
-            let mut discovered = self.state.discovered_videos.lock().await;
-            if let Some(dv) = discovered.get_mut(video_id) {
-                dv.downloading = false;
+    /// Detach `ids` from the transfer of `url`. It's cancelled once nobody
+    /// else is attached, or, with `drop_owner`, when the video that started it
+    /// (whose entry the file is recorded on) is among `ids`. Returns whether
+    /// it carries on for other videos, i.e. whether its file is still in use.
+    async fn detach_from_transfer(&self, url: &str, ids: &[String], drop_owner: bool) -> bool {
+        let mut in_flight = self.in_flight.lock().await;
+        let Some(attached) = in_flight.get_mut(url) else {
+            return false;
+        };
+        let owner_detached = attached.first().is_some_and(|owner| ids.contains(owner));
+        if attached.iter().all(|id| ids.contains(id)) || (drop_owner && owner_detached) {
+            if let Some(token) = self.cancellations.lock().await.remove(url) {
+                token.cancel();
             }
+            return false;
+        }
+        attached.retain(|id| !ids.contains(id));
+        true
+    }
 
-            // Optionally remove partial file from disk:
-            if let Some(local_path) = removed.local_path {
-                let _ = remove_file(local_path).await;
+    /// Let a video stopped with `stop_download`, or whose download failed, be
+    /// queued again. Returns `false` if it was neither.
+    pub async fn restart_download(&self, video_id: &str) -> bool {
+        let stopped = self.stopped.lock().await.remove(video_id);
+        let failed = self.failed.lock().await.remove(video_id);
+        let restarted = stopped || failed;
+        if restarted {
+            self.state.manager_wake.notify_one();
+        }
+        restarted
+    }
+
+    /// Cancel every running download, clear the queue and mark everything as not
+    /// downloading. Partial files are kept for a later resume. Also pauses the
+    /// manager, so nothing starts again until `resume`.
+    pub async fn stop_all(&self) {
+        self.pause();
+        for (_, token) in self.cancellations.lock().await.drain() {
+            token.cancel();
+        }
+        let stopped: Vec<String> = self.in_flight.lock().await.drain().flat_map(|(_, ids)| ids).collect();
+        self.download_queue.lock().await.clear();
+        let mut discovered = self.state.discovered_videos.write().await;
+        for video in discovered.values_mut() {
+            if video.downloading {
+                video.downloading = false;
+                video.download_speed_bps = 0.0;
+                self.state.publish_progress(video);
             }
-            true
-        } else {
-            false
         }
+        info!(count = stopped.len(), "Stopped all downloads");
+    }
+
+    /// Ids of the videos currently downloading (including ones attached to
+    /// another video's transfer).
+    pub async fn active_downloads(&self) -> Vec<String> {
+        self.in_flight.lock().await.values().flatten().cloned().collect()
     }
 
     /// Pull new videos from `ContentDiscovery` and enrich with HEAD requests.
-    async fn discovery_new_videos(&self) {
+    /// Returns how many videos were merged into `discovered_videos`.
+    async fn discovery_new_videos(&self) -> usize {
         // 1) Retrieve newly discovered videos
         let new_batch: Vec<VideoDownload> = self
             .state
@@ -99,6 +410,7 @@ impl DownloadManager {
             .await
             .into_iter()
             .map(VideoDownload::from_nostr_video)
+            .filter(|v| self.state.meets_min_resolution(v))
             .collect();
 
         // 2) HEAD-check content_length in parallel
@@ -106,11 +418,208 @@ impl DownloadManager {
             fetch_content_lengths_in_parallel(self.client.clone(), new_batch, 20).await;
 
         // 3) Merge into the main discovered list
-        let mut discovered = self.state.discovered_videos.lock().await;
-        for mut vid in enriched_batch {
-            discovered.insert(vid.id.clone(), vid);
+        let mut added = Vec::new();
+        let mut discovered = self.state.discovered_videos.write().await;
+        for vid in enriched_batch {
+            match discovered.get_mut(&vid.id) {
+                // Same file seen again (another relay, or another event reposting
+                // it elsewhere): keep the existing entry and its download progress,
+                // just remember the extra URLs, authors and relays.
+                Some(existing) => existing.merge_duplicate(vid),
+                None => {
+                    let mut vid = vid;
+                    vid.compute_score();
+                    // The author's lookup may have finished while this video was in flight.
+                    if vid.nostr.user.name.is_none() {
+                        if let Some(npub) = vid.nostr.user.npub.as_deref() {
+                            if let Some(user) = self.state.content_discovery.known_author(npub).await {
+                                vid.nostr.user = user;
+                            }
+                        }
+                    }
+                    added.push(vid.clone());
+                    discovered.insert(vid.id.clone(), vid);
+                }
+            }
+        }
+        drop(discovered);
+        let added_count = added.len();
+        if !added.is_empty() {
+            self.state.publish(StateEvent::VideosDiscovered { videos: added });
+        }
+
+        // 4) Author metadata is looked up in the background; apply what has
+        //    arrived to videos already known.
+        self.apply_author_updates().await;
+        self.apply_relay_sightings().await;
+        self.apply_removals().await;
+
+        self.prune_discovered().await;
+        added_count
+    }
+
+    /// Fill in author metadata that arrived after the videos did.
+    async fn apply_author_updates(&self) {
+        let updates: HashMap<String, UserData> = self
+            .state
+            .content_discovery
+            .fetch_author_updates()
+            .await
+            .into_iter()
+            .filter_map(|user| Some((user.npub.clone()?, user)))
+            .collect();
+        if updates.is_empty() {
+            return;
+        }
+
+        let mut discovered = self.state.discovered_videos.write().await;
+        for video in discovered.values_mut() {
+            if let Some(user) = video.nostr.user.npub.as_ref().and_then(|npub| updates.get(npub)) {
+                video.nostr.user = user.clone();
+            }
+        }
+        drop(discovered);
+
+        let mut playlists = self.state.playlists.lock().await;
+        for user in updates.values() {
+            for playlist in playlists.values_mut() {
+                playlist.update_user(user);
+            }
+        }
+    }
+
+    /// Add the relays that repeated an event to its video's `source_relays`.
+    async fn apply_relay_sightings(&self) {
+        let sightings = self.state.content_discovery.fetch_relay_sightings().await;
+        if sightings.is_empty() {
+            return;
+        }
+
+        let mut discovered = self.state.discovered_videos.write().await;
+        for sighting in sightings {
+            for video in discovered.values_mut().filter(|v| v.nostr.event_id == sighting.event_id) {
+                if !video.nostr.source_relays.contains(&sighting.relay_url) {
+                    video.nostr.source_relays.push(sighting.relay_url.clone());
+                }
+            }
+        }
+    }
+
+    /// Drop videos their authors deleted (NIP-09): cancel their download,
+    /// delete the file unless another entry shares it, and take them off the
+    /// queue and every playlist.
+    async fn apply_removals(&self) {
+        let removals = self.state.content_discovery.fetch_removed_videos().await;
+        if removals.is_empty() {
+            return;
+        }
+
+        let ids: Vec<String> = self
+            .state
+            .discovered_videos
+            .read()
+            .await
+            .values()
+            .filter(|v| removals.iter().any(|removal| removal.matches(&v.nostr)))
+            .map(|v| v.id.clone())
+            .collect();
+        if ids.is_empty() {
+            return;
+        }
+        self.remove_videos(&ids).await;
+        info!(count = ids.len(), "Removed videos deleted by their authors");
+    }
+
+    /// Purge one video on the client's request: stop its download like
+    /// `stop_download`, then forget it, see `remove_videos`. Returns the bytes
+    /// freed on disk, or `None` if the id is unknown.
+    pub async fn delete_video(&self, id: &str) -> Option<u64> {
+        if !self.state.discovered_videos.read().await.contains_key(id) {
+            return None;
+        }
+        let partial = self.stop_and_discard(id).await.unwrap_or(0);
+        let freed = partial + self.remove_videos(&[id.to_string()]).await;
+        info!(id, freed, "Deleted video");
+        Some(freed)
+    }
+
+    /// Forget the given videos entirely: drop them from `discovered_videos`,
+    /// the queue and every playlist, cancel transfers only they were waiting
+    /// on, and delete their files and thumbnails. Returns the bytes freed.
+    async fn remove_videos(&self, ids: &[String]) -> u64 {
+        let mut discovered = self.state.discovered_videos.write().await;
+        let removed: Vec<VideoDownload> = ids.iter().filter_map(|id| discovered.remove(id)).collect();
+        let kept_paths: Vec<PathBuf> = discovered.values().filter_map(|v| v.local_path.clone()).collect();
+        drop(discovered);
+
+        // Stop transfers nobody else is waiting for. One started by a removed
+        // video can't hand its file on, so it's stopped either way.
+        let mut in_use: Vec<PathBuf> = Vec::new();
+        for video in removed.iter().filter(|v| v.downloading) {
+            if self.detach_from_transfer(&video.url, ids, true).await {
+                in_use.extend(video.local_path.clone());
+            }
         }
-        // End of `discovery_new_videos`.
+        self.download_queue.lock().await.retain(|v| !ids.contains(&v.id));
+        self.stopped.lock().await.retain(|id| !ids.contains(id));
+        self.failed.lock().await.retain(|id| !ids.contains(id));
+        self.state.remove_from_playlists(ids).await;
+
+        // Coalesced downloads share a file, so its bytes are counted once.
+        let mut paths_to_remove: HashMap<PathBuf, u64> = HashMap::new();
+        let mut thumbs_to_remove = Vec::new();
+        for video in removed {
+            if let Some(path) = video.local_path.filter(|p| !kept_paths.contains(p) && !in_use.contains(p)) {
+                paths_to_remove.insert(path, video.downloaded_bytes);
+            }
+            thumbs_to_remove.extend(video.thumbnail_path);
+        }
+        let mut freed = 0u64;
+        for (path, bytes) in paths_to_remove {
+            if let Err(e) = self.state.store.delete(&path).await {
+                warn!(path = %path.display(), error = %e, "Failed to delete removed video");
+            }
+            freed += bytes;
+        }
+        let mut storage = self.state.current_storage_bytes.lock().await;
+        *storage = storage.saturating_sub(freed);
+        drop(storage);
+        self.remove_thumbnails(thumbs_to_remove).await;
+        freed
+    }
+
+    /// Keep `discovered_videos` within `max_discovered` by evicting the
+    /// least-recently-seen entries. Videos that are downloading, have a local
+    /// file, are favorites, or sit in the protected window at the head of the
+    /// download queue are never evicted.
+    async fn prune_discovered(&self) {
+        let mut protected: Vec<String> = {
+            let queue = self.download_queue.lock().await;
+            queue
+                .iter()
+                .take(self.state.target_videos_ahead.load(Ordering::Relaxed))
+                .map(|v| v.id.clone())
+                .collect()
+        };
+        protected.extend(self.state.favorites.lock().await.iter().cloned());
+
+        let mut discovered = self.state.discovered_videos.write().await;
+        if discovered.len() <= self.state.max_discovered {
+            return;
+        }
+
+        let mut evictable: Vec<(u64, String)> = discovered
+            .values()
+            .filter(|v| !v.downloading && v.local_path.is_none() && !protected.contains(&v.id))
+            .map(|v| (v.last_seen, v.id.clone()))
+            .collect();
+        evictable.sort();
+
+        let excess = discovered.len() - self.state.max_discovered;
+        for (_, id) in evictable.into_iter().take(excess) {
+            discovered.remove(&id);
+        }
+        debug!("Pruned discovered videos down to {}", discovered.len());
     }
 
     /// Decide which videos should be in the `download_queue` and in what order, based
@@ -122,53 +631,334 @@ impl DownloadManager {
     ///    (b) After meeting that threshold, prioritize high score, then small size.
     /// 3) Update the local `download_queue` with this sorted subset (only keep videos
     ///    that are actually missing or incomplete).
-    async fn update_download_queue(&self) {
-        let discovered_map = self.state.discovered_videos.lock().await;
+    ///
+    /// Returns whether the queue order changed.
+    async fn update_download_queue(&self) -> bool {
+        let discovered_map = self.state.discovered_videos.read().await;
         let all_videos: Vec<VideoDownload> = discovered_map.values().cloned().collect();
         drop(discovered_map); // drop lock so we can do the sorting below
 
+        // The video being watched, then the next `prefetch_next` in playlist
+        // order, go first whatever their size, so the immediate viewing
+        // experience never waits on the sort.
+        let prefetch_next = self.state.prefetch_next.load(Ordering::Relaxed);
+        let prefetch_ids: Vec<String> = {
+            let current_idx = *self.state.current_index.lock().await;
+            let playlist = self.state.playlist().await;
+            playlist
+                .get(current_idx)
+                .map(|v| v.id.clone())
+                .into_iter()
+                .chain(playlist.ids_after(current_idx, prefetch_next))
+                .collect()
+        };
+
         // Filter for only videos that do NOT have a local file and are not done
         // (a real check might confirm partial downloads as well).
+        let stopped = self.stopped.lock().await.clone();
+        let failed = self.failed.lock().await.clone();
         let mut candidates: Vec<VideoDownload> = all_videos
             .into_iter()
-            .filter(|v| !has_local_file(v) /* or v.local_path.is_none() */ )
+            .filter(|v| needs_download(v, &prefetch_ids))
+            .filter(|v| !stopped.contains(&v.id) && !failed.contains(&v.id))
+            .filter(|v| self.state.meets_length_limits(v))
+            .collect();
+
+        // On a metered connection only fetch the cheapest variant of each event.
+        if self.state.metered {
+            keep_cheapest_variants(&mut candidates);
+        }
+
+        let mut prefetch: Vec<VideoDownload> = prefetch_ids
+            .iter()
+            .filter_map(|id| candidates.iter().position(|v| &v.id == id))
+            .map(|pos| candidates[pos].clone())
             .collect();
+        candidates.retain(|v| !prefetch_ids.contains(&v.id));
 
-        // Sort them with the two-phase stable approach:
-        sort_videos_for_download(
-            &mut candidates,
-            self.state.target_videos_ahead,
-            self.state.target_minutes_ahead,
-        );
+        // Then favorites that still need downloading, in their sorted order below.
+        let favorites = self.state.favorites.lock().await.clone();
+        let (mut favorite_candidates, mut candidates): (Vec<_>, Vec<_>) =
+            candidates.into_iter().partition(|v| favorites.contains(&v.id));
+
+        if self.state.sort_by_published {
+            sort_videos_by_published(&mut candidates);
+            sort_videos_by_published(&mut favorite_candidates);
+        } else {
+            let target_videos_ahead = self.state.target_videos_ahead.load(Ordering::Relaxed);
+            let target_minutes_ahead = *self.state.target_minutes_ahead.lock().await;
+            // Sort the rest with the two-phase stable approach:
+            sort_videos_for_download(
+                &mut candidates,
+                target_videos_ahead,
+                target_minutes_ahead,
+                self.state.assumed_bitrate_bps,
+            );
+            sort_videos_for_download(
+                &mut favorite_candidates,
+                target_videos_ahead,
+                target_minutes_ahead,
+                self.state.assumed_bitrate_bps,
+            );
+        }
+        prefetch.append(&mut favorite_candidates);
+        prefetch.append(&mut candidates);
+        let candidates = prefetch;
 
         // Now update the queue. For simplicity, we replace the entire queue with the new ordering.
         let mut queue = self.download_queue.lock().await;
+        let changed = queue.len() != candidates.len()
+            || queue.iter().zip(&candidates).any(|(a, b)| a.id != b.id);
         *queue = candidates;
+        if changed {
+            self.save_queue_snapshot(&queue).await;
+        }
+        changed
     }
 
-    /// Remove behind-limit videos from disk. This example simply checks how far behind
-    /// our current index we are, and removes anything older than `max_behind_seconds`.
-    async fn enforce_behind_limit(&self) {
+    /// Move up to `warm_start_count` already-known videos to the front of the
+    /// queue: favorites first, then the highest scored. Returns how many.
+    async fn warm_start(&self) -> usize {
+        let count = self.state.warm_start_count;
+        if count == 0 {
+            return 0;
+        }
+        let favorites = self.state.favorites.lock().await.clone();
+        let mut picks: Vec<VideoDownload> = self
+            .state
+            .discovered_videos
+            .read()
+            .await
+            .values()
+            .filter(|v| needs_download(v, &[]))
+            .cloned()
+            .collect();
+        picks.sort_by(|a, b| {
+            favorites
+                .contains(&b.id)
+                .cmp(&favorites.contains(&a.id))
+                .then(b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal))
+        });
+        picks.truncate(count);
+
+        let mut queue = self.download_queue.lock().await;
+        queue.retain(|v| !picks.iter().any(|p| p.id == v.id));
+        let warm = picks.len();
+        queue.splice(0..0, picks);
+        debug!(warm, "Warm start");
+        warm
+    }
+
+    /// At the concurrency limit, with the video at `current_index` queued but
+    /// not downloading: cancel the in-flight transfer furthest down the queue
+    /// so the next tick can start it. The cancelled video keeps its partial
+    /// file and is queued again by the next `update_download_queue`.
+    async fn preempt_for_current(&self, queue: &[VideoDownload]) {
         let current_idx = *self.state.current_index.lock().await;
-        let mut discovered = self.state.discovered_videos.lock().await;
-
-        let mut paths_to_remove = Vec::new();
-        for (vid_id, video) in discovered.iter_mut() {
-            if let Some(length) = video.length_seconds {
-                if length > self.state.max_behind_seconds as f64 {
-                    // schedule removal
-                    if let Some(local_path) = video.local_path.take() {
-                        paths_to_remove.push(local_path);
-                    }
+        let Some(current_id) = self.state.playlist().await.get(current_idx).map(|v| v.id.clone()) else {
+            return;
+        };
+        let Some(current_pos) = queue.iter().position(|v| v.id == current_id) else {
+            return; // already downloaded, or filtered out
+        };
+        if queue[current_pos].downloading {
+            return;
+        }
+
+        let in_flight = self.in_flight.lock().await;
+        // A transfer ranks as its most urgent attached video.
+        let rank = |ids: &Vec<String>| {
+            ids.iter()
+                .filter_map(|id| queue.iter().position(|v| &v.id == id))
+                .min()
+                .unwrap_or(usize::MAX)
+        };
+        let victim = in_flight
+            .iter()
+            .map(|(url, ids)| (url, rank(ids)))
+            .filter(|(_, rank)| *rank > current_pos)
+            .max_by_key(|(_, rank)| *rank);
+        let Some((url, _)) = victim else {
+            return;
+        };
+        if let Some(token) = self.cancellations.lock().await.remove(url) {
+            info!(url = %url, current = %current_id, "Preempting download for the current video");
+            token.cancel();
+        }
+    }
+
+    /// Persist the queue order to `queue_snapshot_path`.
+    async fn save_queue_snapshot(&self, queue: &[VideoDownload]) {
+        let Some(path) = &self.state.queue_snapshot_path else {
+            return;
+        };
+        if let Err(e) = QueueSnapshot::capture(queue).save(path).await {
+            warn!("Failed to save queue snapshot to {}: {e}", path.display());
+        }
+    }
+
+    /// Fill the queue from `queue_snapshot_path`, keeping only ids that are
+    /// still known and not yet downloaded. Returns how many were queued.
+    async fn prime_queue_from_snapshot(&self) -> usize {
+        let Some(path) = &self.state.queue_snapshot_path else {
+            return 0;
+        };
+        let snapshot = match QueueSnapshot::load(path).await {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => return 0,
+            Err(e) => {
+                warn!("Failed to load queue snapshot from {}: {e}", path.display());
+                return 0;
+            }
+        };
+
+        let discovered = self.state.discovered_videos.read().await;
+        let primed: Vec<VideoDownload> = snapshot
+            .videos
+            .into_iter()
+            .filter_map(|entry| {
+                let video = discovered.get(&entry.id)?;
+                if has_local_file(video) {
+                    return None;
                 }
+                let mut video = video.clone();
+                video.url = entry.url;
+                Some(video)
+            })
+            .collect();
+        drop(discovered);
+
+        let count = primed.len();
+        *self.download_queue.lock().await = primed;
+        info!("Primed download queue with {} videos from last session", count);
+        count
+    }
+
+    /// Remove videos the viewer has scrolled well past. Walking back from
+    /// `current_index` through the active playlist, lengths are summed; once
+    /// a video starts more than `max_behind_seconds` behind the one being
+    /// watched, its file is removed. Videos of unknown length count as zero,
+    /// and favorites and anything downloading are kept.
+    async fn enforce_behind_limit(&self) {
+        let current_idx = *self.state.current_index.lock().await;
+        let favorites = self.state.favorites.lock().await.clone();
+        let max_behind_seconds = self.state.max_behind_seconds.load(Ordering::Relaxed) as f64;
+        let order: Vec<String> = self.state.playlist().await.as_vec().into_iter().map(|v| v.id).collect();
+        let mut discovered = self.state.discovered_videos.write().await;
+
+        // Ids at least `max_behind_seconds` back, nearest first.
+        let mut behind_seconds = 0.0;
+        let mut too_far_behind = Vec::new();
+        for id in order.iter().take(current_idx.min(order.len())).rev() {
+            behind_seconds += discovered.get(id).and_then(|v| v.length_seconds).unwrap_or(0.0);
+            if behind_seconds > max_behind_seconds {
+                too_far_behind.push(id.clone());
             }
         }
+
+        let evict: Vec<String> = too_far_behind
+            .into_iter()
+            .filter(|id| !favorites.contains(id))
+            .filter(|id| discovered.get(id).is_some_and(|v| !v.downloading))
+            .collect();
+        let (paths_to_remove, thumbs_to_remove) =
+            take_evicted_files(&mut discovered, &evict, self.state.keep_thumbnails_on_evict);
         drop(discovered);
 
         // Remove files outside the lock
-        for path in paths_to_remove {
-            let _ = remove_file(path).await;
+        let mut freed = 0u64;
+        for (path, bytes) in paths_to_remove {
+            let _ = self.state.store.delete(&path).await;
+            freed += bytes;
+        }
+        let mut storage = self.state.current_storage_bytes.lock().await;
+        *storage = storage.saturating_sub(freed);
+        drop(storage);
+        self.remove_thumbnails(thumbs_to_remove).await;
+    }
+
+    /// Delete thumbnails of evicted videos (see `keep_thumbnails_on_evict`).
+    /// Entries for the same file share one thumbnail, so it's only removed
+    /// once no remaining video refers to it.
+    async fn remove_thumbnails(&self, mut paths: Vec<PathBuf>) {
+        if paths.is_empty() {
+            return;
+        }
+        {
+            let discovered = self.state.discovered_videos.read().await;
+            paths.retain(|p| !discovered.values().any(|v| v.thumbnail_path.as_ref() == Some(p)));
+        }
+        paths.sort();
+        paths.dedup();
+        for path in paths {
+            if let Err(e) = thumbnails::remove(&path).await {
+                warn!(path = %path.display(), error = %e, "Failed to delete thumbnail");
+            }
+        }
+    }
+
+    /// Delete completed files older than `max_file_age`, wherever they sit
+    /// relative to the playback position. The video currently playing,
+    /// favorites and anything still downloading are left alone.
+    async fn enforce_max_file_age(&self) {
+        let Some(max_age) = self.state.max_file_age else {
+            return;
+        };
+        let playing_id = self.state.playlist().await.current().map(|v| v.id.clone());
+        let favorites = self.state.favorites.lock().await.clone();
+        let now = SystemTime::now();
+
+        let mut discovered = self.state.discovered_videos.write().await;
+        // Coalesced downloads share a file, so collect by path and count its bytes once.
+        let mut expired: HashMap<PathBuf, u64> = HashMap::new();
+        let mut kept: Vec<&PathBuf> = Vec::new();
+        for video in discovered.values() {
+            let (Some(path), Some(downloaded_at)) = (&video.local_path, video.downloaded_at) else {
+                continue;
+            };
+            if video.downloading
+                || playing_id.as_deref() == Some(video.id.as_str())
+                || favorites.contains(&video.id)
+            {
+                kept.push(path);
+                continue;
+            }
+            let age = now.duration_since(downloaded_at).unwrap_or_default();
+            if age > max_age {
+                expired.insert(path.clone(), video.downloaded_bytes);
+            }
+        }
+        for path in kept {
+            expired.remove(path);
+        }
+        if expired.is_empty() {
+            return;
+        }
+        let mut thumbs_to_remove = Vec::new();
+        for video in discovered.values_mut() {
+            if video.local_path.as_ref().is_some_and(|p| expired.contains_key(p)) {
+                video.local_path = None;
+                video.downloaded_bytes = 0;
+                video.synced_bytes = 0;
+                video.downloaded_at = None;
+                if !self.state.keep_thumbnails_on_evict {
+                    thumbs_to_remove.extend(video.thumbnail_path.take());
+                }
+            }
+        }
+        drop(discovered);
+        self.remove_thumbnails(thumbs_to_remove).await;
+
+        let mut freed = 0u64;
+        for (path, bytes) in expired {
+            if let Err(e) = self.state.store.delete(&path).await {
+                warn!(path = %path.display(), error = %e, "Failed to delete expired file");
+            }
+            freed += bytes;
         }
+        let mut storage = self.state.current_storage_bytes.lock().await;
+        *storage = storage.saturating_sub(freed);
+        debug!(freed_bytes = freed, "Evicted files past max_file_age");
     }
 
     /// Start downloads if we're below concurrency limit, taking them in the order from
@@ -180,27 +970,66 @@ impl DownloadManager {
             queue.clone()
         };
 
-        let concurrent_downloads = queue_snapshot.iter().filter(|v| v.downloading).count();
+        let (mut concurrent_downloads, mut per_host) = {
+            let in_flight = self.in_flight.lock().await;
+            let mut per_host: HashMap<String, usize> = HashMap::new();
+            for url in in_flight.keys() {
+                *per_host.entry(url_host(url)).or_default() += 1;
+            }
+            (in_flight.len(), per_host)
+        };
 
-        let max_downloads = self.state.max_parallel_downloads;
+        let max_downloads = self.state.max_parallel_downloads.load(Ordering::Relaxed);
+        let max_per_host = self.state.max_downloads_per_host.max(1);
+        // Real free space on the download disk; `None` if the store can't tell.
+        let mut free_disk = self.state.store.available_space(&self.state.download_dir).await.ok();
 
-        // If already at concurrency limit, do nothing
+        // If already at concurrency limit, only make room for the video being watched
         if concurrent_downloads >= max_downloads {
+            self.preempt_for_current(&queue_snapshot).await;
             return;
         }
 
-        // Now pick the top candidates that are NOT downloading
-        let to_start = queue_snapshot
-            .into_iter()
-            .filter(|v| !v.downloading)
-            .take(max_downloads - concurrent_downloads);
+        // Now go through the top candidates that are NOT downloading. Ones
+        // whose host is at its limit stay queued for a later tick.
+        for video in queue_snapshot.into_iter().filter(|v| !v.downloading) {
+            if concurrent_downloads >= max_downloads {
+                break;
+            }
+            // Coalesce: if this URL is already being fetched, attach to that transfer.
+            let attached = {
+                let mut in_flight = self.in_flight.lock().await;
+                if attach_to_in_flight(&mut in_flight, &video.url, &video.id) {
+                    true
+                } else {
+                    // Leave it queued if it wouldn't fit on disk, so it
+                    // can't fail halfway with ENOSPC.
+                    let remaining = video.content_length.unwrap_or(0).saturating_sub(video.downloaded_bytes);
+                    if let Some(free) = free_disk {
+                        if free < remaining.saturating_add(self.state.min_free_disk_bytes) {
+                            debug!(id = %video.id, free, remaining, "Not enough free disk space to start");
+                            continue;
+                        }
+                    }
+                    let host = url_host(&video.url);
+                    let host_count = per_host.entry(host).or_default();
+                    if *host_count >= max_per_host {
+                        continue;
+                    }
+                    *host_count += 1;
+                    concurrent_downloads += 1;
+                    free_disk = free_disk.map(|free| free - remaining);
+                    in_flight.insert(video.url.clone(), vec![video.id.clone()]);
+                    false
+                }
+            };
 
-        for video in to_start {
             // Mark it as downloading in the queue + discovered_videos
             {
-                let mut discovered = self.state.discovered_videos.lock().await;
+                let mut discovered = self.state.discovered_videos.write().await;
                 if let Some(v) = discovered.get_mut(&video.id) {
                     v.downloading = true;
+                    self.state.publish_progress(v);
                 }
             }
             {
@@ -209,66 +1038,293 @@ impl DownloadManager {
                     qv.downloading = true;
                 }
             }
+            if attached {
+                debug!("{} attached to in-flight download of {}", video.id, video.url);
+                continue;
+            }
 
             let dm_state = Arc::clone(&self.state);
             let dm_queue = Arc::clone(&self.download_queue);
             let dm_client = Arc::clone(&self.client);
             let video_clone = video.clone();
+            let host_adapter = self.host_adapter_for(&video);
+
+            let cancel = CancellationToken::new();
+            self.cancellations.lock().await.insert(video.url.clone(), cancel.clone());
 
             let dm = self.clone();
+            let span = info_span!("download", video_id = %video.id, url = %video.url);
             tokio::spawn(async move {
-                match download_video_progressive(
-                    Arc::clone(&dm_state),
-                    dm_client.clone(),
-                    video_clone.clone(),
-                )
-                    .await
-                {
+                let result = tokio::select! {
+                    result = download_with_alternates(
+                        Arc::clone(&dm_state),
+                        dm_client.clone(),
+                        host_adapter,
+                        video_clone.clone(),
+                    ) => result,
+                    _ = cancel.cancelled() => Err("Download cancelled".into()),
+                };
+                dm.cancellations.lock().await.remove(&video_clone.url);
+                match result {
                     Err(e) => {
-                        error!("Failed to download {}: {e}", video_clone.url);
-                        let mut discovered = dm_state.discovered_videos.lock().await;
-                        if let Some(v) = discovered.get_mut(&video_clone.id) {
-                            v.downloading = false;
+                        let attached = dm.in_flight.lock().await.remove(&video_clone.url).unwrap_or_default();
+                        // Retries are used up; don't queue it again. A cancelled
+                        // or out-of-budget download is picked up again later.
+                        if cancel.is_cancelled() || e.is::<StorageBudgetExceeded>() {
+                            debug!(error = %e, "Download interrupted");
+                        } else {
+                            error!(error = %e, "Download failed, giving up");
+                            dm.failed.lock().await.extend(attached.iter().cloned());
                         }
-                        let mut queue = dm_queue.lock().await;
-                        if let Some(pos) = queue.iter().position(|qv| qv.id == video_clone.id) {
-                            queue.remove(pos);
+                        let mut discovered = dm_state.discovered_videos.write().await;
+                        for id in &attached {
+                            if let Some(v) = discovered.get_mut(id) {
+                                v.downloading = false;
+                                dm_state.publish_progress(v);
+                            }
                         }
+                        drop(discovered);
+                        dm_queue.lock().await.retain(|qv| !attached.contains(&qv.id));
                     }
 
                     Ok(_) => {
+                        let attached = dm.in_flight.lock().await.remove(&video_clone.url).unwrap_or_default();
+                        share_completed_download(&dm_state, &video_clone.id, &attached).await;
                         let mut queue = dm_queue.lock().await;
-                        if let Some(pos) = queue.iter().position(|qv| qv.id == video_clone.id) {
-                            queue.remove(pos);
-                        }
-                        let mut playlist = dm_state.playlist.lock().await;
-                        playlist.add(video_clone);
+                        queue.retain(|qv| !attached.contains(&qv.id));
+                        drop(queue);
+                        dm_state.add_to_playlist(DEFAULT_PLAYLIST, video_clone).await;
+                        dm_state.manager_wake.notify_one();
                     }
                 }
-            });
+            }.instrument(span));
+        }
+    }
+}
+
+/// Host part of `url`, used to spread downloads across servers. Unparseable
+/// URLs are keyed by the whole string.
+fn url_host(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_lowercase))
+        .unwrap_or_else(|| url.to_string())
+}
+
+/// Download `video` from the first of its known URLs that works, discarding
+/// the partial file of each failed attempt, the last one included. Running
+/// out of storage budget ends it early and keeps the partial file, to resume
+/// once there's room.
+async fn download_with_alternates(
+    state: Arc<AppState>,
+    client: Arc<reqwest::Client>,
+    host_adapter: Arc<dyn HostAdapter>,
+    video: VideoDownload,
+) -> Result<VideoDownload, Box<dyn Error + Send + Sync>> {
+    let urls = video.download_urls();
+    let mut last_error: Box<dyn Error + Send + Sync> = "video has no URL".into();
+    for (attempt, url) in urls.iter().enumerate() {
+        let mut candidate = video.clone();
+        candidate.url = url.clone();
+        match download_with_retries(state.clone(), client.clone(), host_adapter.clone(), candidate).await {
+            Ok(done) => return Ok(done),
+            Err(e) if e.is::<StorageBudgetExceeded>() => return Err(e),
+            Err(e) => {
+                if attempt + 1 < urls.len() {
+                    warn!(url = %url, error = %e, "Download failed, trying next URL");
+                }
+                let partial = {
+                    let discovered = state.discovered_videos.read().await;
+                    discovered
+                        .get(&video.id)
+                        .and_then(|v| v.local_path.clone().map(|p| (p, v.downloaded_bytes)))
+                };
+                if let Some((path, bytes)) = partial {
+                    discard_partial_download(&state, &video.id, &path, bytes).await;
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Download one URL, retrying transient failures up to `max_retries` times
+/// with exponential backoff. Each retry resumes from the last synced byte.
+async fn download_with_retries(
+    state: Arc<AppState>,
+    client: Arc<reqwest::Client>,
+    host_adapter: Arc<dyn HostAdapter>,
+    video: VideoDownload,
+) -> Result<VideoDownload, Box<dyn Error + Send + Sync>> {
+    let mut attempt = 0;
+    loop {
+        let result =
+            download_video_progressive(state.clone(), client.clone(), host_adapter.clone(), video.clone()).await;
+        match result {
+            Err(e) if attempt < state.max_retries && is_transient(e.as_ref()) => {
+                let delay = retry_delay(attempt);
+                attempt += 1;
+                warn!(attempt, ?delay, error = %e, "Transient download failure, retrying");
+                tokio::time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Detach the files of the `evict` videos, marking them `evicted`. A file
+/// shared with a video we keep (coalesced downloads) stays. Returns the files
+/// to delete with their sizes, a shared file counted once, and the thumbnails
+/// to delete unless `keep_thumbnails`.
+fn take_evicted_files(
+    discovered: &mut HashMap<String, VideoDownload>,
+    evict: &[String],
+    keep_thumbnails: bool,
+) -> (HashMap<PathBuf, u64>, Vec<PathBuf>) {
+    let kept_paths: Vec<PathBuf> = discovered
+        .values()
+        .filter(|v| !evict.contains(&v.id))
+        .filter_map(|v| v.local_path.clone())
+        .collect();
+
+    let mut paths_to_remove: HashMap<PathBuf, u64> = HashMap::new();
+    let mut thumbs_to_remove = Vec::new();
+    for id in evict {
+        let Some(video) = discovered.get_mut(id) else {
+            continue;
+        };
+        if video.local_path.as_ref().map_or(true, |p| kept_paths.contains(p)) {
+            continue;
+        }
+        // schedule removal
+        if let Some(local_path) = video.local_path.take() {
+            paths_to_remove.insert(local_path, video.downloaded_bytes);
+            video.downloaded_bytes = 0;
+            video.synced_bytes = 0;
+            video.downloaded_at = None;
+            video.evicted = true;
+            if !keep_thumbnails {
+                thumbs_to_remove.extend(video.thumbnail_path.take());
+            }
+        }
+    }
+    (paths_to_remove, thumbs_to_remove)
+}
+
+/// Whether a finished download of `received` bytes is too small to keep: under
+/// `min_valid_bytes`, or short of a `known_length` announced for it.
+fn download_too_small(received: u64, min_valid_bytes: u64, known_length: Option<u64>) -> bool {
+    received < min_valid_bytes || known_length.is_some_and(|known| received < known)
+}
+
+/// Wait before retry number `attempt` (0-based): `RETRY_BASE_DELAY`, doubling
+/// each time, capped at `MAX_RETRY_DELAY`.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Point every video that attached to `owner_id`'s transfer at the same file
+/// and metadata.
+async fn share_completed_download(state: &AppState, owner_id: &str, attached: &[String]) {
+    let mut discovered = state.discovered_videos.write().await;
+    let Some(owner) = discovered.get(owner_id).cloned() else {
+        return;
+    };
+    for id in attached.iter().filter(|id| id.as_str() != owner_id) {
+        if let Some(v) = discovered.get_mut(id) {
+            adopt_download(v, &owner);
+            state.publish_progress(v);
         }
     }
 }
 
+/// Point `video` at the file `owner`'s transfer wrote, with its metadata.
+fn adopt_download(video: &mut VideoDownload, owner: &VideoDownload) {
+    video.local_path = owner.local_path.clone();
+    video.downloading = false;
+    video.downloaded_bytes = owner.downloaded_bytes;
+    video.synced_bytes = owner.synced_bytes;
+    video.content_length = owner.content_length;
+    video.length_seconds = owner.length_seconds;
+    video.format = owner.format.clone();
+    video.mime_type = owner.mime_type.clone();
+    video.width = owner.width;
+    video.height = owner.height;
+    video.bitrate_bps = owner.bitrate_bps;
+    video.thumbnail_path = owner.thumbnail_path.clone();
+    video.downloaded_at = owner.downloaded_at;
+}
+
+/// Attach `id` to a running transfer of `url`, if there is one. Returns
+/// whether it attached; the caller starts the transfer otherwise.
+fn attach_to_in_flight(in_flight: &mut HashMap<String, Vec<String>>, url: &str, id: &str) -> bool {
+    let Some(ids) = in_flight.get_mut(url) else {
+        return false;
+    };
+    if !ids.iter().any(|i| i == id) {
+        ids.push(id.to_string());
+    }
+    true
+}
+
+/// Build the HTTP client used for HEAD probes and downloads.
+///
+/// Automatic decompression is disabled on purpose: we want the bytes on disk to
+/// be exactly the bytes the imeta `x` hash was computed over, and we compare the
+/// bytes received against `Content-Length` (which, for an encoded response, is
+/// the *compressed* size). Requests also send `Accept-Encoding: identity` so that
+/// HEAD-derived and GET-derived lengths describe the same representation.
+fn build_download_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new())
+}
+
 // ===========================
 // The two-phase stable sorting
 // ===========================
 
-/// Utility to check if a `VideoDownload` effectively has a local file.
+/// Utility to check if a `VideoDownload` effectively has a local file. An
+/// interrupted partial doesn't count, so it's queued again and resumed.
 fn has_local_file(video: &VideoDownload) -> bool {
-    video.local_path.is_some()
+    video.local_path.is_some() && !video.is_partial()
+}
+
+/// Whether a video still has to be fetched. One evicted for being too far
+/// behind only counts again while it's in `wanted` (being watched or
+/// prefetched); otherwise every eviction would be undone on the next pass.
+fn needs_download(video: &VideoDownload, wanted: &[String]) -> bool {
+    !has_local_file(video) && (!video.evicted || wanted.contains(&video.id))
 }
 
 /// Sort videos in a stable manner such that:
 ///
-/// 1. We first take enough videos to meet:
-///    - `target_videos_ahead` count, OR
+/// 1. We first take videos (in their incoming order) until *both*
+///    - `target_videos_ahead` count, AND
 ///    - `target_minutes_ahead` total length
-///    sorting them by **small content_length ascending, then high score descending**.
+///    are met, sorting them by **small content_length ascending, then high score descending**.
 ///
 /// 2. Once we have satisfied the target, subsequent videos are sorted
 ///    by **score descending, then small content_length ascending**.
 ///
+/// Edge cases (see the tests):
+///    - Fewer videos than the target: everything is phase 1, so the whole list
+///      comes out small-first.
+///    - Exactly `target_videos_ahead` videos whose lengths cover
+///      `target_minutes_ahead`: still all phase 1; phase 2 starts with the next one.
+///    - A video with unknown `length_seconds` counts towards the video target but
+///      adds no minutes, so a list of unknown-length videos stays in phase 1.
+///    - An unknown `content_length` (e.g. a chunked response) is estimated as
+///      `length_seconds × assumed_bitrate_bps / 8`, see `sort_size`. With neither
+///      known it sorts as `u64::MAX`, i.e. after every known size; equal
+///      scores/sizes keep their incoming order.
+///
 /// We do this by:
 ///    - Partitioning the videos into (needed_for_target, leftover)
 ///      using a running count of how many videos we've added and how many total minutes
@@ -281,6 +1337,7 @@ pub fn sort_videos_for_download(
     videos: &mut Vec<VideoDownload>,
     target_videos_ahead: usize,
     target_minutes_ahead: f64,
+    assumed_bitrate_bps: u64,
 ) {
     // Step 1) partition into "needed" vs "leftover"
     let (mut needed, mut leftover) = partition_for_target(videos, target_videos_ahead, target_minutes_ahead);
@@ -289,8 +1346,8 @@ pub fn sort_videos_for_download(
     //  needed:  by content_length ASC, then score DESC
     needed.sort_by(|a, b| {
         // content_length ASC
-        let a_len = a.content_length.unwrap_or(u64::MAX);
-        let b_len = b.content_length.unwrap_or(u64::MAX);
+        let a_len = sort_size(a, assumed_bitrate_bps);
+        let b_len = sort_size(b, assumed_bitrate_bps);
         match a_len.cmp(&b_len) {
             std::cmp::Ordering::Equal => {
                 // score DESC
@@ -306,8 +1363,8 @@ pub fn sort_videos_for_download(
         match b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal) {
             std::cmp::Ordering::Equal => {
                 // content_length ASC
-                let a_len = a.content_length.unwrap_or(u64::MAX);
-                let b_len = b.content_length.unwrap_or(u64::MAX);
+                let a_len = sort_size(a, assumed_bitrate_bps);
+                let b_len = sort_size(b, assumed_bitrate_bps);
                 a_len.cmp(&b_len)
             }
             other => other,
@@ -319,8 +1376,55 @@ pub fn sort_videos_for_download(
     *videos = needed;
 }
 
-/// Given a list of videos, pick as many as needed to satisfy either the
-/// `target_videos_ahead` count or the `target_minutes_ahead` total length.
+/// Newest publish time first; videos without one (restored from an older
+/// snapshot) go last. Stable, so equal times keep their discovery order.
+pub fn sort_videos_by_published(videos: &mut [VideoDownload]) {
+    videos.sort_by_key(|v| std::cmp::Reverse(v.nostr.published_at));
+}
+
+/// Keep one variant per Nostr event: the lowest known bitrate, else the
+/// smallest resolution, else the first seen. Videos without an event id are
+/// left alone.
+fn keep_cheapest_variants(videos: &mut Vec<VideoDownload>) {
+    fn cost(v: &VideoDownload) -> (u8, f64) {
+        match (v.bitrate_bps, v.width, v.height) {
+            (Some(bps), _, _) => (0, bps),
+            (None, Some(w), Some(h)) => (1, w as f64 * h as f64),
+            _ => (2, 0.0),
+        }
+    }
+
+    let mut cheapest: HashMap<String, (u8, f64, String)> = HashMap::new();
+    for v in videos.iter().filter(|v| !v.nostr.event_id.is_empty()) {
+        let (rank, value) = cost(v);
+        let entry = cheapest
+            .entry(v.nostr.event_id.clone())
+            .or_insert((rank, value, v.id.clone()));
+        if (rank, value) < (entry.0, entry.1) {
+            *entry = (rank, value, v.id.clone());
+        }
+    }
+    videos.retain(|v| {
+        v.nostr.event_id.is_empty()
+            || cheapest.get(&v.nostr.event_id).is_some_and(|(_, _, id)| id == &v.id)
+    });
+}
+
+/// Size used for ordering. Hosts using chunked transfer send no
+/// Content-Length, so fall back to an estimate from the duration at
+/// `assumed_bitrate_bps` (bits per second) rather than treating the video as
+/// infinitely large. Such downloads are only limited by the running storage
+/// budget checked per chunk.
+fn sort_size(video: &VideoDownload, assumed_bitrate_bps: u64) -> u64 {
+    match (video.content_length, video.length_seconds) {
+        (Some(len), _) => len,
+        (None, Some(secs)) if secs > 0.0 => (secs * assumed_bitrate_bps as f64 / 8.0) as u64,
+        _ => u64::MAX,
+    }
+}
+
+/// Given a list of videos, pick as many as needed to satisfy both the
+/// `target_videos_ahead` count and the `target_minutes_ahead` total length.
 /// Return (needed, leftover).
 fn partition_for_target(
     videos: &[VideoDownload],
@@ -355,46 +1459,146 @@ fn partition_for_target(
 async fn download_video_progressive(
     state: Arc<AppState>,
     client: Arc<reqwest::Client>,
+    host_adapter: Arc<dyn HostAdapter>,
     video: VideoDownload,
 ) -> Result<(VideoDownload), Box<dyn Error + Send + Sync>> {
-    let mut resp = client.get(&video.url).send().await?;
-    if !resp.status().is_success() {
-        return Err(format!("HTTP request failed with status: {}", resp.status()).into());
+    // An earlier attempt (or a previous run) left a partial file: ask only
+    // for what's missing after its last durable checkpoint.
+    let mut partial = {
+        let discovered = state.discovered_videos.read().await;
+        discovered.get(&video.id).and_then(|v| {
+            let path = v.local_path.clone()?;
+            Some((path, v.synced_bytes, v.downloaded_bytes, v.format.is_some()))
+        })
+    };
+    // Nothing durable to resume from: drop it and start over.
+    if let Some((path, synced, downloaded, _)) = partial.clone() {
+        if synced == 0 || !state.store.exists(&path).await {
+            discard_partial_download(&state, &video.id, &path, downloaded).await;
+            partial = None;
+        }
+    }
+
+    let request_url = host_adapter.rewrite_url(&video.url);
+    let mut request = client
+        .get(&request_url)
+        .headers(host_adapter.headers(&request_url))
+        .header(ACCEPT_ENCODING, "identity");
+    if let Some((_, offset, _, _)) = &partial {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    let mut resp = request.send().await?;
+    let status = resp.status();
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        return Err(TransientError::Status(status).into());
+    }
+    if !status.is_success() {
+        return Err(format!("HTTP request failed with status: {}", status).into());
+    }
+
+    // Hosts that ignore Range send the whole file again (or some other part
+    // of it); start over then.
+    let content_range = resp
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_content_range_start);
+    let resumed = match partial {
+        Some(partial) if resumes_at(status, content_range, partial.1) => Some(partial),
+        Some((path, _, downloaded, _)) => {
+            discard_partial_download(&state, &video.id, &path, downloaded).await;
+            None
+        }
+        None => None,
+    };
+    let resume_offset = resumed.as_ref().map_or(0, |(_, offset, _, _)| *offset);
+    if let Some(encoding) = resp.headers().get(CONTENT_ENCODING) {
+        // We store the raw bytes regardless; flag it since the file likely won't play.
+        warn!(content_encoding = ?encoding, "Served with Content-Encoding despite identity request");
     }
 
-    // Possibly store content_length if available:
-    if let Some(cl) = resp.content_length() {
-        let mut videos_guard = state.discovered_videos.lock().await;
+    // Possibly store content_length if available (a resumed response only
+    // counts the remainder):
+    let expected_length = resp.content_length().map(|cl| cl + resume_offset);
+    if let Some(cl) = expected_length {
+        let mut videos_guard = state.discovered_videos.write().await;
         if let Some(video_mut) = videos_guard.get_mut(&video.id) {
             video_mut.content_length = Some(cl);
+            video_mut.update_bitrate();
         }
     }
 
-    // Create a unique file path
-    let file_name = format!("{}.mp4", Uuid::new_v4());
-    let file_path = std::env::temp_dir().join(file_name);
-
-    // Store the local_path
-    {
-        let mut discovered = state.discovered_videos.lock().await;
-        if let Some(video_mut) = discovered.get_mut(&video.id) {
-            video_mut.local_path = Some(file_path.clone());
+    let mut pending_chunk = next_chunk(&mut resp, &state, &video.url).await?;
+    let mut last_progress_event: Option<std::time::Instant> = None;
+    let (file_path, mut file, mut downloaded_bytes, mut metadata_extracted) = match resumed {
+        Some((path, offset, previously_downloaded, has_metadata)) => {
+            let file = state.store.reopen(&path, offset).await?;
+            // Bytes past the checkpoint get written again; stop counting them.
+            {
+                let mut storage = state.current_storage_bytes.lock().await;
+                *storage = storage.saturating_sub(previously_downloaded.saturating_sub(offset));
+            }
+            {
+                let mut discovered = state.discovered_videos.write().await;
+                if let Some(video_mut) = discovered.get_mut(&video.id) {
+                    video_mut.downloaded_bytes = offset;
+                }
+            }
+            info!(offset, "Resuming download");
+            (path, file, offset, has_metadata)
         }
-    }
+        None => {
+            // Peek at the first chunk so the file extension matches the actual container
+            // (WebM served as `.mp4` confuses players that sniff by extension).
+            let sniffed = pending_chunk.as_deref().and_then(container_extension);
+            let extension = sniffed.unwrap_or("mp4");
+
+            // Create a unique file path
+            let file_name = format!("{}.{}", Uuid::new_v4(), extension);
+            let file_path = state.download_dir.join(file_name);
+
+            // Store the local_path
+            {
+                let mut discovered = state.discovered_videos.write().await;
+                if let Some(video_mut) = discovered.get_mut(&video.id) {
+                    video_mut.local_path = Some(file_path.clone());
+                    // What the bytes are beats what the event claimed.
+                    if let Some(mime) = sniffed.and_then(mime_for_extension) {
+                        video_mut.mime_type = Some(mime.to_string());
+                    }
+                }
+            }
 
-    let mut file = File::create(&file_path).await?;
+            let file = state.store.put(&file_path).await?;
+            (file_path, file, 0, false)
+        }
+    };
     let mut parse_buffer: Vec<u8> = Vec::new();
-    let mut downloaded_bytes = 0u64;
-    let mut metadata_extracted = false;
+    let mut parse_schedule = ParseSchedule::default();
+    let mut unsynced_bytes = 0u64;
 
     // Download in chunks
-    while let Some(chunk) = resp.chunk().await? {
+    loop {
+        let chunk = match pending_chunk.take() {
+            Some(chunk) => chunk,
+            None => match next_chunk(&mut resp, &state, &video.url).await? {
+                Some(chunk) => chunk,
+                None => break, // normal end of stream
+            },
+        };
+
+        // Stay under the shared bandwidth cap, if any.
+        if let Some(limit) = state.max_download_bytes_per_sec {
+            state.download_limiter.acquire(chunk.len() as u64, limit).await;
+        }
+
         // 1) Check storage budget
         {
             let mut storage = state.current_storage_bytes.lock().await;
-            if *storage + (chunk.len() as u64) > state.max_storage_bytes {
-                warn!("Storage budget exceeded while downloading {}", video.url);
-                return Err("Storage budget exceeded".into());
+            let max_storage_bytes = state.max_storage_bytes.load(Ordering::Relaxed);
+            if *storage + (chunk.len() as u64) > max_storage_bytes {
+                warn!(max_storage_bytes, "Storage budget exceeded");
+                return Err(StorageBudgetExceeded.into());
             }
             *storage += chunk.len() as u64;
         }
@@ -402,15 +1606,24 @@ async fn download_video_progressive(
         // 2) Write to disk
         file.write_all(&chunk).await?;
         downloaded_bytes += chunk.len() as u64;
+        unsynced_bytes += chunk.len() as u64;
+
+        // Periodically make the partial file durable so `synced_bytes` is a
+        // checkpoint we can resume from after a crash.
+        if unsynced_bytes >= state.fsync_every_bytes {
+            sync_checkpoint(&state, &video.id, &mut file, downloaded_bytes).await?;
+            unsynced_bytes = 0;
+        }
 
         // 3) Update progress
         {
-            let mut discovered = state.discovered_videos.lock().await;
+            let mut discovered = state.discovered_videos.write().await;
             if let Some(video_mut) = discovered.get_mut(&video.id) {
                 video_mut.downloaded_bytes = downloaded_bytes;
                 if video_mut.content_length.is_none() {
-                    if let Some(cl) = resp.content_length() {
+                    if let Some(cl) = expected_length {
                         video_mut.content_length = Some(cl);
+                        video_mut.update_bitrate();
                     }
                 }
 
@@ -431,79 +1644,384 @@ async fn download_video_progressive(
                         }
                     }
                 }
+
+                if last_progress_event.map_or(true, |at| now.duration_since(at) >= PROGRESS_EVENT_INTERVAL) {
+                    state.publish_progress(video_mut);
+                    last_progress_event = Some(now);
+                }
             }
         }
 
-        // Attempt to parse partial metadata (moov box)
-        if !metadata_extracted {
+        // Attempt to parse partial metadata (moov box). A resumed download
+        // only has the tail in memory; it's reparsed from storage at the end.
+        if !metadata_extracted && resume_offset == 0 {
             parse_buffer.extend_from_slice(&chunk);
-            match try_parse_mp4_in_blocking_thread(parse_buffer.clone()).await {
-                Ok(Some(metadata)) => {
-                    update_metadata(state.clone(), &video.id, &file_path, metadata).await;
-                    metadata_extracted = true;
-
-                    #[cfg(debug_server)]
-                    if let Ok(jpeg_data) = ffmpeg_extractor::extract_first_frame_to_jpeg(&parse_buffer) {
-                        let thumb_path = std::env::temp_dir()
-                            .join(format!("thumb_{}.jpg", Uuid::new_v4()));
-                        if let Err(e) = write_image_to_jpeg(&jpeg_data, &thumb_path).await {
-                            warn!("Could not write thumbnail: {}", e);
-                        } else {
-                            // Update discovered
-                            let mut list = state.discovered_videos.lock().await;
-                            if let Some(video_mut) = list.get_mut(&video.id) {
-                                video_mut.thumbnail_path = Some(thumb_path);
-                            }
+            // Re-parsing the whole buffer on every chunk is quadratic over the
+            // download, see `ParseSchedule`.
+            if let Some(end) = parse_schedule.next_attempt(&parse_buffer) {
+                match try_parse_mp4_in_blocking_thread(parse_buffer[..end].to_vec()).await {
+                    Ok(Some(metadata)) => {
+                        update_metadata(state.clone(), &video.id, &file_path, metadata).await;
+                        metadata_extracted = true;
+
+                        // Nothing more is accumulated; hand the bytes over.
+                        let video_data = std::mem::take(&mut parse_buffer);
+                        if state.generate_thumbnails {
+                            generate_thumbnail(&state, &video.id, &video.nostr.id, video_data).await;
                         }
                     }
-
+                    Ok(None) => { /* not enough data yet */ }
+                    Err(_) => { /* parse error is non-fatal here, ignore */ }
                 }
-                Ok(None) => { /* not enough data yet */ }
-                Err(_) => { /* parse error is non-fatal here, ignore */ }
             }
         }
     }
 
-    file.flush().await?;
+    sync_checkpoint(&state, &video.id, &mut file, downloaded_bytes).await?;
     drop(file);
 
+    // A short (or long) body means a truncated response or a misbehaving proxy.
+    // Retry: a short file is synced, so the retry resumes after it; a long one
+    // can't be trusted and is thrown away to be re-fetched cleanly.
+    if let Some(expected) = expected_length {
+        if downloaded_bytes != expected {
+            warn!(expected, received = downloaded_bytes, "Content-Length mismatch");
+            if downloaded_bytes > expected {
+                discard_partial_download(&state, &video.id, &file_path, downloaded_bytes).await;
+            }
+            return Err(TransientError::Truncated { expected, received: downloaded_bytes }.into());
+        }
+    }
+
+    // A 200 with an empty or tiny body would otherwise be served as a broken video.
+    // Fail instead, so the next URL (or a later retry) gets a chance.
+    let known_length = expected_length.or(video.content_length);
+    if download_too_small(downloaded_bytes, state.min_valid_bytes, known_length) {
+        warn!(received = downloaded_bytes, min_valid_bytes = state.min_valid_bytes, "Download suspiciously small");
+        discard_partial_download(&state, &video.id, &file_path, downloaded_bytes).await;
+        return Err(format!("Download too small: received {downloaded_bytes} bytes").into());
+    }
+
     // If never extracted metadata, parse final buffer
-    if !metadata_extracted {
+    if !metadata_extracted && resume_offset > 0 {
+        if let Err(e) = reparse_metadata(&state, &video.id).await {
+            warn!(error = %e, "Error parsing resumed download");
+        }
+    } else if !metadata_extracted {
         match try_parse_mp4_in_blocking_thread(parse_buffer).await {
             Ok(Some(metadata)) => {
-                info!("Parsed final MP4 for {} ({}s)", video.url, metadata.duration_seconds);
+                info!(duration_seconds = metadata.duration_seconds, "Parsed final MP4");
                 update_metadata(state.clone(), &video.id, &file_path, metadata).await;
             }
             Ok(None) => {
-                warn!("Could not parse MP4 metadata for {} (possibly no moov box)", video.url);
+                warn!("Could not parse MP4 metadata (possibly no moov box)");
             }
             Err(e) => {
-                warn!("Error parsing final MP4 data for {}: {e}", video.url);
+                warn!(error = %e, "Error parsing final MP4 data");
             }
         }
     }
 
     // Mark downloading = false in discovered
     {
-        let mut list = state.discovered_videos.lock().await;
+        let mut list = state.discovered_videos.write().await;
         if let Some(video_mut) = list.get_mut(&video.id) {
             video_mut.downloading = false;
+            video_mut.downloaded_at = Some(SystemTime::now());
+            state.publish_progress(video_mut);
         }
     }
 
-    debug!("Downloaded {} => size: {} bytes as {}", video.url, downloaded_bytes, video.id);
+    debug!(bytes = downloaded_bytes, "Download complete");
     let final_size = downloaded_bytes;
-    {
-        let mut discovered = state.discovered_videos.lock().await;
+    let completed = {
+        let mut discovered = state.discovered_videos.write().await;
         if let Some(v) = discovered.get_mut(&video.id) {
             if v.content_length.is_none() {
                 v.content_length = Some(final_size);
+                v.update_bitrate();
             }
         }
+        discovered.get(&video.id).cloned()
+    };
+    if let (Some(command), Some(completed)) = (&state.on_complete, completed) {
+        spawn_on_complete(command.clone(), completed);
     }
     Ok((video))
 }
 
+/// Run the user's `on_complete` command for a finished download, in the
+/// background. The command gets the video id and file path as arguments and
+/// the metadata as `TOKSTR_*` environment variables; a non-zero exit is logged.
+fn spawn_on_complete(command: PathBuf, video: VideoDownload) {
+    let path = video.local_path.clone().unwrap_or_default();
+    let mut cmd = tokio::process::Command::new(&command);
+    cmd.arg(&video.id)
+        .arg(&path)
+        .env("TOKSTR_VIDEO_ID", &video.id)
+        .env("TOKSTR_VIDEO_PATH", &path)
+        .env("TOKSTR_VIDEO_URL", &video.url)
+        .env("TOKSTR_TITLE", &video.nostr.title)
+        .env("TOKSTR_BYTES", video.downloaded_bytes.to_string());
+    if let Some(length) = video.length_seconds {
+        cmd.env("TOKSTR_LENGTH_SECONDS", length.to_string());
+    }
+    if let Some(format) = &video.format {
+        cmd.env("TOKSTR_FORMAT", format);
+    }
+    if let (Some(width), Some(height)) = (video.width, video.height) {
+        cmd.env("TOKSTR_WIDTH", width.to_string())
+            .env("TOKSTR_HEIGHT", height.to_string());
+    }
+
+    tokio::spawn(
+        async move {
+            match cmd.output().await {
+                Ok(output) if output.status.success() => {
+                    debug!("on_complete command finished");
+                }
+                Ok(output) => {
+                    warn!(
+                        status = %output.status,
+                        stderr = %String::from_utf8_lossy(&output.stderr).trim(),
+                        "on_complete command failed"
+                    );
+                }
+                Err(e) => {
+                    warn!(command = %command.display(), error = %e, "Failed to run on_complete command");
+                }
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+
+/// Read the next body chunk. A server that stops sending without closing the
+/// socket would block `chunk()` forever, so each read gets a stall timeout.
+/// `Ok(None)` is a normal end of stream.
+async fn next_chunk(
+    resp: &mut reqwest::Response,
+    state: &AppState,
+    url: &str,
+) -> Result<Option<Bytes>, Box<dyn Error + Send + Sync>> {
+    match tokio::time::timeout(state.download_stall_timeout, resp.chunk()).await {
+        Ok(Ok(chunk)) => Ok(chunk),
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            warn!(url, timeout = ?state.download_stall_timeout, "Download stalled");
+            Err(TransientError::Stalled(state.download_stall_timeout).into())
+        }
+    }
+}
+
+/// Guess a file extension from the first bytes of a container.
+fn container_extension(head: &[u8]) -> Option<&'static str> {
+    // ISO BMFF: [size]["ftyp"][major brand]
+    if head.len() >= 12 && &head[4..8] == b"ftyp" {
+        return Some(if &head[8..12] == b"qt  " { "mov" } else { "mp4" });
+    }
+    // EBML: the DocType tells WebM from generic Matroska
+    if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        let header = &head[..head.len().min(64)];
+        let is_webm = header.windows(4).any(|w| w == b"webm");
+        return Some(if is_webm { "webm" } else { "mkv" });
+    }
+    None
+}
+
+/// Extract the first frame of `video_data` and store it as the video's thumbnail.
+/// Thumbnails are cached by content `hash`, so one left from an earlier run (or
+/// another entry for the same file) is reused without extracting again.
+async fn generate_thumbnail(state: &AppState, video_id: &str, hash: &str, video_data: Vec<u8>) {
+    let cache = &state.thumbnail_cache;
+    let format = state.thumbnail_format;
+    let thumb_path = match cache.find(hash, format).await {
+        Some(existing) => existing,
+        None => {
+            let frame = match frames::extract_first_frame_to_jpeg_async(video_data).await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!(video_id, error = %e, "No thumbnail");
+                    return;
+                }
+            };
+            // The frame knows its size; fill it in if the container didn't say.
+            if frame.width > 0 && frame.height > 0 {
+                let mut list = state.discovered_videos.write().await;
+                if let Some(video) = list.get_mut(video_id) {
+                    video.width.get_or_insert(frame.width);
+                    video.height.get_or_insert(frame.height);
+                }
+            }
+            let thumb_path = cache.path_for(hash, format);
+            let written = match cache.ensure_dir().await {
+                Ok(()) => write_image(&frame.jpeg, &thumb_path, format),
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = written {
+                warn!(video_id, error = %e, "Could not write thumbnail");
+                return;
+            }
+            let removed = cache.enforce_cap().await.unwrap_or_else(|e| {
+                warn!(error = %e, "Could not trim thumbnail cache");
+                Vec::new()
+            });
+            forget_thumbnails(state, &removed).await;
+            thumb_path
+        }
+    };
+
+    let mut list = state.discovered_videos.write().await;
+    if let Some(video_mut) = list.get_mut(video_id) {
+        video_mut.thumbnail_path = Some(thumb_path);
+    }
+}
+
+/// Clear `thumbnail_path` on videos pointing at any of `removed`.
+async fn forget_thumbnails(state: &AppState, removed: &[PathBuf]) {
+    if removed.is_empty() {
+        return;
+    }
+    let mut list = state.discovered_videos.write().await;
+    for video in list.values_mut() {
+        if video.thumbnail_path.as_ref().is_some_and(|p| removed.contains(p)) {
+            video.thumbnail_path = None;
+        }
+    }
+}
+
+/// Delete a failed download's file, clear its `local_path`/progress and give the
+/// bytes back to the storage budget.
+async fn discard_partial_download(
+    state: &AppState,
+    video_id: &str,
+    file_path: &std::path::Path,
+    downloaded_bytes: u64,
+) {
+    let _ = state.store.delete(file_path).await;
+    {
+        let mut discovered = state.discovered_videos.write().await;
+        if let Some(video_mut) = discovered.get_mut(video_id) {
+            video_mut.local_path = None;
+            video_mut.downloaded_bytes = 0;
+            video_mut.synced_bytes = 0;
+        }
+    }
+    let mut storage = state.current_storage_bytes.lock().await;
+    *storage = storage.saturating_sub(downloaded_bytes);
+}
+
+/// Flush and fsync the partial file, then record `downloaded_bytes` as the
+/// durable checkpoint on the video.
+async fn sync_checkpoint(
+    state: &AppState,
+    video_id: &str,
+    file: &mut Box<dyn VideoWriter>,
+    downloaded_bytes: u64,
+) -> std::io::Result<()> {
+    file.sync().await?;
+
+    let mut discovered = state.discovered_videos.write().await;
+    if let Some(video_mut) = discovered.get_mut(video_id) {
+        video_mut.synced_bytes = downloaded_bytes;
+    }
+    Ok(())
+}
+
+/// Re-read a completed download from storage and re-extract its metadata into
+/// `discovered_videos`. Returns whether metadata was found; errors if the video
+/// is unknown or has no readable file.
+pub async fn reparse_metadata(
+    state: &Arc<AppState>,
+    video_id: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let path = {
+        let discovered = state.discovered_videos.read().await;
+        discovered
+            .get(video_id)
+            .and_then(|v| v.local_path.clone())
+            .ok_or("video not downloaded")?
+    };
+
+    let data = state.store.read_all(&path).await?;
+    let parsed = try_parse_mp4_in_blocking_thread(data)
+        .await
+        .map_err(|e| format!("MP4 parse error: {e}"))?;
+    match parsed {
+        Some(metadata) => {
+            info!("Reparsed metadata for {} ({}s)", video_id, metadata.duration_seconds);
+            update_metadata(state.clone(), video_id, &path, metadata).await;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// When `download_video_progressive` tries parsing its growing buffer: once
+/// as soon as a complete `moov` box is in (just the boxes up to it), otherwise
+/// every `PARSE_RETRY_BYTES`.
+#[derive(Debug, Default)]
+struct ParseSchedule {
+    /// Buffer length at the last attempt
+    last_parse_len: usize,
+    /// Whether the first complete `moov` box has been tried on its own
+    tried_moov: bool,
+}
+
+impl ParseSchedule {
+    /// How much of `buffer` to parse now, or `None` to wait for more bytes.
+    fn next_attempt(&mut self, buffer: &[u8]) -> Option<usize> {
+        let moov_end = complete_moov_end(buffer).filter(|_| !self.tried_moov);
+        if moov_end.is_none() && buffer.len().saturating_sub(self.last_parse_len) < PARSE_RETRY_BYTES {
+            return None;
+        }
+        self.tried_moov |= moov_end.is_some();
+        self.last_parse_len = buffer.len();
+        Some(moov_end.unwrap_or(buffer.len()))
+    }
+}
+
+/// First byte of a `Content-Range: bytes <start>-<end>/<total>` response header.
+fn parse_content_range_start(value: &str) -> Option<u64> {
+    let (start, _) = value.trim().strip_prefix("bytes ")?.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+/// Whether a response to a `bytes=<offset>-` request continues the partial
+/// file: a 206 starting at `offset`. A 206 without a Content-Range is taken at
+/// its word.
+fn resumes_at(status: StatusCode, content_range_start: Option<u64>, offset: u64) -> bool {
+    status == StatusCode::PARTIAL_CONTENT && content_range_start.map_or(true, |start| start == offset)
+}
+
+/// End offset of the top-level `moov` box if all of it is in `data`. Walks the
+/// box headers from the start, so it stops at the first incomplete box (e.g.
+/// an `mdat` still downloading in front of the `moov`).
+fn complete_moov_end(data: &[u8]) -> Option<usize> {
+    let mut offset = 0usize;
+    while offset + 8 <= data.len() {
+        let size = u32::from_be_bytes(data[offset..offset + 4].try_into().ok()?) as u64;
+        let box_type = &data[offset + 4..offset + 8];
+        let size = match size {
+            0 => return None, // extends to end of file
+            1 => u64::from_be_bytes(data.get(offset + 8..offset + 16)?.try_into().ok()?),
+            n => n,
+        };
+        if size < 8 {
+            return None;
+        }
+        let end = offset.checked_add(usize::try_from(size).ok()?)?;
+        if end > data.len() {
+            return None;
+        }
+        if box_type == b"moov" {
+            return Some(end);
+        }
+        offset = end;
+    }
+    None
+}
 
 fn parse_mp4_entire(parse_buffer: &[u8]) -> Result<Option<VideoMetadata>, Mp4Error> {
     let context = read_mp4(&mut std::io::Cursor::new(parse_buffer))?;
@@ -567,7 +2085,7 @@ async fn update_metadata(
     file_path: &std::path::Path,
     metadata: VideoMetadata,
 ) {
-    let mut list = state.discovered_videos.lock().await;
+    let mut list = state.discovered_videos.write().await;
     if let Some(video) = list.get_mut(video_id) {
         video.local_path = Some(file_path.to_path_buf());
         video.length_seconds = Some(metadata.duration_seconds);
@@ -578,6 +2096,7 @@ async fn update_metadata(
         if metadata.height > 0 {
             video.height = Some(metadata.height);
         }
+        video.update_bitrate();
     }
 }
 
@@ -597,7 +2116,12 @@ pub async fn fetch_content_lengths_in_parallel(
                     return video;
                 }
 
-                let response = match client.head(&video.url).send().await {
+                let response = match client
+                    .head(&video.url)
+                    .header(ACCEPT_ENCODING, "identity")
+                    .send()
+                    .await
+                {
                     Ok(resp) => resp,
                     Err(e) => {
                         warn!("HEAD request error for {}: {}", video.url, e);
@@ -617,6 +2141,7 @@ pub async fn fetch_content_lengths_in_parallel(
                     .and_then(|s| s.parse::<u64>().ok())
                 {
                     video.content_length = Some(length);
+                    video.update_bitrate();
                 }
 
                 video
@@ -626,3 +2151,724 @@ pub async fn fetch_content_lengths_in_parallel(
         .collect()
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::store::{MemoryStore, VideoStore};
+
+    fn sized(id: &str, content_length: Option<u64>, length_seconds: Option<f64>, score: f64) -> VideoDownload {
+        let mut v = VideoDownload::for_test(id);
+        v.content_length = content_length;
+        v.length_seconds = length_seconds;
+        v.score = score;
+        v
+    }
+
+    fn ids(videos: &[VideoDownload]) -> Vec<&str> {
+        videos.iter().map(|v| v.id.as_str()).collect()
+    }
+
+    #[test]
+    fn sort_size_estimates_from_length_and_bitrate() {
+        assert_eq!(sort_size(&sized("a", Some(1234), Some(60.0), 0.0), 8_000), 1234);
+        // 60 s at 8 kbit/s.
+        assert_eq!(sort_size(&sized("a", None, Some(60.0), 0.0), 8_000), 60_000);
+        assert_eq!(sort_size(&sized("a", None, Some(0.0), 0.0), 8_000), u64::MAX);
+        assert_eq!(sort_size(&sized("a", None, None, 0.0), 8_000), u64::MAX);
+        // A chunked response sorts among known sizes instead of last.
+        let mut videos = vec![
+            sized("big", Some(100_000), Some(60.0), 1.0),
+            sized("chunked", None, Some(30.0), 1.0),
+            sized("small", Some(10_000), Some(60.0), 1.0),
+        ];
+        sort_videos_for_download(&mut videos, 10, 0.0, 8_000);
+        assert_eq!(ids(&videos), ["small", "chunked", "big"]);
+    }
+
+    #[test]
+    fn sort_phase_one_ends_at_video_target() {
+        let mut videos = vec![
+            sized("a", Some(300), Some(600.0), 1.0),
+            sized("b", Some(100), Some(600.0), 1.0),
+            sized("c", Some(50), Some(600.0), 1.0),
+            sized("d", Some(500), Some(600.0), 9.0),
+        ];
+        sort_videos_for_download(&mut videos, 2, 1.0, 0);
+        // a, b are phase 1 (small first); c, d phase 2 (high score first).
+        assert_eq!(ids(&videos), ["b", "a", "d", "c"]);
+    }
+
+    #[test]
+    fn sort_exactly_the_target_is_all_phase_one() {
+        let videos = vec![
+            sized("a", Some(300), Some(60.0), 9.0),
+            sized("b", Some(100), Some(60.0), 1.0),
+            sized("c", Some(200), Some(60.0), 5.0),
+        ];
+        // Three videos covering three minutes: all phase 1, smallest first
+        // whatever the score.
+        let mut exact = videos.clone();
+        sort_videos_for_download(&mut exact, 3, 3.0, 0);
+        assert_eq!(ids(&exact), ["b", "c", "a"]);
+
+        // One more starts phase 2.
+        let mut one_more = videos;
+        one_more.push(sized("d", Some(50), Some(60.0), 1.0));
+        sort_videos_for_download(&mut one_more, 3, 3.0, 0);
+        assert_eq!(ids(&one_more), ["b", "c", "a", "d"]);
+    }
+
+    #[test]
+    fn sort_phase_one_ends_at_minutes_target() {
+        let mut videos = vec![
+            sized("a", Some(300), Some(120.0), 1.0),
+            sized("b", Some(100), Some(120.0), 1.0),
+            sized("c", Some(200), Some(120.0), 1.0),
+            sized("d", Some(500), Some(120.0), 9.0),
+        ];
+        // One video meets the count, but it takes three to cover 5 minutes.
+        sort_videos_for_download(&mut videos, 1, 5.0, 0);
+        assert_eq!(ids(&videos), ["b", "c", "a", "d"]);
+    }
+
+    #[test]
+    fn sort_phase_two_orders_by_score_then_size() {
+        let mut videos = vec![
+            sized("a", Some(10), Some(60.0), 1.0),
+            sized("b", Some(100), Some(60.0), 5.0),
+            sized("c", Some(50), Some(60.0), 5.0),
+            sized("d", Some(1), Some(60.0), 3.0),
+        ];
+        sort_videos_for_download(&mut videos, 0, 0.0, 0);
+        assert_eq!(ids(&videos), ["c", "b", "d", "a"]);
+    }
+
+    #[test]
+    fn sort_estimates_unknown_sizes_from_bitrate() {
+        let mut videos = vec![
+            sized("unknown", None, None, 1.0),
+            sized("estimated", None, Some(10.0), 1.0),
+            sized("known", Some(5_000), Some(10.0), 1.0),
+            sized("unknown2", None, None, 1.0),
+        ];
+        // "estimated" is 10 s at 8 kbit/s, i.e. 10 000 bytes; unknowns go last
+        // in their incoming order.
+        sort_videos_for_download(&mut videos, 10, 0.0, 8_000);
+        assert_eq!(ids(&videos), ["known", "estimated", "unknown", "unknown2"]);
+    }
+
+    #[test]
+    fn sort_unknown_lengths_add_no_minutes() {
+        let mut videos = vec![
+            sized("a", Some(300), None, 9.0),
+            sized("b", Some(200), None, 1.0),
+            sized("c", Some(100), None, 5.0),
+        ];
+        // The video target is met after one, but the minutes never are.
+        sort_videos_for_download(&mut videos, 1, 1.0, 0);
+        assert_eq!(ids(&videos), ["c", "b", "a"]);
+    }
+
+    /// A top-level box with a zeroed payload of `payload` bytes.
+    fn mp4_box(kind: &[u8; 4], payload: usize) -> Vec<u8> {
+        let mut data = ((payload + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.resize(payload + 8, 0);
+        data
+    }
+
+    fn mp4(boxes: &[(&[u8; 4], usize)]) -> Vec<u8> {
+        boxes.iter().flat_map(|(kind, payload)| mp4_box(kind, *payload)).collect()
+    }
+
+    /// Feed `data` to a `ParseSchedule` `chunk` bytes at a time, returning
+    /// (buffer length, parse end) for every attempt.
+    fn parse_attempts(data: &[u8], chunk: usize) -> Vec<(usize, usize)> {
+        let mut schedule = ParseSchedule::default();
+        let mut attempts = Vec::new();
+        let mut len = 0;
+        while len < data.len() {
+            len = (len + chunk).min(data.len());
+            if let Some(end) = schedule.next_attempt(&data[..len]) {
+                attempts.push((len, end));
+            }
+        }
+        attempts
+    }
+
+    #[test]
+    fn moov_before_mdat() {
+        let data = mp4(&[(b"ftyp", 8), (b"moov", 100), (b"mdat", 1000)]);
+        assert_eq!(complete_moov_end(&data), Some(124));
+        // The mdat still downloading doesn't matter.
+        assert_eq!(complete_moov_end(&data[..130]), Some(124));
+    }
+
+    #[test]
+    fn moov_after_mdat() {
+        let data = mp4(&[(b"ftyp", 8), (b"mdat", 1000), (b"moov", 100)]);
+        assert_eq!(complete_moov_end(&data), Some(data.len()));
+        assert_eq!(complete_moov_end(&data[..1100]), None);
+    }
+
+    #[test]
+    fn moov_split_across_chunks() {
+        let data = mp4(&[(b"ftyp", 8), (b"moov", 100), (b"mdat", 1000)]);
+        for len in 0..124 {
+            assert_eq!(complete_moov_end(&data[..len]), None, "{len} bytes");
+        }
+        assert_eq!(complete_moov_end(&data[..124]), Some(124));
+    }
+
+    #[test]
+    fn moov_behind_truncated_or_invalid_box() {
+        // Header cut off, size 0 ("to the end of the file") and a size too
+        // small for its own header.
+        assert_eq!(complete_moov_end(&mp4_box(b"ftyp", 8)[..6]), None);
+        let mut to_end = mp4(&[(b"mdat", 10), (b"moov", 10)]);
+        to_end[..4].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(complete_moov_end(&to_end), None);
+        let mut tiny = to_end.clone();
+        tiny[..4].copy_from_slice(&4u32.to_be_bytes());
+        assert_eq!(complete_moov_end(&tiny), None);
+
+        // A 64-bit size is followed to the box after it.
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"mdat");
+        large.extend_from_slice(&24u64.to_be_bytes());
+        large.resize(24, 0);
+        large.extend(mp4_box(b"moov", 10));
+        assert_eq!(complete_moov_end(&large), Some(large.len()));
+        assert_eq!(complete_moov_end(&large[..12]), None);
+    }
+
+    #[test]
+    fn parse_once_moov_is_in() {
+        let data = mp4(&[(b"ftyp", 8), (b"moov", 5000), (b"mdat", PARSE_RETRY_BYTES + 10_000)]);
+        let moov_end = 16 + 5008;
+        let attempts = parse_attempts(&data, 1024);
+        // First as soon as the chunk completing the moov arrives, up to its end;
+        // then only once another `PARSE_RETRY_BYTES` are in.
+        assert_eq!(attempts[0], (6144, moov_end));
+        assert_eq!(attempts[1], (6144 + PARSE_RETRY_BYTES, 6144 + PARSE_RETRY_BYTES));
+        assert_eq!(attempts.len(), 2);
+    }
+
+    #[test]
+    fn parse_every_retry_bytes_without_moov() {
+        let data = mp4(&[(b"ftyp", 8), (b"mdat", 3 * PARSE_RETRY_BYTES), (b"moov", 100)]);
+        let chunk = 64 * 1024;
+        let attempts = parse_attempts(&data, chunk);
+        let lens: Vec<usize> = attempts.iter().map(|&(len, _)| len).collect();
+        assert_eq!(lens, [PARSE_RETRY_BYTES, 2 * PARSE_RETRY_BYTES, 3 * PARSE_RETRY_BYTES, data.len()]);
+        // Without a complete moov the whole buffer is parsed.
+        assert!(attempts[..3].iter().all(|&(len, end)| len == end));
+        // The final chunk completes the moov box.
+        assert_eq!(attempts[3], (data.len(), data.len()));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        let delays: Vec<u64> = (0..7).map(|attempt| retry_delay(attempt).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn content_range_start() {
+        assert_eq!(parse_content_range_start("bytes 1000-1999/2000"), Some(1000));
+        assert_eq!(parse_content_range_start("bytes 0-0/*"), Some(0));
+        assert_eq!(parse_content_range_start("bytes */2000"), None);
+        assert_eq!(parse_content_range_start("items 0-1/2"), None);
+        assert_eq!(parse_content_range_start("bytes x-1/2"), None);
+    }
+
+    #[test]
+    fn resume_needs_partial_content_at_the_offset() {
+        assert!(resumes_at(StatusCode::PARTIAL_CONTENT, Some(1000), 1000));
+        assert!(resumes_at(StatusCode::PARTIAL_CONTENT, None, 1000));
+        // Range ignored: the whole file again.
+        assert!(!resumes_at(StatusCode::OK, None, 1000));
+        assert!(!resumes_at(StatusCode::OK, Some(1000), 1000));
+        // Some other part of the file.
+        assert!(!resumes_at(StatusCode::PARTIAL_CONTENT, Some(0), 1000));
+    }
+
+    #[test]
+    fn second_start_of_a_url_attaches() {
+        let mut in_flight = HashMap::new();
+        let url = "https://example.com/a.mp4";
+        assert!(!attach_to_in_flight(&mut in_flight, url, "a"));
+        in_flight.insert(url.to_string(), vec!["a".to_string()]);
+
+        assert!(attach_to_in_flight(&mut in_flight, url, "b"));
+        assert!(attach_to_in_flight(&mut in_flight, url, "b"));
+        assert!(attach_to_in_flight(&mut in_flight, url, "a"));
+        assert_eq!(in_flight[url], ["a", "b"]);
+        assert!(!attach_to_in_flight(&mut in_flight, "https://example.com/other.mp4", "b"));
+    }
+
+    #[test]
+    fn attached_video_shares_the_owners_file() {
+        let mut owner = VideoDownload::for_test("a");
+        owner.local_path = Some(PathBuf::from("a.mp4"));
+        owner.downloaded_bytes = 100;
+        owner.synced_bytes = 100;
+        owner.content_length = Some(100);
+        owner.length_seconds = Some(12.0);
+        owner.downloaded_at = Some(SystemTime::UNIX_EPOCH);
+
+        let mut attached = VideoDownload::for_test("b");
+        attached.downloading = true;
+        adopt_download(&mut attached, &owner);
+        assert_eq!(attached.local_path, owner.local_path);
+        assert!(!attached.downloading);
+        assert_eq!((attached.downloaded_bytes, attached.content_length), (100, Some(100)));
+        assert_eq!(attached.length_seconds, Some(12.0));
+        assert_eq!(attached.downloaded_at, owner.downloaded_at);
+        assert!(has_local_file(&attached));
+    }
+
+    /// Answer one HTTP request on a local port with `response`, verbatim.
+    async fn serve_once(response: Vec<u8>) -> String {
+        serve(response, false).await
+    }
+
+    /// Like `serve_once`, but then keep the connection open without sending
+    /// anything more.
+    async fn serve_then_hang(response: Vec<u8>) -> String {
+        serve(response, true).await
+    }
+
+    async fn serve(response: Vec<u8>, hang: bool) -> String {
+        use tokio::io::AsyncReadExt;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = socket.read(&mut request).await;
+            socket.write_all(&response).await.unwrap();
+            if hang {
+                std::future::pending::<()>().await;
+            }
+            let _ = socket.shutdown().await;
+        });
+        format!("http://{addr}/video.mp4")
+    }
+
+    /// A `200 OK` with the given extra header lines and body.
+    fn http_response(headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut response = format!("HTTP/1.1 200 OK\r\n{headers}Connection: close\r\n\r\n").into_bytes();
+        response.extend_from_slice(body);
+        response
+    }
+
+    /// An offline `AppState` writing into a fresh `MemoryStore`.
+    fn memory_state(configure: impl FnOnce(&mut AppState)) -> (Arc<AppState>, MemoryStore) {
+        let store = MemoryStore::new();
+        let mut state = AppState::for_test(Arc::new(store.clone()));
+        configure(&mut state);
+        (Arc::new(state), store)
+    }
+
+    /// Add a video served at `url` to `discovered_videos`.
+    async fn discover(state: &AppState, id: &str, url: &str) -> VideoDownload {
+        let mut video = VideoDownload::for_test(id);
+        video.url = url.to_string();
+        video.urls = vec![url.to_string()];
+        state.discovered_videos.write().await.insert(id.to_string(), video.clone());
+        video
+    }
+
+    async fn download(state: &Arc<AppState>, video: VideoDownload) -> Result<VideoDownload, Box<dyn Error + Send + Sync>> {
+        let client = Arc::new(build_download_client());
+        download_video_progressive(Arc::clone(state), client, Arc::new(NoopHostAdapter), video).await
+    }
+
+    #[tokio::test]
+    async fn download_into_memory_store() {
+        let (state, store) = memory_state(|_| {});
+        let mut events = state.events.subscribe();
+        let body = vec![7u8; 10_000];
+        let url = serve_once(http_response("Content-Length: 10000\r\n", &body)).await;
+        let video = discover(&state, "a", &url).await;
+        download(&state, video).await.unwrap();
+
+        let done = state.discovered_videos.read().await["a"].clone();
+        assert_eq!(store.contents(done.local_path.as_ref().unwrap()), Some(body));
+        assert_eq!((done.downloaded_bytes, done.synced_bytes), (10_000, 10_000));
+        assert_eq!(done.content_length, Some(10_000));
+        assert_eq!(*state.current_storage_bytes.lock().await, 10_000);
+        // The first chunk opens the speed window; a rate needs a second of data.
+        assert!(done.last_speed_update_instant.is_some());
+        assert_eq!(done.download_speed_bps, 0.0);
+
+        let last = std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                StateEvent::Progress(progress) => Some(progress),
+                _ => None,
+            })
+            .last()
+            .unwrap();
+        assert_eq!((last.downloaded_bytes, last.total_bytes), (10_000, Some(10_000)));
+    }
+
+    #[tokio::test]
+    async fn download_stops_at_the_storage_budget() {
+        let (state, store) = memory_state(|_| {});
+        state.max_storage_bytes.store(5_000, Ordering::Relaxed);
+        *state.current_storage_bytes.lock().await = 1_000;
+        let body = vec![7u8; 10_000];
+        let url = serve_once(http_response("Content-Length: 10000\r\n", &body)).await;
+        let video = discover(&state, "a", &url).await;
+
+        let err = download(&state, video).await.unwrap_err();
+        assert!(err.is::<StorageBudgetExceeded>());
+        // Only what was written before the cutoff is counted.
+        let path = state.discovered_videos.read().await["a"].local_path.clone().unwrap();
+        let written = store.contents(&path).unwrap().len() as u64;
+        let storage = *state.current_storage_bytes.lock().await;
+        assert_eq!(storage, 1_000 + written);
+        assert!(storage <= 5_000);
+    }
+
+    #[tokio::test]
+    async fn truncated_download_fails_and_is_deleted() {
+        let (state, store) = memory_state(|state| state.max_retries = 0);
+        // The connection closes 100 bytes into a 10000 byte body.
+        let url = serve_once(http_response("Content-Length: 10000\r\n", &[7u8; 100])).await;
+        let video = discover(&state, "a", &url).await;
+
+        let client = Arc::new(build_download_client());
+        let err = download_with_alternates(Arc::clone(&state), client, Arc::new(NoopHostAdapter), video)
+            .await
+            .unwrap_err();
+        // Retried (resuming after what arrived) while retries are left.
+        assert!(is_transient(err.as_ref()), "{err}");
+        // Given up: nothing left behind.
+        assert!(store.keys().is_empty());
+        let video = state.discovered_videos.read().await["a"].clone();
+        assert_eq!((video.local_path, video.downloaded_bytes), (None, 0));
+        assert_eq!(*state.current_storage_bytes.lock().await, 0);
+    }
+
+    /// Wait until the manager has given up on `id`.
+    async fn wait_for_failure(manager: &DownloadManager, id: &str) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !manager.failed.lock().await.contains(id) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_download_times_out() {
+        let (state, _store) = memory_state(|state| state.download_stall_timeout = Duration::from_millis(100));
+        // 100 bytes of 10000, then silence on an open connection.
+        let url = serve_then_hang(http_response("Content-Length: 10000\r\n", &[7u8; 100])).await;
+        let video = discover(&state, "a", &url).await;
+
+        let err = download(&state, video).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<TransientError>(), Some(TransientError::Stalled(_))), "{err}");
+        // What arrived before the stall is kept for the retry to resume.
+        assert_eq!(state.discovered_videos.read().await["a"].downloaded_bytes, 100);
+    }
+
+    #[tokio::test]
+    async fn failed_download_is_not_queued_again() {
+        let (state, _store) = memory_state(|state| state.max_retries = 0);
+        let url = serve_once(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec()).await;
+        discover(&state, "a", &url).await;
+        let manager = DownloadManager::new(Arc::clone(&state));
+
+        assert!(manager.update_download_queue().await);
+        manager.download_videos().await;
+        wait_for_failure(&manager, "a").await;
+        manager.update_download_queue().await;
+        assert!(manager.download_queue.lock().await.is_empty());
+
+        // Until it's restarted.
+        assert!(manager.restart_download("a").await);
+        manager.update_download_queue().await;
+        assert_eq!(ids(&manager.download_queue.lock().await), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn chunked_download_records_its_size() {
+        let (state, store) = memory_state(|_| {});
+        // No Content-Length: one 10000 byte chunk, then the last one.
+        let mut body = b"2710\r\n".to_vec();
+        body.extend_from_slice(&[7u8; 10_000]);
+        body.extend_from_slice(b"\r\n0\r\n\r\n");
+        let url = serve_once(http_response("Transfer-Encoding: chunked\r\n", &body)).await;
+        let video = discover(&state, "a", &url).await;
+        download(&state, video).await.unwrap();
+
+        let done = state.discovered_videos.read().await["a"].clone();
+        assert_eq!(done.content_length, Some(10_000));
+        assert_eq!((done.downloaded_bytes, done.synced_bytes), (10_000, 10_000));
+        assert_eq!(store.contents(done.local_path.as_ref().unwrap()).map(|c| c.len()), Some(10_000));
+        assert_eq!(*state.current_storage_bytes.lock().await, 10_000);
+    }
+
+    /// Wait until `id` has a finished download.
+    async fn wait_for_download(state: &AppState, id: &str) -> VideoDownload {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(v) = state.discovered_videos.read().await.get(id) {
+                    if v.downloaded_at.is_some() && !v.downloading {
+                        return v.clone();
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn same_url_is_downloaded_once() {
+        let (state, store) = memory_state(|_| {});
+        // The server answers a single request; a second one would never complete.
+        let url = serve_once(http_response("Content-Length: 10000\r\n", &[7u8; 10_000])).await;
+        discover(&state, "a", &url).await;
+        discover(&state, "b", &url).await;
+        let manager = DownloadManager::new(Arc::clone(&state));
+
+        manager.update_download_queue().await;
+        manager.download_videos().await;
+        let a = wait_for_download(&state, "a").await;
+        let b = wait_for_download(&state, "b").await;
+
+        // Whichever started the transfer, the other attached and shares its file.
+        assert!(a.local_path.is_some());
+        assert_eq!(a.local_path, b.local_path);
+        assert_eq!((b.downloaded_bytes, b.content_length), (10_000, Some(10_000)));
+        assert_eq!(store.keys().len(), 1);
+        assert_eq!(*state.current_storage_bytes.lock().await, 10_000);
+        assert!(manager.in_flight.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stopping_a_download_frees_its_storage() {
+        let (state, store) = memory_state(|_| {});
+        // 5000 bytes of 10000, then the transfer waits.
+        let url = serve_then_hang(http_response("Content-Length: 10000\r\n", &[7u8; 5_000])).await;
+        discover(&state, "a", &url).await;
+        let manager = DownloadManager::new(Arc::clone(&state));
+
+        manager.update_download_queue().await;
+        manager.download_videos().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.discovered_videos.read().await["a"].downloaded_bytes < 5_000 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(manager.stop_download("a").await);
+        assert_eq!(*state.current_storage_bytes.lock().await, 0);
+        assert!(store.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn eviction_frees_its_storage() {
+        let (state, store) = memory_state(|_| {});
+        state.max_behind_seconds.store(10, Ordering::Relaxed);
+        for (id, bytes) in [("a", 100), ("b", 200)] {
+            let mut video = downloaded(id, &format!("{id}.mp4"), bytes);
+            video.length_seconds = Some(60.0);
+            let mut file = store.put(video.local_path.as_ref().unwrap()).await.unwrap();
+            file.write_all(&vec![7u8; bytes as usize]).await.unwrap();
+            state.discovered_videos.write().await.insert(id.to_string(), video.clone());
+            state.add_to_playlist(DEFAULT_PLAYLIST, video).await;
+        }
+        *state.current_storage_bytes.lock().await = 300;
+        // Watching "b": "a" is 60 s behind, over the 10 s limit.
+        *state.current_index.lock().await = 1;
+        let manager = DownloadManager::new(Arc::clone(&state));
+
+        manager.enforce_behind_limit().await;
+        assert_eq!(*state.current_storage_bytes.lock().await, 200);
+        assert_eq!(store.keys(), [PathBuf::from("b.mp4")]);
+        assert!(state.discovered_videos.read().await["a"].local_path.is_none());
+    }
+
+    #[tokio::test]
+    async fn download_client_keeps_encoded_bytes() {
+        // Not valid gzip past the magic number; decoding it would fail.
+        let body = [0x1f, 0x8b, 0x08, 0x00, 1, 2, 3, 4, 5, 6];
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&body);
+        let url = serve_once(response).await;
+
+        let resp = build_download_client()
+            .get(&url)
+            .header(ACCEPT_ENCODING, "identity")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.content_length(), Some(body.len() as u64));
+        assert_eq!(resp.bytes().await.unwrap().as_ref(), body);
+    }
+
+    #[tokio::test]
+    async fn empty_download_fails_and_leaves_nothing() {
+        let (state, store) = memory_state(|_| {});
+        let url = serve_once(http_response("Content-Length: 0\r\n", b"")).await;
+        discover(&state, "a", &url).await;
+        let manager = DownloadManager::new(Arc::clone(&state));
+
+        manager.update_download_queue().await;
+        manager.download_videos().await;
+        wait_for_failure(&manager, "a").await;
+        assert!(state.discovered_videos.read().await["a"].local_path.is_none());
+        assert!(store.keys().is_empty());
+        manager.update_download_queue().await;
+        assert!(manager.download_queue.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stopping_a_shared_transfer_keeps_its_file() {
+        let (state, store) = memory_state(|_| {});
+        let manager = DownloadManager::new(Arc::clone(&state));
+        let url = "https://example.com/shared.mp4";
+        let path = PathBuf::from("downloads/shared.mp4");
+        store.put(&path).await.unwrap().write_all(&[7u8; 100]).await.unwrap();
+        for id in ["a", "b"] {
+            let mut video = discover(&state, id, url).await;
+            video.downloading = true;
+            manager.download_queue.lock().await.push(video);
+        }
+        state.discovered_videos.write().await.get_mut("a").unwrap().local_path = Some(path.clone());
+        let token = CancellationToken::new();
+        manager.in_flight.lock().await.insert(url.to_string(), vec!["a".to_string(), "b".to_string()]);
+        manager.cancellations.lock().await.insert(url.to_string(), token.clone());
+
+        // "b" still waits on the transfer "a" started.
+        assert!(manager.stop_download("a").await);
+        assert!(!token.is_cancelled());
+        assert_eq!(manager.in_flight.lock().await[url], ["b"]);
+        assert_eq!(state.discovered_videos.read().await["a"].local_path, Some(path.clone()));
+        assert!(store.contents(&path).is_some());
+
+        // Nobody left.
+        assert!(manager.stop_download("b").await);
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn empty_or_short_download_is_too_small() {
+        let min = 4 * 1024;
+        assert!(download_too_small(0, min, None));
+        assert!(download_too_small(0, min, Some(0)));
+        assert!(download_too_small(100, min, None));
+        assert!(download_too_small(min + 10, min, Some(min + 11)));
+        assert!(!download_too_small(min, min, None));
+        assert!(!download_too_small(min + 10, min, Some(min + 10)));
+        // Tiny files are fine once the minimum is lowered.
+        assert!(!download_too_small(100, 0, Some(100)));
+    }
+
+    fn downloaded(id: &str, file: &str, bytes: u64) -> VideoDownload {
+        let mut video = VideoDownload::for_test(id);
+        video.local_path = Some(PathBuf::from(file));
+        video.downloaded_bytes = bytes;
+        video.synced_bytes = bytes;
+        video.content_length = Some(bytes);
+        video.thumbnail_path = Some(PathBuf::from(format!("{file}.jpg")));
+        video
+    }
+
+    #[test]
+    fn evicting_everything_frees_all_storage() {
+        let mut discovered: HashMap<String, VideoDownload> = [
+            downloaded("a", "a.mp4", 100),
+            downloaded("b", "b.mp4", 200),
+            // Coalesced with "b": same file, counted once.
+            downloaded("c", "b.mp4", 200),
+        ]
+        .into_iter()
+        .map(|v| (v.id.clone(), v))
+        .collect();
+        let storage: u64 = 300;
+
+        let evict = ["a".to_string(), "b".to_string(), "c".to_string()];
+        let (files, thumbs) = take_evicted_files(&mut discovered, &evict, false);
+        let freed: u64 = files.values().sum();
+        assert_eq!(storage.saturating_sub(freed), 0);
+        assert_eq!(files.len(), 2);
+        assert_eq!(thumbs.len(), 3);
+        for video in discovered.values() {
+            assert!(video.evicted);
+            assert_eq!((video.local_path.as_ref(), video.downloaded_bytes), (None, 0));
+        }
+    }
+
+    #[test]
+    fn evicting_keeps_files_shared_with_kept_videos() {
+        let mut discovered: HashMap<String, VideoDownload> = [
+            downloaded("a", "a.mp4", 100),
+            downloaded("b", "b.mp4", 200),
+            downloaded("c", "b.mp4", 200),
+        ]
+        .into_iter()
+        .map(|v| (v.id.clone(), v))
+        .collect();
+
+        let (files, thumbs) = take_evicted_files(&mut discovered, &["a".to_string(), "b".to_string()], true);
+        assert_eq!(files, HashMap::from([(PathBuf::from("a.mp4"), 100)]));
+        assert!(thumbs.is_empty());
+        assert!(discovered["a"].thumbnail_path.is_some());
+        assert!(!discovered["b"].evicted);
+        assert!(has_local_file(&discovered["b"]));
+    }
+
+    #[test]
+    fn container_extension_from_magic_bytes() {
+        assert_eq!(container_extension(b"\0\0\0\x18ftypisom\0\0\x02\0"), Some("mp4"));
+        assert_eq!(container_extension(b"\0\0\0\x14ftypqt  \0\0\x02\0"), Some("mov"));
+        let ebml = [0x1A, 0x45, 0xDF, 0xA3, 0x9F, 0x42, 0x82, 0x84];
+        assert_eq!(container_extension(&[&ebml[..], &b"webm"[..]].concat()), Some("webm"));
+        assert_eq!(container_extension(&[&ebml[..], &b"matroska"[..]].concat()), Some("mkv"));
+        // Too short to tell, or something else entirely.
+        assert_eq!(container_extension(b"\0\0\0\x18ftyp"), None);
+        assert_eq!(container_extension(b"<!DOCTYPE html>"), None);
+        assert_eq!(container_extension(b""), None);
+    }
+
+    #[test]
+    fn evicted_video_is_not_queued_again() {
+        let mut evicted = VideoDownload::for_test("a");
+        evicted.evicted = true;
+        assert!(!needs_download(&evicted, &[]));
+        assert!(!needs_download(&evicted, &["b".to_string()]));
+        assert!(needs_download(&VideoDownload::for_test("b"), &[]));
+    }
+
+    #[test]
+    fn evicted_video_is_queued_when_watched_again() {
+        let mut evicted = VideoDownload::for_test("a");
+        evicted.evicted = true;
+        assert!(needs_download(&evicted, &["a".to_string()]));
+    }
+
+    #[test]
+    fn downloaded_video_is_not_queued() {
+        let mut done = VideoDownload::for_test("a");
+        done.local_path = Some(PathBuf::from("a.mp4"));
+        done.downloaded_bytes = 100;
+        done.content_length = Some(100);
+        assert!(!needs_download(&done, &["a".to_string()]));
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Token bucket shared by every running download, so together they stay under
+/// `AppState::max_download_bytes_per_sec`. Holds at most one second's worth of
+/// tokens; a chunk larger than what's available puts the bucket in debt, and
+/// its reader sleeps until the debt is paid off.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` from the bucket, refilled at `bytes_per_sec`, sleeping as
+    /// long as needed to stay under that rate.
+    pub async fn acquire(&self, bytes: u64, bytes_per_sec: u64) {
+        let rate = bytes_per_sec.max(1) as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last_refill = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -246,6 +246,8 @@ fn link_ffmpeg_static(lib_dir: &Path) {
 fn compile_c(include_dirs: &[&Path]) {
     let mut cc_builder = cc::Build::new();
     cc_builder.file("c_src/extract_jpeg_frame.c");
+    cc_builder.file("c_src/extract_animated_preview.c");
+    cc_builder.file("c_src/extract_sprite_sheet.c");
     cc_builder.include("c_src");
 
     for inc in include_dirs {
@@ -312,6 +314,8 @@ fn try_pkg_config_ffmpeg() -> bool {
     // If all packages were found, compile C code with those includes:
     let mut cc_builder = cc::Build::new();
     cc_builder.file("c_src/extract_jpeg_frame.c");
+    cc_builder.file("c_src/extract_animated_preview.c");
+    cc_builder.file("c_src/extract_sprite_sheet.c");
     cc_builder.include("c_src");
 
     for inc in &all_includes {
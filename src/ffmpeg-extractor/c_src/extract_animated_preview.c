@@ -0,0 +1,404 @@
+#include "extract_animated_preview.h"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <stdint.h>
+
+#include <libavcodec/avcodec.h>
+#include <libavformat/avformat.h>
+#include <libswscale/swscale.h>
+#include <libavutil/imgutils.h>
+#include <libavutil/mem.h>
+
+/**
+ * In-memory input, same as in extract_jpeg_frame.c.
+ */
+typedef struct {
+    const uint8_t* buffer;
+    size_t size;
+    size_t position;
+} PreviewIOContext;
+
+static int preview_read_packet(void* opaque, uint8_t* buf, int buf_size) {
+    PreviewIOContext* ioCtx = (PreviewIOContext*)opaque;
+    int remaining = (int)(ioCtx->size - ioCtx->position);
+    if (remaining <= 0) {
+        return AVERROR_EOF;
+    }
+    int to_read = buf_size < remaining ? buf_size : remaining;
+    memcpy(buf, ioCtx->buffer + ioCtx->position, to_read);
+    ioCtx->position += to_read;
+    return to_read;
+}
+
+/**
+ * Drain every packet the encoder has ready into the output.
+ * Return 0 on success (including "needs more input"), <0 on error.
+ */
+static int write_encoded_packets(AVCodecContext* encoderCtx, AVFormatContext* outCtx,
+                                 AVStream* outStream, AVPacket* packet) {
+    for (;;) {
+        int ret = avcodec_receive_packet(encoderCtx, packet);
+        if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
+            return 0;
+        } else if (ret < 0) {
+            fprintf(stderr, "avcodec_receive_packet() failed: %d\n", ret);
+            return ret;
+        }
+        packet->stream_index = outStream->index;
+        av_packet_rescale_ts(packet, encoderCtx->time_base, outStream->time_base);
+        ret = av_interleaved_write_frame(outCtx, packet);
+        if (ret < 0) {
+            fprintf(stderr, "av_interleaved_write_frame() failed: %d\n", ret);
+            return ret;
+        }
+    }
+}
+
+FrameData* extract_animated_preview(const uint8_t* videoData, size_t dataSize,
+                                    double durationSeconds, int fps, int maxWidth) {
+    const int ioBufferSize = 32 * 1024; // 32k
+    unsigned char* ioBuffer = NULL;
+    AVIOContext* avioCtx = NULL;
+    AVFormatContext* formatCtx = NULL;
+    AVCodecContext* decoderCtx = NULL;
+    AVCodecContext* encoderCtx = NULL;
+    AVFormatContext* outCtx = NULL;
+    AVStream* outStream = NULL;
+    AVFrame* decodedFrame = NULL;
+    AVFrame* scaledFrame = NULL;
+    AVPacket* packet = NULL;
+    AVPacket* encodedPacket = NULL;
+    struct SwsContext* swsCtx = NULL;
+    uint8_t* gifBytes = NULL;
+    int gifSize = 0;
+    int headerWritten = 0;
+    PreviewIOContext customIO = { videoData, dataSize, 0 };
+    FrameData* result = NULL;
+
+    int ret = 0;
+    int videoStreamIndex = -1;
+    int64_t framesWritten = 0;
+    double nextSampleTime = 0.0;
+
+    if (fps <= 0 || durationSeconds <= 0.0 || maxWidth <= 0) {
+        fprintf(stderr, "Invalid preview parameters\n");
+        return NULL;
+    }
+
+    // -------- Open the input from memory -------------------
+    ioBuffer = (unsigned char*)av_malloc(ioBufferSize);
+    if (!ioBuffer) {
+        fprintf(stderr, "Failed to allocate ioBuffer\n");
+        goto cleanup;
+    }
+    avioCtx = avio_alloc_context(ioBuffer, ioBufferSize, 0, &customIO,
+                                 preview_read_packet, NULL, NULL);
+    if (!avioCtx) {
+        fprintf(stderr, "Failed to create avio context\n");
+        goto cleanup;
+    }
+    formatCtx = avformat_alloc_context();
+    if (!formatCtx) {
+        fprintf(stderr, "Failed to allocate format context\n");
+        goto cleanup;
+    }
+    formatCtx->pb = avioCtx;
+
+    ret = avformat_open_input(&formatCtx, NULL, NULL, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_open_input() failed: %d\n", ret);
+        goto cleanup;
+    }
+    ret = avformat_find_stream_info(formatCtx, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_find_stream_info() failed: %d\n", ret);
+        goto cleanup;
+    }
+    for (unsigned int i = 0; i < formatCtx->nb_streams; i++) {
+        if (formatCtx->streams[i]->codecpar->codec_type == AVMEDIA_TYPE_VIDEO) {
+            videoStreamIndex = i;
+            break;
+        }
+    }
+    if (videoStreamIndex < 0) {
+        fprintf(stderr, "No video stream found\n");
+        goto cleanup;
+    }
+
+    // -------- Set up the decoder ---------------------------
+    {
+        AVCodecParameters* codecpar = formatCtx->streams[videoStreamIndex]->codecpar;
+        const AVCodec* decoder = avcodec_find_decoder(codecpar->codec_id);
+        if (!decoder) {
+            fprintf(stderr, "Decoder not found\n");
+            goto cleanup;
+        }
+        decoderCtx = avcodec_alloc_context3(decoder);
+        if (!decoderCtx) {
+            fprintf(stderr, "Failed to allocate decoder context\n");
+            goto cleanup;
+        }
+        ret = avcodec_parameters_to_context(decoderCtx, codecpar);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_parameters_to_context() failed: %d\n", ret);
+            goto cleanup;
+        }
+        ret = avcodec_open2(decoderCtx, decoder, NULL);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_open2() failed: %d\n", ret);
+            goto cleanup;
+        }
+    }
+
+    // -------- Set up the GIF encoder -----------------------
+    // GIF is always built into FFmpeg (animated WebP needs libwebp), and every
+    // browser and image widget can play it.
+    {
+        const AVCodec* gifCodec = avcodec_find_encoder(AV_CODEC_ID_GIF);
+        if (!gifCodec) {
+            fprintf(stderr, "GIF encoder not found\n");
+            goto cleanup;
+        }
+        encoderCtx = avcodec_alloc_context3(gifCodec);
+        if (!encoderCtx) {
+            fprintf(stderr, "Failed to allocate GIF encoder context\n");
+            goto cleanup;
+        }
+
+        // Downscale to maxWidth, keeping the aspect ratio (even dimensions).
+        int width = decoderCtx->width;
+        int height = decoderCtx->height;
+        if (width <= 0 || height <= 0) {
+            fprintf(stderr, "Unknown video dimensions\n");
+            goto cleanup;
+        }
+        if (width > maxWidth) {
+            height = (int)((int64_t)height * maxWidth / width);
+            width = maxWidth;
+        }
+        encoderCtx->width = width & ~1;
+        encoderCtx->height = (height & ~1) > 0 ? (height & ~1) : 2;
+        encoderCtx->pix_fmt = AV_PIX_FMT_RGB8;
+        encoderCtx->time_base = (AVRational){1, fps};
+        encoderCtx->framerate = (AVRational){fps, 1};
+
+        ret = avcodec_open2(encoderCtx, gifCodec, NULL);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_open2() for GIF failed: %d\n", ret);
+            goto cleanup;
+        }
+    }
+
+    // -------- Set up the in-memory GIF muxer ---------------
+    ret = avformat_alloc_output_context2(&outCtx, NULL, "gif", NULL);
+    if (ret < 0 || !outCtx) {
+        fprintf(stderr, "avformat_alloc_output_context2() failed: %d\n", ret);
+        goto cleanup;
+    }
+    outStream = avformat_new_stream(outCtx, NULL);
+    if (!outStream) {
+        fprintf(stderr, "Failed to create output stream\n");
+        goto cleanup;
+    }
+    ret = avcodec_parameters_from_context(outStream->codecpar, encoderCtx);
+    if (ret < 0) {
+        fprintf(stderr, "avcodec_parameters_from_context() failed: %d\n", ret);
+        goto cleanup;
+    }
+    outStream->time_base = encoderCtx->time_base;
+    ret = avio_open_dyn_buf(&outCtx->pb);
+    if (ret < 0) {
+        fprintf(stderr, "avio_open_dyn_buf() failed: %d\n", ret);
+        goto cleanup;
+    }
+    ret = avformat_write_header(outCtx, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_write_header() failed: %d\n", ret);
+        goto cleanup;
+    }
+    headerWritten = 1;
+
+    // -------- Scaler + working frames ----------------------
+    packet = av_packet_alloc();
+    encodedPacket = av_packet_alloc();
+    decodedFrame = av_frame_alloc();
+    scaledFrame = av_frame_alloc();
+    if (!packet || !encodedPacket || !decodedFrame || !scaledFrame) {
+        fprintf(stderr, "Failed to allocate packets/frames\n");
+        goto cleanup;
+    }
+    scaledFrame->format = encoderCtx->pix_fmt;
+    scaledFrame->width = encoderCtx->width;
+    scaledFrame->height = encoderCtx->height;
+    ret = av_frame_get_buffer(scaledFrame, 32);
+    if (ret < 0) {
+        fprintf(stderr, "av_frame_get_buffer() failed: %d\n", ret);
+        goto cleanup;
+    }
+
+    // -------- Decode, sample at fps, encode ----------------
+    while (av_read_frame(formatCtx, packet) >= 0) {
+        if (packet->stream_index != videoStreamIndex) {
+            av_packet_unref(packet);
+            continue;
+        }
+        ret = avcodec_send_packet(decoderCtx, packet);
+        av_packet_unref(packet);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_send_packet() failed: %d\n", ret);
+            goto cleanup;
+        }
+
+        int done = 0;
+        while ((ret = avcodec_receive_frame(decoderCtx, decodedFrame)) >= 0) {
+            int64_t pts = decodedFrame->best_effort_timestamp;
+            double frameTime = pts == AV_NOPTS_VALUE
+                ? (double)framesWritten / fps
+                : pts * av_q2d(formatCtx->streams[videoStreamIndex]->time_base);
+            if (frameTime >= durationSeconds) {
+                done = 1;
+                break;
+            }
+            if (frameTime < nextSampleTime) {
+                continue;
+            }
+            nextSampleTime += 1.0 / fps;
+
+            // The scaler is created lazily: the decoded pixel format is only
+            // reliable once we have a frame.
+            if (!swsCtx) {
+                swsCtx = sws_getContext(
+                    decodedFrame->width, decodedFrame->height, (enum AVPixelFormat)decodedFrame->format,
+                    scaledFrame->width, scaledFrame->height, (enum AVPixelFormat)scaledFrame->format,
+                    SWS_BILINEAR, NULL, NULL, NULL
+                );
+                if (!swsCtx) {
+                    fprintf(stderr, "sws_getContext() failed.\n");
+                    goto cleanup;
+                }
+            }
+            ret = av_frame_make_writable(scaledFrame);
+            if (ret < 0) {
+                fprintf(stderr, "av_frame_make_writable() failed: %d\n", ret);
+                goto cleanup;
+            }
+            sws_scale(swsCtx,
+                      (const uint8_t* const*)decodedFrame->data,
+                      decodedFrame->linesize,
+                      0,
+                      decodedFrame->height,
+                      scaledFrame->data,
+                      scaledFrame->linesize);
+            scaledFrame->pts = framesWritten++;
+
+            ret = avcodec_send_frame(encoderCtx, scaledFrame);
+            if (ret < 0) {
+                fprintf(stderr, "avcodec_send_frame() failed: %d\n", ret);
+                goto cleanup;
+            }
+            if (write_encoded_packets(encoderCtx, outCtx, outStream, encodedPacket) < 0) {
+                goto cleanup;
+            }
+        }
+        if (ret < 0 && ret != AVERROR(EAGAIN) && ret != AVERROR_EOF) {
+            fprintf(stderr, "avcodec_receive_frame() failed: %d\n", ret);
+            goto cleanup;
+        }
+        if (done) {
+            break;
+        }
+    }
+
+    if (framesWritten == 0) {
+        fprintf(stderr, "No frame could be decoded.\n");
+        goto cleanup;
+    }
+
+    // -------- Flush the encoder and finish the file --------
+    avcodec_send_frame(encoderCtx, NULL);
+    if (write_encoded_packets(encoderCtx, outCtx, outStream, encodedPacket) < 0) {
+        goto cleanup;
+    }
+    ret = av_write_trailer(outCtx);
+    headerWritten = 0;
+    if (ret < 0) {
+        fprintf(stderr, "av_write_trailer() failed: %d\n", ret);
+        goto cleanup;
+    }
+    gifSize = avio_close_dyn_buf(outCtx->pb, &gifBytes);
+    outCtx->pb = NULL;
+    if (gifSize <= 0 || !gifBytes) {
+        fprintf(stderr, "Empty GIF output\n");
+        goto cleanup;
+    }
+
+    // ---------- Copy the GIF into our result ---------------
+    result = (FrameData*)malloc(sizeof(FrameData));
+    if (!result) {
+        fprintf(stderr, "Failed to allocate FrameData\n");
+        goto cleanup;
+    }
+    result->frameSize = gifSize;
+    result->width = encoderCtx->width;
+    result->height = encoderCtx->height;
+    result->pixelFormat = decoderCtx->pix_fmt;
+    result->frameData = (uint8_t*)malloc(gifSize);
+    if (!result->frameData) {
+        fprintf(stderr, "Failed to allocate FrameData->frameData\n");
+        free(result);
+        result = NULL;
+        goto cleanup;
+    }
+    memcpy(result->frameData, gifBytes, gifSize);
+
+cleanup:
+    if (gifBytes) {
+        av_free(gifBytes);
+    }
+    if (outCtx) {
+        if (headerWritten) {
+            av_write_trailer(outCtx);
+        }
+        if (outCtx->pb) {
+            uint8_t* discard = NULL;
+            avio_close_dyn_buf(outCtx->pb, &discard);
+            av_free(discard);
+            outCtx->pb = NULL;
+        }
+        avformat_free_context(outCtx);
+    }
+    if (encodedPacket) {
+        av_packet_free(&encodedPacket);
+    }
+    if (scaledFrame) {
+        av_frame_free(&scaledFrame);
+    }
+    if (swsCtx) {
+        sws_freeContext(swsCtx);
+    }
+    if (decodedFrame) {
+        av_frame_free(&decodedFrame);
+    }
+    if (packet) {
+        av_packet_free(&packet);
+    }
+    if (encoderCtx) {
+        avcodec_free_context(&encoderCtx);
+    }
+    if (decoderCtx) {
+        avcodec_free_context(&decoderCtx);
+    }
+    if (formatCtx) {
+        avformat_close_input(&formatCtx);
+        avformat_free_context(formatCtx);
+    }
+    if (avioCtx) {
+        if (avioCtx->buffer) {
+            av_freep(&avioCtx->buffer);
+        }
+        av_freep(&avioCtx);
+    }
+
+    return result;
+}
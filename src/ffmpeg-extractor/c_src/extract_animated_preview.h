@@ -0,0 +1,20 @@
+#ifndef EXTRACT_ANIMATED_PREVIEW_H
+#define EXTRACT_ANIMATED_PREVIEW_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#include "extract_jpeg_frame.h"
+
+/**
+ * Decode the first durationSeconds of videoData, sample it at fps frames per
+ * second, downscale to at most maxWidth pixels wide and encode the result as a
+ * looping animated GIF in memory.
+ *
+ * Return a FrameData holding the GIF bytes (free with free_frame_data()), or
+ * NULL on error (e.g. no video stream, or no frame could be decoded).
+ */
+FrameData* extract_animated_preview(const uint8_t* videoData, size_t dataSize,
+                                    double durationSeconds, int fps, int maxWidth);
+
+#endif
@@ -47,6 +47,35 @@ static int read_packet(void* opaque, uint8_t* buf, int buf_size) {
     return to_read;
 }
 
+/**
+ * Receive decoded frames until one is at or after timestampSeconds.
+ *
+ * Return 1 with that frame in `frame`, 0 once the decoder needs more input,
+ * AVERROR_EOF once a flushed decoder is empty, or another negative error.
+ */
+static int receive_frame_at(AVCodecContext* decoderCtx, AVFrame* frame,
+                            AVRational timeBase, double timestampSeconds) {
+    for (;;) {
+        int ret = avcodec_receive_frame(decoderCtx, frame);
+        if (ret == AVERROR(EAGAIN)) {
+            return 0;
+        } else if (ret < 0) {
+            return ret;
+        }
+
+        // Skip frames before the requested timestamp
+        if (timestampSeconds > 0.0) {
+            int64_t pts = frame->best_effort_timestamp;
+            double frameTime = pts == AV_NOPTS_VALUE ? 0.0 : pts * av_q2d(timeBase);
+            if (frameTime < timestampSeconds) {
+                av_frame_unref(frame);
+                continue;
+            }
+        }
+        return 1;
+    }
+}
+
 /**
  * Extract the *first* video frame from videoData, encode it as JPEG in memory,
  * and return a FrameData struct containing the JPEG bytes and size.
@@ -54,6 +83,17 @@ static int read_packet(void* opaque, uint8_t* buf, int buf_size) {
  * Return NULL on error (e.g., if no valid frame can be decoded).
  */
 FrameData* extract_jpeg_frame(const uint8_t* videoData, size_t dataSize) {
+    return extract_jpeg_frame_at(videoData, dataSize, 0.0);
+}
+
+/**
+ * Decode videoData up to the first frame at or after timestampSeconds, encode
+ * it as JPEG in memory, and return a FrameData struct.
+ *
+ * Our custom IO has no seek callback, so this decodes sequentially from the
+ * start. Return NULL on error or if the video ends before timestampSeconds.
+ */
+FrameData* extract_jpeg_frame_at(const uint8_t* videoData, size_t dataSize, double timestampSeconds) {
     // Allocate an IO buffer for FFmpeg to read from
     const int ioBufferSize = 32 * 1024; // 32k
     unsigned char* ioBuffer = NULL;
@@ -71,6 +111,7 @@ FrameData* extract_jpeg_frame(const uint8_t* videoData, size_t dataSize) {
 
     int ret = 0;
     int videoStreamIndex = -1;
+    int gotFrame = 0;
 
     // -------- Initialize basic FFmpeg structures ----------
     // (In modern FFmpeg, av_register_all() isn't needed.)
@@ -169,37 +210,58 @@ FrameData* extract_jpeg_frame(const uint8_t* videoData, size_t dataSize) {
         goto cleanup;
     }
 
-    // -------- Read frames until we decode one video frame --
-    while (av_read_frame(formatCtx, packet) >= 0) {
-        if (packet->stream_index == videoStreamIndex) {
-            // Send packet to decoder
-            ret = avcodec_send_packet(decoderCtx, packet);
+    // -------- Decode until a frame at or after the timestamp --
+    // A packet can give zero or several frames, and decoders hold frames back
+    // (B-frame reordering, frame threading), so each packet is followed by
+    // receiving until the decoder wants more input, and the decoder is
+    // flushed at the end of the input for the frames it still holds.
+    AVRational timeBase = formatCtx->streams[videoStreamIndex]->time_base;
+    while (!gotFrame && av_read_frame(formatCtx, packet) >= 0) {
+        if (packet->stream_index != videoStreamIndex) {
+            // Not our video stream, just discard
             av_packet_unref(packet);
+            continue;
+        }
+
+        // EAGAIN means the decoder's output is full: take frames out, then resend
+        while ((ret = avcodec_send_packet(decoderCtx, packet)) == AVERROR(EAGAIN)) {
+            gotFrame = receive_frame_at(decoderCtx, decodedFrame, timeBase, timestampSeconds);
+            if (gotFrame != 0) {
+                break;
+            }
+        }
+        av_packet_unref(packet);
+        if (gotFrame == 0) {
             if (ret < 0) {
                 fprintf(stderr, "avcodec_send_packet() failed: %d\n", ret);
                 goto cleanup;
             }
+            gotFrame = receive_frame_at(decoderCtx, decodedFrame, timeBase, timestampSeconds);
+        }
+        if (gotFrame < 0) {
+            fprintf(stderr, "avcodec_receive_frame() failed: %d\n", gotFrame);
+            goto cleanup;
+        }
+    }
 
-            // Receive frame from decoder
-            ret = avcodec_receive_frame(decoderCtx, decodedFrame);
-            if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
-                // Need more data or we reached the end
-                continue;
-            } else if (ret < 0) {
-                fprintf(stderr, "avcodec_receive_frame() failed: %d\n", ret);
-                goto cleanup;
-            }
-
-            // We got one decoded frame -> now encode it as JPEG
-            break; // exit the reading loop
-        } else {
-            // Not our video stream, just discard
-            av_packet_unref(packet);
+    // End of input: flush the decoder and drain the frames it held back
+    if (!gotFrame) {
+        ret = avcodec_send_packet(decoderCtx, NULL);
+        if (ret < 0 && ret != AVERROR_EOF) {
+            fprintf(stderr, "avcodec_send_packet() failed: %d\n", ret);
+            goto cleanup;
+        }
+        gotFrame = receive_frame_at(decoderCtx, decodedFrame, timeBase, timestampSeconds);
+        if (gotFrame == AVERROR_EOF) {
+            gotFrame = 0;
+        } else if (gotFrame < 0) {
+            fprintf(stderr, "avcodec_receive_frame() failed: %d\n", gotFrame);
+            goto cleanup;
         }
     }
 
-    // If we never got a frame, ret will be something else:
-    if (ret < 0) {
+    // If we never got a frame (or the video ended before the timestamp):
+    if (!gotFrame) {
         fprintf(stderr, "No frame could be decoded.\n");
         goto cleanup;
     }
@@ -307,6 +369,9 @@ FrameData* extract_jpeg_frame(const uint8_t* videoData, size_t dataSize) {
         goto cleanup;
     }
     result->frameSize = encodedPacket->size;
+    result->width = encoderCtx->width;
+    result->height = encoderCtx->height;
+    result->pixelFormat = decodedFrame->format;
     result->frameData = (uint8_t*)malloc(result->frameSize);
     if (!result->frameData) {
         fprintf(stderr, "Failed to allocate FrameData->frameData\n");
@@ -358,3 +423,93 @@ cleanup:
     return result;
 }
 
+
+/**
+ * Read the container headers of videoData and fill in info. Nothing is decoded.
+ *
+ * Return 0 on success, a negative value on error (e.g. no video stream).
+ */
+int probe_video_info(const uint8_t* videoData, size_t dataSize, VideoInfo* info) {
+    const int ioBufferSize = 32 * 1024; // 32k
+    unsigned char* ioBuffer = NULL;
+    AVIOContext* avioCtx = NULL;
+    AVFormatContext* formatCtx = NULL;
+    IOContext customIO = { videoData, dataSize, 0 };
+    int result = -1;
+    int ret = 0;
+
+    if (!info) {
+        return -1;
+    }
+    info->durationSeconds = 0.0;
+    info->frameCount = 0;
+
+    ioBuffer = (unsigned char*)av_malloc(ioBufferSize);
+    if (!ioBuffer) {
+        fprintf(stderr, "Failed to allocate ioBuffer\n");
+        goto cleanup;
+    }
+    avioCtx = avio_alloc_context(ioBuffer, ioBufferSize, 0, &customIO, read_packet, NULL, NULL);
+    if (!avioCtx) {
+        fprintf(stderr, "Failed to create avio context\n");
+        goto cleanup;
+    }
+    formatCtx = avformat_alloc_context();
+    if (!formatCtx) {
+        fprintf(stderr, "Failed to allocate format context\n");
+        goto cleanup;
+    }
+    formatCtx->pb = avioCtx;
+
+    ret = avformat_open_input(&formatCtx, NULL, NULL, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_open_input() failed: %d\n", ret);
+        goto cleanup;
+    }
+    ret = avformat_find_stream_info(formatCtx, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_find_stream_info() failed: %d\n", ret);
+        goto cleanup;
+    }
+
+    for (unsigned int i = 0; i < formatCtx->nb_streams; i++) {
+        AVStream* stream = formatCtx->streams[i];
+        if (stream->codecpar->codec_type != AVMEDIA_TYPE_VIDEO) {
+            continue;
+        }
+
+        // Prefer the stream's own duration, else the container's.
+        if (stream->duration != AV_NOPTS_VALUE && stream->duration > 0) {
+            info->durationSeconds = stream->duration * av_q2d(stream->time_base);
+        } else if (formatCtx->duration != AV_NOPTS_VALUE && formatCtx->duration > 0) {
+            info->durationSeconds = (double)formatCtx->duration / AV_TIME_BASE;
+        }
+
+        if (stream->nb_frames > 0) {
+            info->frameCount = stream->nb_frames;
+        } else if (stream->avg_frame_rate.num > 0 && stream->avg_frame_rate.den > 0) {
+            info->frameCount = (int64_t)(info->durationSeconds * av_q2d(stream->avg_frame_rate));
+        }
+        result = 0;
+        break;
+    }
+    if (result < 0) {
+        fprintf(stderr, "No video stream found\n");
+    }
+
+cleanup:
+    if (formatCtx) {
+        avformat_close_input(&formatCtx);
+        avformat_free_context(formatCtx);
+    }
+    if (avioCtx) {
+        if (avioCtx->buffer) {
+            av_freep(&avioCtx->buffer);
+        }
+        av_freep(&avioCtx);
+    } else if (ioBuffer) {
+        av_free(ioBuffer);
+    }
+
+    return result;
+}
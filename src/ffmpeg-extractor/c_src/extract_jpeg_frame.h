@@ -10,6 +10,9 @@
 typedef struct {
     uint8_t* frameData;   // Pointer to JPEG-encoded bytes
     int frameSize;        // Number of bytes in frameData
+    int width;            // Width of the encoded image in pixels
+    int height;           // Height of the encoded image in pixels
+    int pixelFormat;      // AVPixelFormat of the decoded source frame, -1 if unknown
 } FrameData;
 
 /**
@@ -20,6 +23,29 @@ typedef struct {
  */
 FrameData* extract_jpeg_frame(const uint8_t* videoData, size_t dataSize);
 
+/**
+ * Decode videoData up to the first frame at or after timestampSeconds, encode
+ * it as JPEG in memory, and return it like extract_jpeg_frame().
+ *
+ * Return NULL on error or if the video ends before timestampSeconds.
+ */
+FrameData* extract_jpeg_frame_at(const uint8_t* videoData, size_t dataSize, double timestampSeconds);
+
+/**
+ * Duration and (estimated) frame count of a video's first video stream.
+ */
+typedef struct {
+    double durationSeconds;  // 0 if unknown
+    int64_t frameCount;      // From the container, else duration * frame rate; 0 if unknown
+} VideoInfo;
+
+/**
+ * Read the container headers of videoData and fill in info.
+ *
+ * Return 0 on success, a negative value on error (e.g. no video stream).
+ */
+int probe_video_info(const uint8_t* videoData, size_t dataSize, VideoInfo* info);
+
 /**
  * Free a FrameData struct allocated by extract_jpeg_frame().
  */
@@ -0,0 +1,334 @@
+#include "extract_sprite_sheet.h"
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <stdint.h>
+#include <limits.h>
+
+#include <libavcodec/avcodec.h>
+#include <libavformat/avformat.h>
+#include <libswscale/swscale.h>
+#include <libavutil/mem.h>
+
+/**
+ * In-memory input, same as in extract_jpeg_frame.c.
+ */
+typedef struct {
+    const uint8_t* buffer;
+    size_t size;
+    size_t position;
+} SpriteIOContext;
+
+static int sprite_read_packet(void* opaque, uint8_t* buf, int buf_size) {
+    SpriteIOContext* ioCtx = (SpriteIOContext*)opaque;
+    int remaining = (int)(ioCtx->size - ioCtx->position);
+    if (remaining <= 0) {
+        return AVERROR_EOF;
+    }
+    int to_read = buf_size < remaining ? buf_size : remaining;
+    memcpy(buf, ioCtx->buffer + ioCtx->position, to_read);
+    ioCtx->position += to_read;
+    return to_read;
+}
+
+/**
+ * The sheet being filled while the video decodes.
+ */
+typedef struct {
+    const double* timestamps;  // One per cell, ascending
+    int count;                 // cols * rows
+    int cols;
+    int cellWidth;
+    int cellHeight;
+    int stride;                // Bytes per sheet row: cols * cellWidth * 3
+    uint8_t* pixels;           // RGB24, stride * rows * cellHeight bytes
+    struct SwsContext* swsCtx;
+    AVRational timeBase;
+    int next;                  // Next cell to fill
+} SpriteSheet;
+
+/**
+ * Scale frame straight into its place in the sheet for cell `index`.
+ * Return 0 on success, <0 on error.
+ */
+static int scale_into_cell(SpriteSheet* sheet, const AVFrame* frame, int index) {
+    // Cached: only rebuilt if the decoded size or pixel format changes.
+    sheet->swsCtx = sws_getCachedContext(
+        sheet->swsCtx,
+        frame->width, frame->height, (enum AVPixelFormat)frame->format,
+        sheet->cellWidth, sheet->cellHeight, AV_PIX_FMT_RGB24,
+        SWS_BILINEAR, NULL, NULL, NULL
+    );
+    if (!sheet->swsCtx) {
+        fprintf(stderr, "sws_getCachedContext() failed.\n");
+        return -1;
+    }
+
+    size_t row = (size_t)(index / sheet->cols) * sheet->cellHeight;
+    size_t col = (size_t)(index % sheet->cols) * sheet->cellWidth;
+    uint8_t* dst[4] = { sheet->pixels + row * sheet->stride + col * 3, NULL, NULL, NULL };
+    int dstStride[4] = { sheet->stride, 0, 0, 0 };
+    sws_scale(sheet->swsCtx,
+              (const uint8_t* const*)frame->data,
+              frame->linesize,
+              0,
+              frame->height,
+              dst,
+              dstStride);
+    return 0;
+}
+
+/**
+ * Receive every frame the decoder has ready and fill the cells each one
+ * reaches. Return 0 once the decoder needs more input, is drained, or the
+ * sheet is full; <0 on error.
+ */
+static int drain_frames(AVCodecContext* decoderCtx, AVFrame* frame, SpriteSheet* sheet) {
+    int ret = 0;
+    while (sheet->next < sheet->count) {
+        ret = avcodec_receive_frame(decoderCtx, frame);
+        if (ret == AVERROR(EAGAIN) || ret == AVERROR_EOF) {
+            return 0;
+        } else if (ret < 0) {
+            fprintf(stderr, "avcodec_receive_frame() failed: %d\n", ret);
+            return ret;
+        }
+
+        int64_t pts = frame->best_effort_timestamp;
+        double frameTime = pts == AV_NOPTS_VALUE ? 0.0 : pts * av_q2d(sheet->timeBase);
+        // Cells closer together than the frame interval share a frame.
+        while (sheet->next < sheet->count && frameTime >= sheet->timestamps[sheet->next]) {
+            ret = scale_into_cell(sheet, frame, sheet->next);
+            if (ret < 0) {
+                av_frame_unref(frame);
+                return ret;
+            }
+            sheet->next++;
+        }
+        av_frame_unref(frame);
+    }
+    return 0;
+}
+
+/**
+ * Decode videoData once and lay out the frames at timestamps as a sprite sheet.
+ * See extract_sprite_sheet.h.
+ */
+FrameData* extract_sprite_sheet(const uint8_t* videoData, size_t dataSize,
+                                const double* timestamps, int cols, int rows, int cellWidth) {
+    const int ioBufferSize = 32 * 1024; // 32k
+    unsigned char* ioBuffer = NULL;
+    AVIOContext* avioCtx = NULL;
+    AVFormatContext* formatCtx = NULL;
+    AVCodecContext* decoderCtx = NULL;
+    AVFrame* decodedFrame = NULL;
+    AVPacket* packet = NULL;
+    SpriteIOContext customIO = { videoData, dataSize, 0 };
+    SpriteSheet sheet = { 0 };
+    FrameData* result = NULL;
+
+    int ret = 0;
+    int videoStreamIndex = -1;
+    int sheetHeight = 0;
+    int sheetSize = 0;
+
+    if (!timestamps || cols <= 0 || rows <= 0 || cellWidth <= 0 || cols > INT_MAX / rows) {
+        fprintf(stderr, "Invalid sprite sheet parameters\n");
+        return NULL;
+    }
+
+    // -------- Open the input from memory -------------------
+    ioBuffer = (unsigned char*)av_malloc(ioBufferSize);
+    if (!ioBuffer) {
+        fprintf(stderr, "Failed to allocate ioBuffer\n");
+        goto cleanup;
+    }
+    avioCtx = avio_alloc_context(ioBuffer, ioBufferSize, 0, &customIO,
+                                 sprite_read_packet, NULL, NULL);
+    if (!avioCtx) {
+        fprintf(stderr, "Failed to create avio context\n");
+        goto cleanup;
+    }
+    formatCtx = avformat_alloc_context();
+    if (!formatCtx) {
+        fprintf(stderr, "Failed to allocate format context\n");
+        goto cleanup;
+    }
+    formatCtx->pb = avioCtx;
+
+    ret = avformat_open_input(&formatCtx, NULL, NULL, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_open_input() failed: %d\n", ret);
+        goto cleanup;
+    }
+    ret = avformat_find_stream_info(formatCtx, NULL);
+    if (ret < 0) {
+        fprintf(stderr, "avformat_find_stream_info() failed: %d\n", ret);
+        goto cleanup;
+    }
+    for (unsigned int i = 0; i < formatCtx->nb_streams; i++) {
+        if (formatCtx->streams[i]->codecpar->codec_type == AVMEDIA_TYPE_VIDEO) {
+            videoStreamIndex = i;
+            break;
+        }
+    }
+    if (videoStreamIndex < 0) {
+        fprintf(stderr, "No video stream found\n");
+        goto cleanup;
+    }
+
+    // -------- Set up the decoder ---------------------------
+    {
+        AVCodecParameters* codecpar = formatCtx->streams[videoStreamIndex]->codecpar;
+        const AVCodec* decoder = avcodec_find_decoder(codecpar->codec_id);
+        if (!decoder) {
+            fprintf(stderr, "Decoder not found\n");
+            goto cleanup;
+        }
+        decoderCtx = avcodec_alloc_context3(decoder);
+        if (!decoderCtx) {
+            fprintf(stderr, "Failed to allocate decoder context\n");
+            goto cleanup;
+        }
+        ret = avcodec_parameters_to_context(decoderCtx, codecpar);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_parameters_to_context() failed: %d\n", ret);
+            goto cleanup;
+        }
+        ret = avcodec_open2(decoderCtx, decoder, NULL);
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_open2() failed: %d\n", ret);
+            goto cleanup;
+        }
+    }
+
+    // -------- Size and allocate the sheet ------------------
+    if (decoderCtx->width <= 0 || decoderCtx->height <= 0) {
+        fprintf(stderr, "Unknown video dimensions\n");
+        goto cleanup;
+    }
+    sheet.timestamps = timestamps;
+    sheet.count = cols * rows;
+    sheet.cols = cols;
+    sheet.cellWidth = cellWidth;
+    sheet.cellHeight = (int)((int64_t)decoderCtx->height * cellWidth / decoderCtx->width);
+    if (sheet.cellHeight < 1) {
+        sheet.cellHeight = 1;
+    }
+    sheet.timeBase = formatCtx->streams[videoStreamIndex]->time_base;
+    {
+        // FrameData sizes are ints.
+        int64_t stride = (int64_t)cols * cellWidth * 3;
+        int64_t height = (int64_t)rows * sheet.cellHeight;
+        if (stride > INT_MAX || height > INT_MAX || stride * height > INT_MAX) {
+            fprintf(stderr, "Sprite sheet too large\n");
+            goto cleanup;
+        }
+        sheet.stride = (int)stride;
+        sheetHeight = (int)height;
+        sheetSize = (int)(stride * height);
+    }
+    sheet.pixels = (uint8_t*)calloc(1, sheetSize);
+    if (!sheet.pixels) {
+        fprintf(stderr, "Failed to allocate sprite sheet\n");
+        goto cleanup;
+    }
+
+    packet = av_packet_alloc();
+    decodedFrame = av_frame_alloc();
+    if (!packet || !decodedFrame) {
+        fprintf(stderr, "Failed to allocate packet/frame\n");
+        goto cleanup;
+    }
+
+    // -------- Decode once, filling cells in order ----------
+    while (sheet.next < sheet.count && av_read_frame(formatCtx, packet) >= 0) {
+        if (packet->stream_index != videoStreamIndex) {
+            av_packet_unref(packet);
+            continue;
+        }
+
+        // EAGAIN means the decoder's output is full: take frames out, then resend
+        while ((ret = avcodec_send_packet(decoderCtx, packet)) == AVERROR(EAGAIN)) {
+            if (drain_frames(decoderCtx, decodedFrame, &sheet) < 0) {
+                av_packet_unref(packet);
+                goto cleanup;
+            }
+            if (sheet.next >= sheet.count) {
+                break;
+            }
+        }
+        av_packet_unref(packet);
+        if (sheet.next >= sheet.count) {
+            break;
+        }
+        if (ret < 0) {
+            fprintf(stderr, "avcodec_send_packet() failed: %d\n", ret);
+            goto cleanup;
+        }
+        if (drain_frames(decoderCtx, decodedFrame, &sheet) < 0) {
+            goto cleanup;
+        }
+    }
+
+    // End of input: flush the decoder for the frames it held back
+    if (sheet.next < sheet.count) {
+        ret = avcodec_send_packet(decoderCtx, NULL);
+        if (ret < 0 && ret != AVERROR_EOF) {
+            fprintf(stderr, "avcodec_send_packet() failed: %d\n", ret);
+            goto cleanup;
+        }
+        if (drain_frames(decoderCtx, decodedFrame, &sheet) < 0) {
+            goto cleanup;
+        }
+    }
+
+    if (sheet.next < sheet.count) {
+        fprintf(stderr, "Video ended after %d of %d cells.\n", sheet.next, sheet.count);
+        goto cleanup;
+    }
+
+    // ---------- Hand the pixels over to our result ---------
+    result = (FrameData*)malloc(sizeof(FrameData));
+    if (!result) {
+        fprintf(stderr, "Failed to allocate FrameData\n");
+        goto cleanup;
+    }
+    result->frameData = sheet.pixels;
+    result->frameSize = sheetSize;
+    result->width = cols * cellWidth;
+    result->height = sheetHeight;
+    result->pixelFormat = decoderCtx->pix_fmt;
+    sheet.pixels = NULL;
+
+cleanup:
+    if (sheet.pixels) {
+        free(sheet.pixels);
+    }
+    if (sheet.swsCtx) {
+        sws_freeContext(sheet.swsCtx);
+    }
+    if (decodedFrame) {
+        av_frame_free(&decodedFrame);
+    }
+    if (packet) {
+        av_packet_free(&packet);
+    }
+    if (decoderCtx) {
+        avcodec_free_context(&decoderCtx);
+    }
+    if (formatCtx) {
+        avformat_close_input(&formatCtx);
+        avformat_free_context(formatCtx);
+    }
+    if (avioCtx) {
+        if (avioCtx->buffer) {
+            av_freep(&avioCtx->buffer);
+        }
+        av_freep(&avioCtx);
+    } else if (ioBuffer) {
+        av_free(ioBuffer);
+    }
+
+    return result;
+}
@@ -0,0 +1,21 @@
+#ifndef EXTRACT_SPRITE_SHEET_H
+#define EXTRACT_SPRITE_SHEET_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#include "extract_jpeg_frame.h"
+
+/**
+ * Decode videoData once and lay out cols * rows frames as one image: cell i is
+ * the first frame at or after timestamps[i] (ascending, cols * rows entries),
+ * scaled to cellWidth pixels wide keeping the aspect ratio, filled row by row.
+ *
+ * Return a FrameData holding the sheet as packed RGB24 pixels (width * height
+ * * 3 bytes, free with free_frame_data()), or NULL on error or if the video
+ * ends before the last timestamp.
+ */
+FrameData* extract_sprite_sheet(const uint8_t* videoData, size_t dataSize,
+                                const double* timestamps, int cols, int rows, int cellWidth);
+
+#endif
@@ -1 +1,3 @@
 #include "extract_jpeg_frame.h"
+#include "extract_animated_preview.h"
+#include "extract_sprite_sheet.h"
@@ -8,11 +8,114 @@ use ffi::*;
 
 use std::slice;
 
+/// A JPEG-encoded frame and what we know about it.
+#[derive(Debug, Clone)]
+pub struct ExtractedFrame {
+    pub jpeg: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// FFmpeg `AVPixelFormat` of the decoded source frame, -1 if unknown
+    pub pixel_format: i32,
+}
+
 /// A safe Rust wrapper around `extract_jpeg_frame`.
-pub fn extract_first_frame_to_jpeg(video_data: &[u8]) -> Result<Vec<u8>, String> {
+pub fn extract_first_frame_to_jpeg(video_data: &[u8]) -> Result<ExtractedFrame, String> {
     // Call the unsafe C function
     let ptr = unsafe { extract_jpeg_frame(video_data.as_ptr(), video_data.len()) };
+    take_frame(ptr)
+}
+
+/// A safe Rust wrapper around `extract_jpeg_frame_at`: returns the JPEG of the
+/// first frame at or after `seconds`.
+pub fn extract_frame_at_timestamp(video_data: &[u8], seconds: f64) -> Result<Vec<u8>, String> {
+    let ptr = unsafe { extract_jpeg_frame_at(video_data.as_ptr(), video_data.len(), seconds) };
+    take_frame_data(ptr)
+}
+
+/// Duration and (estimated) frame count of a video, from its container headers.
+#[derive(Debug, Clone, Copy)]
+pub struct VideoInfo {
+    pub duration_seconds: f64,
+    pub frame_count: u64,
+}
+
+/// A safe Rust wrapper around `probe_video_info`.
+pub fn probe_video(video_data: &[u8]) -> Result<VideoInfo, String> {
+    let mut info = ffi::VideoInfo { durationSeconds: 0.0, frameCount: 0 };
+    let ret = unsafe { probe_video_info(video_data.as_ptr(), video_data.len(), &mut info) };
+    if ret < 0 {
+        return Err("Failed to probe video".into());
+    }
+    Ok(VideoInfo {
+        duration_seconds: info.durationSeconds.max(0.0),
+        frame_count: info.frameCount.max(0) as u64,
+    })
+}
+
+/// Widest animated preview we produce; larger videos are downscaled.
+const PREVIEW_MAX_WIDTH: i32 = 320;
+
+/// A safe Rust wrapper around `extract_animated_preview`: decodes the first
+/// `seconds` of the video, samples it at `fps` and returns a looping animated GIF.
+pub fn extract_animated_preview(video_data: &[u8], seconds: f64, fps: u32) -> Result<Vec<u8>, String> {
+    let fps = i32::try_from(fps).map_err(|_| "fps out of range".to_string())?;
+    let ptr = unsafe {
+        ffi::extract_animated_preview(
+            video_data.as_ptr(),
+            video_data.len(),
+            seconds,
+            fps,
+            PREVIEW_MAX_WIDTH,
+        )
+    };
+    take_frame_data(ptr)
+}
+
+/// A sprite sheet as packed RGB24 pixels, row by row.
+#[derive(Debug, Clone)]
+pub struct RgbSheet {
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A safe Rust wrapper around `extract_sprite_sheet`: decodes the video once
+/// and lays out the first frame at or after each of `timestamps` (ascending,
+/// `cols * rows` of them), scaled to `cell_width` wide, row by row.
+pub fn extract_sprite_sheet(
+    video_data: &[u8],
+    timestamps: &[f64],
+    cols: u32,
+    rows: u32,
+    cell_width: u32,
+) -> Result<RgbSheet, String> {
+    let out_of_range = |_| "sprite sheet size out of range".to_string();
+    let cells = cols.checked_mul(rows).ok_or("too many cells")?;
+    if timestamps.len() != cells as usize {
+        return Err(format!("{} timestamps for {} cells", timestamps.len(), cells));
+    }
+    let (cols, rows) = (i32::try_from(cols).map_err(out_of_range)?, i32::try_from(rows).map_err(out_of_range)?);
+    let cell_width = i32::try_from(cell_width).map_err(out_of_range)?;
+    let ptr = unsafe {
+        ffi::extract_sprite_sheet(
+            video_data.as_ptr(),
+            video_data.len(),
+            timestamps.as_ptr(),
+            cols,
+            rows,
+            cell_width,
+        )
+    };
+    take_frame(ptr).map(|frame| RgbSheet { pixels: frame.jpeg, width: frame.width, height: frame.height })
+}
+
+/// Copy the encoded bytes out of a C-allocated `FrameData` and free it.
+fn take_frame_data(ptr: *mut FrameData) -> Result<Vec<u8>, String> {
+    take_frame(ptr).map(|frame| frame.jpeg)
+}
 
+/// Like `take_frame_data`, keeping the image dimensions and source pixel format.
+fn take_frame(ptr: *mut FrameData) -> Result<ExtractedFrame, String> {
     if ptr.is_null() {
         return Err("Failed to extract frame (null pointer returned)".into());
     }
@@ -30,13 +133,17 @@ pub fn extract_first_frame_to_jpeg(video_data: &[u8]) -> Result<Vec<u8>, String>
 
     // Copy the JPEG bytes into a Vec<u8> for safe ownership in Rust
     let slice = unsafe { slice::from_raw_parts(frame_data.frameData, frame_data.frameSize as usize) };
-    let jpeg_bytes = slice.to_vec();
+    let frame = ExtractedFrame {
+        jpeg: slice.to_vec(),
+        width: frame_data.width.max(0) as u32,
+        height: frame_data.height.max(0) as u32,
+        pixel_format: frame_data.pixelFormat,
+    };
 
     // Free the C-allocated memory
     unsafe {
         free_frame_data(ptr);
     }
 
-    // Return the JPEG bytes
-    Ok(jpeg_bytes)
+    Ok(frame)
 }
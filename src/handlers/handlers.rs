@@ -1,28 +1,44 @@
 use axum::{
     body::Body,
-    extract::{Query, State},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Query, State,
+    },
     http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
 use bytes::Bytes;
-use futures_util::StreamExt;
+use futures_util::stream::{self, BoxStream, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{io::SeekFrom};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc};
+use std::time::{SystemTime, UNIX_EPOCH};
 use axum::response::Html;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 use tokio_util::io::ReaderStream;
+use tracing::warn;
+use crate::discovery::fetchers::{RelayAuthStatus, RelayStatus};
+use crate::download::manager::{reparse_metadata, DownloadManager};
 use crate::service::state::AppState;
-use crate::models::models::VideoDownload;
+use crate::models::models::{StateEvent, VideoDownload};
+use crate::utils::frames;
+use crate::utils::utils::ThumbFormat;
 
 #[derive(Debug, Deserialize)]
 pub struct VideoQuery {
     pub id: String,
+    /// `/video.mp4` only: start roughly this many seconds in, see `seek_offset`
+    pub seek: Option<f64>,
 }
 
 /// Serve video in partial content (Range) if requested, or full if no Range is given.
+/// `?seek=<seconds>` without a Range header returns a 206 starting at an
+/// approximate offset, see `seek_offset`.
 ///
 /// Example usage: GET /video.mp4?index=0
 pub async fn stream_video(
@@ -30,113 +46,444 @@ pub async fn stream_video(
     Query(query): Query<VideoQuery>,
     headers: HeaderMap,
 ) -> Result<Response, StatusCode> {
+    state.touch().await;
     let id = query.id;
-    let maybe_path = {
-        let videos = state.discovered_videos.lock().await;
-        videos.get(&id).and_then(|v| v.local_path.clone())
+    let (maybe_path, bitrate_bps, content_length, downloading, downloaded_bytes, content_type) = {
+        let videos = state.discovered_videos.read().await;
+        match videos.get(&id) {
+            Some(v) => (
+                v.local_path.clone(),
+                v.bitrate_bps,
+                v.content_length,
+                v.downloading,
+                v.downloaded_bytes,
+                v.content_type(),
+            ),
+            None => (None, None, None, false, 0, String::new()),
+        }
     };
 
     let Some(path) = maybe_path else {
         return Err(StatusCode::NOT_FOUND);
     };
 
-    let meta = tokio::fs::metadata(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
-    let file_size = meta.len();
+    let file_size = state.store.len(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+
+    // A file still being downloaded is served as a growing resource: only the
+    // bytes recorded as written are available, and the advertised total is
+    // the eventual Content-Length so players know more is coming.
+    let (available, total_size) = if downloading {
+        (
+            file_size.min(downloaded_bytes),
+            content_length.unwrap_or(file_size).max(file_size),
+        )
+    } else {
+        (file_size, file_size)
+    };
+
+    // Validators, so a resuming client can tell whether its earlier bytes are
+    // still from this file. The ETag names the file and its eventual size, so
+    // it survives the file growing but not the download being restarted.
+    let modified = state.store.modified(&path).await.ok();
+    let etag = format!(
+        "\"{}-{:x}\"",
+        path.file_stem().and_then(|s| s.to_str()).unwrap_or_default(),
+        total_size
+    );
+    let last_modified = modified.map(httpdate::fmt_http_date);
+
+    // Check if we have a Range header. A non-matching If-Range means the
+    // client's copy is stale: ignore the Range and send the whole file.
+    let range_header = headers
+        .get(header::RANGE)
+        .and_then(|val| val.to_str().ok())
+        .filter(|_| match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) {
+            Some(if_range) => if_range_matches(if_range, &etag, modified),
+            None => true,
+        });
 
-    // Check if we have a Range header
-    let range_header = headers.get(header::RANGE).and_then(|val| val.to_str().ok());
+    // No Range but a `?seek=` hint: answer as if "bytes=<offset>-" was asked.
+    let seek_start = match (range_header, query.seek) {
+        (None, Some(seconds)) => seek_offset(seconds, bitrate_bps, available),
+        _ => None,
+    };
+    let seek_range = seek_start.map(|offset| format!("bytes={offset}-"));
+    let range_header = range_header.or(seek_range.as_deref());
 
     // If no Range header, return entire file
     if range_header.is_none() {
-        let file = File::open(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
-        let stream = ReaderStream::new(file);
+        let reader = state
+            .store
+            .get_range(&path, 0, available)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let stream = ReaderStream::new(reader);
         let body = Body::from_stream(stream);
 
-        return Ok(Response::builder()
+        return Ok(with_validators(Response::builder(), &etag, last_modified.as_deref())
             .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "video/mp4")
+            .header(header::CONTENT_TYPE, &content_type)
+            .header(header::ACCEPT_RANGES, "bytes")
             .body(body)
             .unwrap());
     }
 
     // We do have a Range header, parse it
     let range_str = range_header.unwrap();
-    let (start, end) = parse_range_header(range_str, file_size)?;
+    let ranges = parse_range_header(range_str, total_size)?;
 
-    // Ensure start < file_size
-    if start >= file_size {
+    // Asking far past the end of the whole video (not just what's downloaded
+    // so far) usually means a buggy client; worth a note before clamping.
+    let full_size = content_length.unwrap_or(file_size).max(file_size);
+    if ranges.iter().any(|&(_, end)| range_end_oversized(end, full_size)) {
+        warn!(video_id = %id, range = range_str, file_size, full_size, "Range end beyond the end of the video");
+    }
+
+    // Keep the ranges that start within what's downloaded, clamping their
+    // ends to it. If none do (also any range on an empty file), a 416 tells
+    // the player to retry once more has arrived.
+    let ranges = clamp_ranges(ranges, available);
+    if ranges.is_empty() {
         return Err(StatusCode::RANGE_NOT_SATISFIABLE);
     }
 
-    // If end is beyond the current downloaded size, clamp it
-    let end = end.min(file_size - 1);
-    let chunk_size = end - start + 1;
+    // The Content-Range total is the resource size (see `total_size`), never
+    // the requested end.
+    if let &[(start, end)] = ranges.as_slice() {
+        let chunk_size = (end - start).saturating_add(1);
 
-    // Seek file to 'start'
-    let mut file = File::open(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
-    file.seek(SeekFrom::Start(start)).await.map_err(|_| StatusCode::NOT_FOUND)?;
+        // We only read `chunk_size` bytes from 'start'
+        let limited_reader = state
+            .store
+            .get_range(&path, start, chunk_size)
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let stream = ReaderStream::new(limited_reader).map(|res| {
+            res.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+                .map(Bytes::from)
+        });
 
-    // We only read `chunk_size` bytes
-    let limited_reader = file.take(chunk_size);
-    let stream = ReaderStream::new(limited_reader).map(|res| {
-        res.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
-            .map(Bytes::from)
-    });
+        let body = Body::from_stream(stream);
 
-    let body = Body::from_stream(stream);
+        // Build partial content response
+        let content_range = format!("bytes {}-{}/{}", start, end, total_size);
 
-    // Build partial content response
-    let content_range = format!("bytes {}-{}/{}", start, end, file_size);
+        return Ok(with_validators(Response::builder(), &etag, last_modified.as_deref())
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_TYPE, &content_type)
+            .header(header::CONTENT_RANGE, content_range)
+            .header(header::ACCEPT_RANGES, "bytes")
+            .body(body)
+            .unwrap());
+    }
 
-    Ok(Response::builder()
+    // Several ranges: one multipart/byteranges body, each part with its own
+    // Content-Range, streamed from the store one after the other.
+    let boundary = format!("tokstr-{}", uuid::Uuid::new_v4().simple());
+    let mut parts: Vec<BoxStream<'static, std::io::Result<Bytes>>> = Vec::new();
+    for (start, end) in ranges {
+        let part_header = format!(
+            "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+            boundary, content_type, start, end, total_size
+        );
+        let reader = state
+            .store
+            .get_range(&path, start, (end - start).saturating_add(1))
+            .await
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        parts.push(stream::once(async move { Ok(Bytes::from(part_header)) }).boxed());
+        parts.push(ReaderStream::new(reader).boxed());
+        parts.push(stream::once(async { Ok(Bytes::from_static(b"\r\n")) }).boxed());
+    }
+    let closing = Bytes::from(format!("--{}--\r\n", boundary));
+    parts.push(stream::once(async move { Ok(closing) }).boxed());
+
+    Ok(with_validators(Response::builder(), &etag, last_modified.as_deref())
         .status(StatusCode::PARTIAL_CONTENT)
-        .header(header::CONTENT_TYPE, "video/mp4")
-        .header(header::CONTENT_RANGE, content_range)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
         .header(header::ACCEPT_RANGES, "bytes")
-        .body(body)
+        .body(Body::from_stream(stream::iter(parts).flatten()))
         .unwrap())
 }
 
-/// A simple Range header parser that expects: "bytes=start-end".
-/// Example: "bytes=0-1023" => (0, 1023).
-/// If "bytes=100-" => (100, file_size-1).
-fn parse_range_header(range_str: &str, file_size: u64) -> Result<(u64, u64), StatusCode> {
-    // Ensure format
-    if !range_str.starts_with("bytes=") {
-        return Err(StatusCode::BAD_REQUEST);
+#[derive(Debug, Serialize)]
+pub struct VideoInfoResponse {
+    pub length_seconds: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<String>,
+    pub content_length: Option<u64>,
+    pub downloaded_bytes: u64,
+    pub downloading: bool,
+    /// Fraction downloaded, 0.0..=1.0, if the size is known
+    pub progress: Option<f64>,
+    /// Remaining download time at the current speed, while downloading
+    pub eta_seconds: Option<f64>,
+}
+
+/// Metadata and download progress for one video, without the body.
+/// A lightweight alternative to `/status`.
+///
+/// Example usage: GET /video/info?id=<video id>
+pub async fn video_info(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<VideoQuery>,
+) -> Result<Json<VideoInfoResponse>, StatusCode> {
+    let videos = state.discovered_videos.read().await;
+    let video = videos.get(&query.id).ok_or(StatusCode::NOT_FOUND)?;
+
+    let progress = video
+        .content_length
+        .filter(|&len| len > 0)
+        .map(|len| (video.downloaded_bytes as f64 / len as f64).min(1.0));
+    let eta_seconds = match video.content_length {
+        Some(len) if video.downloading && video.download_speed_bps > 0.0 => {
+            Some(len.saturating_sub(video.downloaded_bytes) as f64 / video.download_speed_bps)
+        }
+        _ => None,
+    };
+
+    Ok(Json(VideoInfoResponse {
+        length_seconds: video.length_seconds,
+        width: video.width,
+        height: video.height,
+        format: video.format.clone(),
+        content_length: video.content_length,
+        downloaded_bytes: video.downloaded_bytes,
+        downloading: video.downloading,
+        progress,
+        eta_seconds,
+    }))
+}
+
+/// Approximate byte offset `seconds` into a file, from its average bitrate,
+/// clamped to what's downloaded so far. This is not keyframe-accurate: players
+/// resync on the next keyframe (or, for MP4, need the `moov` box from a
+/// separate range request). `None` if there's no bitrate or nothing to skip, in
+/// which case the whole file is served.
+fn seek_offset(seconds: f64, bitrate_bps: Option<f64>, file_size: u64) -> Option<u64> {
+    if !seconds.is_finite() || seconds <= 0.0 || file_size == 0 {
+        return None;
     }
-    let no_prefix = &range_str[6..];
-    let parts: Vec<&str> = no_prefix.split('-').collect();
-    if parts.len() != 2 {
-        return Err(StatusCode::BAD_REQUEST);
+    let offset = (seconds * bitrate_bps? / 8.0) as u64;
+    Some(offset.min(file_size.saturating_sub(1))).filter(|&offset| offset > 0)
+}
+
+/// Whether an `If-Range` value (an ETag or an HTTP date) still describes the
+/// file. Dates match if the file hasn't been written to since.
+fn if_range_matches(if_range: &str, etag: &str, modified: Option<SystemTime>) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        // If-Range needs a strong comparison, so weak tags never match.
+        return if_range == etag;
+    }
+    match (httpdate::parse_http_date(if_range), modified) {
+        (Ok(date), Some(modified)) => {
+            // HTTP dates have whole-second resolution.
+            let secs = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            secs(modified) <= secs(date)
+        }
+        _ => false,
+    }
+}
+
+fn with_validators(
+    builder: axum::http::response::Builder,
+    etag: &str,
+    last_modified: Option<&str>,
+) -> axum::http::response::Builder {
+    let builder = builder.header(header::ETAG, etag);
+    match last_modified {
+        Some(last_modified) => builder.header(header::LAST_MODIFIED, last_modified),
+        None => builder,
     }
+}
+
+/// Keep the ranges starting within the first `available` bytes, with their
+/// ends clamped to the last of them.
+fn clamp_ranges(ranges: Vec<(u64, u64)>, available: u64) -> Vec<(u64, u64)> {
+    ranges
+        .into_iter()
+        .filter(|&(start, _)| start < available)
+        .map(|(start, end)| (start, end.min(available.saturating_sub(1))))
+        .collect()
+}
 
-    // Parse start
-    let start: u64 = parts[0].parse().map_err(|_| StatusCode::BAD_REQUEST)?;
-    // Parse end
-    if parts[1].is_empty() {
+/// How many times the video size a range end may reach before it's logged.
+const OVERSIZED_RANGE_FACTOR: u64 = 2;
+
+/// A simple Range header parser that expects: "bytes=start-end", or several
+/// comma-separated ranges ("bytes=0-99,200-299"), returned in request order.
+/// Example: "bytes=0-1023" => [(0, 1023)].
+/// If "bytes=100-" => [(100, file_size-1)].
+/// If "bytes=-500" => the last 500 bytes (the whole file if it's smaller).
+/// Any malformed range (including a reversed one) is a 400, whatever the file
+/// size. Ranges the file can't satisfy (starting past the end, an empty
+/// suffix "bytes=-0", anything on an empty file) are dropped, and only if none
+/// are left is it a 416.
+fn parse_range_header(range_str: &str, file_size: u64) -> Result<Vec<(u64, u64)>, StatusCode> {
+    // Check the syntax of every range before looking at the file
+    let specs = range_str
+        .strip_prefix("bytes=")
+        .ok_or(StatusCode::BAD_REQUEST)?
+        .split(',')
+        .map(|spec| parse_range_spec(spec.trim()).ok_or(StatusCode::BAD_REQUEST))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let ranges: Vec<(u64, u64)> = specs
+        .into_iter()
+        .filter_map(|spec| satisfiable_range(spec, file_size))
+        .collect();
+    if ranges.is_empty() {
+        return Err(StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+    Ok(ranges)
+}
+
+/// Whether a range end is far enough past a `size`-byte video to be worth a
+/// warning. Players routinely ask for a little more than there is (or for
+/// "0-" spelled out with a guessed size), so only ends beyond
+/// `OVERSIZED_RANGE_FACTOR` times the size count.
+fn range_end_oversized(end: u64, size: u64) -> bool {
+    end > size.saturating_mul(OVERSIZED_RANGE_FACTOR)
+}
+
+/// One range of a Range header, as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeSpec {
+    /// "start-end", or "start-" (no end) for everything from `start`
+    From(u64, Option<u64>),
+    /// "-suffix": the last `suffix` bytes
+    Suffix(u64),
+}
+
+/// Parse one "start-end" (or "start-" / "-suffix"), None if it's malformed.
+fn parse_range_spec(spec: &str) -> Option<RangeSpec> {
+    let (first, last) = spec.split_once('-')?;
+
+    // Suffix range: "bytes=-500" asks for the last 500 bytes (players fetching
+    // a tail `moov` box do this).
+    if first.is_empty() {
+        return last.parse().ok().map(RangeSpec::Suffix);
+    }
+
+    let start: u64 = first.parse().ok()?;
+    if last.is_empty() {
         // "bytes=100-" means from 100 to the end
-        let end = file_size - 1;
-        Ok((start, end))
-    } else {
-        let end: u64 = parts[1].parse().map_err(|_| StatusCode::BAD_REQUEST)?;
-        Ok((start, end))
+        return Some(RangeSpec::From(start, None));
+    }
+    let end: u64 = last.parse().ok()?;
+    // An end before the start makes the range invalid, not just unsatisfiable.
+    (end >= start).then_some(RangeSpec::From(start, Some(end)))
+}
+
+/// The inclusive byte range `spec` asks for in a `file_size`-byte file, None
+/// if it asks for nothing there. Ends past the file are left for the caller
+/// to clamp.
+fn satisfiable_range(spec: RangeSpec, file_size: u64) -> Option<(u64, u64)> {
+    let last = file_size.checked_sub(1)?;
+    match spec {
+        RangeSpec::Suffix(0) => None,
+        RangeSpec::Suffix(len) => Some((file_size.saturating_sub(len), last)),
+        RangeSpec::From(start, _) if start >= file_size => None,
+        RangeSpec::From(start, end) => Some((start, end.unwrap_or(last))),
+    }
+}
+
+/// A video as listed by `/status`: just what a progress view needs, so the
+/// polled path doesn't copy every event's metadata. `/video/info` has the rest.
+#[derive(Debug, Serialize)]
+pub struct StatusVideo {
+    pub id: String,
+    pub title: String,
+    pub local_path: Option<PathBuf>,
+    pub downloading: bool,
+    pub downloaded_bytes: u64,
+    pub content_length: Option<u64>,
+    pub download_speed_bps: f64,
+    pub length_seconds: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub format: Option<String>,
+    pub favorited: bool,
+}
+
+impl StatusVideo {
+    fn new(video: &VideoDownload, favorited: bool) -> Self {
+        Self {
+            id: video.id.clone(),
+            title: video.nostr.title.clone(),
+            local_path: video.local_path.clone(),
+            downloading: video.downloading,
+            downloaded_bytes: video.downloaded_bytes,
+            content_length: video.content_length,
+            download_speed_bps: video.download_speed_bps,
+            length_seconds: video.length_seconds,
+            width: video.width,
+            height: video.height,
+            format: video.format.clone(),
+            favorited,
+        }
     }
 }
 
 #[derive(Debug, Serialize)]
 pub struct StatusResponse {
+    /// False while relays are still connecting (the response is then a 503)
+    pub ready: bool,
     pub current_index: usize,
-    pub videos: Vec<VideoDownload>,
+    /// Number of videos matching the filters, before `offset`/`limit`
+    pub total: usize,
+    pub videos: Vec<StatusVideo>,
     pub used_storage_bytes: u64,
     pub max_storage_bytes: u64,
     pub total_download_speed_bps: f64,
+    /// Total length of every known video with a known length, downloaded or not.
+    /// Kept for older clients; see `buffered_minutes` for what's playable.
     pub total_downloaded_minutes: f64,
+    /// Playback actually on disk, counting partial downloads pro rata
+    /// (see `VideoDownload::downloaded_seconds`); compare to `target_minutes_ahead`
+    pub buffered_minutes: f64,
+    pub active_playlist: String,
+    pub playlists: Vec<String>,
+    /// NIP-42 auth state of relays that challenged or refused us
+    pub relay_auth: HashMap<String, RelayAuthStatus>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StatusQuery {
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub downloaded_only: bool,
+    #[serde(default)]
+    pub downloading_only: bool,
 }
 
 /// Returns JSON status of the system.
-pub async fn get_status(State(state): State<Arc<AppState>>) -> impl IntoResponse{
-    let list = state.discovered_videos.lock().await;
+///
+/// The `videos` list can be filtered and paginated with
+/// `?offset=&limit=&downloaded_only=&downloading_only=`; the aggregate stats
+/// always cover every discovered video.
+pub async fn get_status(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<StatusQuery>,
+) -> impl IntoResponse {
+    state.touch().await;
+    let status = build_status(&state, &query).await;
+
+    // 503 until discovery is up, so clients can show "connecting" instead of "no content".
+    let code = if status.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (code, Json(status))
+}
+
+/// The `/status` body; also the first message on `/ws`.
+async fn build_status(state: &AppState, query: &StatusQuery) -> StatusResponse {
+    let favorites = state.favorites.lock().await.clone();
+    let list = state.discovered_videos.read().await;
     let current_idx = *state.current_index.lock().await;
     let used_storage = *state.current_storage_bytes.lock().await;
 
@@ -154,17 +501,47 @@ pub async fn get_status(State(state): State<Arc<AppState>>) -> impl IntoResponse
             total_minutes += length / 60.0;
         }
     }
+    let buffered_minutes = list
+        .values()
+        .filter_map(|v| v.downloaded_seconds())
+        .sum::<f64>()
+        / 60.0;
 
-    let status = StatusResponse {
+    // Sort by id so pages are stable between calls.
+    let mut matching: Vec<&VideoDownload> = list
+        .values()
+        .filter(|v| !query.downloaded_only || (v.local_path.is_some() && !v.downloading))
+        .filter(|v| !query.downloading_only || v.downloading)
+        .collect();
+    matching.sort_by(|a, b| a.id.cmp(&b.id));
+    let total = matching.len();
+    let videos = matching
+        .into_iter()
+        .skip(query.offset.unwrap_or(0))
+        .take(query.limit.unwrap_or(usize::MAX))
+        .map(|v| StatusVideo::new(v, favorites.contains(&v.id)))
+        .collect();
+
+    let active_playlist = state.active_playlist.lock().await.clone();
+    let playlists = state.playlist_names().await;
+    let relay_auth = state.content_discovery.auth_status().await;
+
+    let ready = state.ready.load(Ordering::Relaxed);
+
+    StatusResponse {
+        ready,
         current_index: current_idx,
-        videos: list.values().cloned().collect(),
+        total,
+        videos,
         used_storage_bytes: used_storage,
-        max_storage_bytes: state.max_storage_bytes,
+        max_storage_bytes: state.max_storage_bytes.load(Ordering::Relaxed),
         total_download_speed_bps: total_speed,
         total_downloaded_minutes: total_minutes,
-    };
-
-    Json(status)
+        buffered_minutes,
+        active_playlist,
+        playlists,
+        relay_auth,
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -172,12 +549,57 @@ pub struct SetIndexRequest {
     pub index: usize,
 }
 
+/// Set the watch index of the active playlist; 400 if it has no such item.
 pub async fn set_index(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<SetIndexRequest>,
-) -> impl IntoResponse {
-    *state.current_index.lock().await = payload.index;
-    "OK"
+) -> Result<&'static str, StatusCode> {
+    state.touch().await;
+    if !state.set_index(payload.index).await {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    state.manager_wake.notify_one();
+    Ok("OK")
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaylistsResponse {
+    pub active: String,
+    pub playlists: Vec<String>,
+}
+
+/// Lists the named playlists and which one is active.
+pub async fn list_playlists(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let active = state.active_playlist.lock().await.clone();
+    let playlists = state.playlist_names().await;
+    Json(PlaylistsResponse { active, playlists })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaylistRequest {
+    pub name: String,
+}
+
+pub async fn create_playlist(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PlaylistRequest>,
+) -> Result<&'static str, StatusCode> {
+    if state.create_playlist(&payload.name).await {
+        Ok("OK")
+    } else {
+        Err(StatusCode::CONFLICT)
+    }
+}
+
+pub async fn switch_playlist(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<PlaylistRequest>,
+) -> Result<&'static str, StatusCode> {
+    if state.switch_playlist(&payload.name).await {
+        Ok("OK")
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
 }
 
 
@@ -185,20 +607,40 @@ pub async fn set_index(
 #[derive(Debug, Deserialize)]
 pub struct ThumbnailQuery {
     pub id: String,
+    /// If set, extract the frame at this many seconds instead of serving the
+    /// pre-generated thumbnail.
+    pub t: Option<f64>,
 }
 
+/// Upper bound on cached on-demand frames before the cache is cleared.
+const MAX_CACHED_FRAMES: usize = 512;
+
 pub async fn get_thumbnail(
     State(state): State<Arc<AppState>>,
     Query(query): Query<ThumbnailQuery>,
 ) -> Result<Response, StatusCode> {
     let id = query.id;
 
-    let maybe_thumb = {
-        let videos = state.discovered_videos.lock().await;
-        videos.get(&id)
-            .and_then(|v| v.thumbnail_path.clone())
+    if let Some(t) = query.t {
+        let data = frame_at_timestamp(&state, &id, t).await?;
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "image/jpeg")
+            .body(Body::from(data))
+            .unwrap());
+    }
+
+    let (maybe_thumb, hash) = {
+        let videos = state.discovered_videos.read().await;
+        let video = videos.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+        (video.thumbnail_path.clone(), video.nostr.id.clone())
     };
 
+    // Not generated this session; one from an earlier run may be cached.
+    let maybe_thumb = match maybe_thumb {
+        Some(path) => Some(path),
+        None => state.thumbnail_cache.find(&hash, state.thumbnail_format).await,
+    };
     let Some(thumb_path) = maybe_thumb else {
         return Err(StatusCode::NOT_FOUND);
     };
@@ -209,14 +651,680 @@ pub async fn get_thumbnail(
         Err(_) => return Err(StatusCode::NOT_FOUND),
     };
 
+    // The setting may have changed since this one was written; go by the file.
+    let content_type = ThumbFormat::from_path(&thumb_path)
+        .unwrap_or(state.thumbnail_format)
+        .content_type();
+
     Ok(Response::builder()
         .status(StatusCode::OK)
-        .header("Content-Type", "image/jpeg")
+        .header("Content-Type", content_type)
         .body(Body::from(data))
         .unwrap())
 }
 
 
+/// Extract (or fetch from cache) the frame at `t` seconds of a downloaded video.
+/// Scrubbing tends to hit the same positions, so results are cached per
+/// (id, t rounded to 0.1s).
+async fn frame_at_timestamp(state: &AppState, id: &str, t: f64) -> Result<Bytes, StatusCode> {
+    if !t.is_finite() || t < 0.0 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let key = (id.to_string(), (t * 10.0).round() as u64);
+
+    if let Some(cached) = state.frame_cache.lock().await.get(&key) {
+        return Ok(cached.clone());
+    }
+
+    let maybe_path = {
+        let videos = state.discovered_videos.read().await;
+        videos.get(id).and_then(|v| v.local_path.clone())
+    };
+    let Some(path) = maybe_path else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let video_data = state.store.read_all(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+    let seconds = key.1 as f64 / 10.0;
+    let jpeg = tokio::task::spawn_blocking(move || {
+        frames::extract_frame_at_timestamp(&video_data, seconds)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|_| StatusCode::NOT_FOUND)?;
+    let jpeg = Bytes::from(jpeg);
+
+    let mut cache = state.frame_cache.lock().await;
+    if cache.len() >= MAX_CACHED_FRAMES {
+        cache.clear();
+    }
+    cache.insert(key, jpeg.clone());
+    Ok(jpeg)
+}
+
+/// Length and frame rate of `/preview` when not given.
+const DEFAULT_PREVIEW_SECONDS: f64 = 3.0;
+const DEFAULT_PREVIEW_FPS: u32 = 10;
+/// Upper bounds for `/preview` parameters, to keep generation cheap.
+const MAX_PREVIEW_SECONDS: f64 = 10.0;
+const MAX_PREVIEW_FPS: u32 = 24;
+/// Upper bound on cached previews before the cache is cleared.
+const MAX_CACHED_PREVIEWS: usize = 128;
+
+#[derive(Debug, Deserialize)]
+pub struct PreviewQuery {
+    pub id: String,
+    /// Preview length in seconds (default 3, max 10)
+    pub seconds: Option<f64>,
+    /// Frames per second (default 10, max 24)
+    pub fps: Option<u32>,
+}
+
+/// Serve a short looping animated GIF of the start of a downloaded video.
+/// Generated on first request and cached.
+///
+/// Example usage: GET /preview?id=<video id>&seconds=3&fps=10
+pub async fn get_preview(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<PreviewQuery>,
+) -> Result<Response, StatusCode> {
+    let seconds = query.seconds.unwrap_or(DEFAULT_PREVIEW_SECONDS);
+    let fps = query.fps.unwrap_or(DEFAULT_PREVIEW_FPS);
+    if !seconds.is_finite() || seconds <= 0.0 || seconds > MAX_PREVIEW_SECONDS || fps == 0 || fps > MAX_PREVIEW_FPS {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let key = (query.id.clone(), (seconds * 10.0).round() as u64, fps);
+
+    let cached = state.preview_cache.lock().await.get(&key).cloned();
+    let gif = match cached {
+        Some(gif) => gif,
+        None => {
+            let maybe_path = {
+                let videos = state.discovered_videos.read().await;
+                videos.get(&query.id).and_then(|v| v.local_path.clone())
+            };
+            let Some(path) = maybe_path else {
+                return Err(StatusCode::NOT_FOUND);
+            };
+            let video_data = state.store.read_all(&path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+            let gif = tokio::task::spawn_blocking(move || {
+                frames::extract_animated_preview(&video_data, seconds, fps)
+            })
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+            let gif = Bytes::from(gif);
+
+            let mut cache = state.preview_cache.lock().await;
+            if cache.len() >= MAX_CACHED_PREVIEWS {
+                cache.clear();
+            }
+            cache.insert(key, gif.clone());
+            gif
+        }
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/gif")
+        .body(Body::from(gif))
+        .unwrap())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReparseQuery {
+    pub id: String,
+}
+
+/// Re-run metadata extraction on an already-downloaded file.
+pub async fn reparse(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReparseQuery>,
+) -> Result<&'static str, StatusCode> {
+    match reparse_metadata(&state, &query.id).await {
+        Ok(true) => Ok("OK"),
+        Ok(false) => Err(StatusCode::UNPROCESSABLE_ENTITY),
+        Err(_) => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Ask the relays for the latest videos now (pull-to-refresh). Debounced.
+pub async fn refresh(State(state): State<Arc<AppState>>) -> Result<&'static str, StatusCode> {
+    if state.content_discovery.refresh().await {
+        state.manager_wake.notify_one();
+        Ok("OK")
+    } else {
+        Err(StatusCode::TOO_MANY_REQUESTS)
+    }
+}
+
+/// Download progress as server-sent events, one JSON `ProgressEvent` per
+/// message, instead of polling `/status`. A listener that falls behind skips
+/// the events it missed.
+pub async fn progress_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<BoxStream<'static, Result<Event, Infallible>>> {
+    let receiver = state.events.subscribe();
+    let events = stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(StateEvent::Progress(progress)) => {
+                    let event = Event::default().json_data(&progress).unwrap_or_default();
+                    return Some((Ok(event), receiver));
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(events.boxed()).keep_alive(KeepAlive::default())
+}
+
+/// Push updates over a WebSocket: the full status (`{"type": "status", ...}`)
+/// on connect, then every `StateEvent` as it happens (progress, newly
+/// discovered videos, playlist changes). Messages from the client are ignored.
+pub async fn ws_updates(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> Response {
+    ws.on_upgrade(move |socket| push_updates(socket, state))
+}
+
+async fn push_updates(mut socket: WebSocket, state: Arc<AppState>) {
+    // Subscribe before taking the snapshot so nothing in between is lost.
+    let mut receiver = state.events.subscribe();
+    let status = build_status(&state, &StatusQuery::default()).await;
+    let initial = serde_json::json!({ "type": "status", "status": status });
+    if socket.send(Message::Text(initial.to_string().into())).await.is_err() {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            update = receiver.recv() => match update {
+                Ok(event) => {
+                    let Ok(text) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "WebSocket client fell behind; skipping updates");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    // Returning drops the receiver, unsubscribing this client.
+}
+
+/// Connection state of every relay.
+pub async fn list_relays(State(state): State<Arc<AppState>>) -> Json<Vec<RelayStatus>> {
+    Json(state.content_discovery.relays().await)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelayRequest {
+    pub url: String,
+}
+
+/// Add a relay and subscribe to it, without restarting.
+pub async fn add_relay(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RelayRequest>,
+) -> Result<&'static str, (StatusCode, String)> {
+    state
+        .content_discovery
+        .add_relay(request.url)
+        .await
+        .map(|_| "OK")
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Drop a relay from the pool.
+pub async fn remove_relay(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<RelayRequest>,
+) -> Result<&'static str, (StatusCode, String)> {
+    state
+        .content_discovery
+        .remove_relay(request.url)
+        .await
+        .map(|_| "OK")
+        .map_err(|e| (StatusCode::NOT_FOUND, e.to_string()))
+}
+
+#[derive(Debug, Serialize)]
+pub struct StopAllResponse {
+    /// Ids whose downloads were cancelled
+    pub stopped: Vec<String>,
+}
+
+/// Cancel every running download and clear the queue ("stop everything").
+/// Downloads stay paused until `/resume`.
+pub async fn stop_all_downloads(
+    Extension(manager): Extension<Arc<DownloadManager>>,
+) -> Json<StopAllResponse> {
+    let stopped = manager.active_downloads().await;
+    manager.stop_all().await;
+    Json(StopAllResponse { stopped })
+}
+
+/// Stop starting new downloads (e.g. the phone left wifi). Running downloads
+/// finish; discovery carries on.
+pub async fn pause_downloads(Extension(manager): Extension<Arc<DownloadManager>>) -> &'static str {
+    manager.pause();
+    "OK"
+}
+
+/// Start downloading again after `/pause`.
+pub async fn resume_downloads(Extension(manager): Extension<Arc<DownloadManager>>) -> &'static str {
+    manager.resume();
+    "OK"
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StopDownloadRequest {
+    pub id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StopDownloadResponse {
+    /// False if the video wasn't queued or downloading
+    pub stopped: bool,
+}
+
+/// Cancel one download and drop it from the queue. Its partial file is deleted
+/// and it isn't queued again until `/restart_download`.
+///
+/// Example usage: POST /stop_download with {"id": "<video id>"}
+pub async fn stop_download(
+    Extension(manager): Extension<Arc<DownloadManager>>,
+    Json(request): Json<StopDownloadRequest>,
+) -> Json<StopDownloadResponse> {
+    let stopped = manager.stop_download(&request.id).await;
+    Json(StopDownloadResponse { stopped })
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestartDownloadResponse {
+    /// False if the video was neither stopped nor failed
+    pub restarted: bool,
+}
+
+/// Let a video stopped with `/stop_download`, or whose download failed, be
+/// queued again.
+///
+/// Example usage: POST /restart_download with {"id": "<video id>"}
+pub async fn restart_download(
+    Extension(manager): Extension<Arc<DownloadManager>>,
+    Json(request): Json<StopDownloadRequest>,
+) -> Json<RestartDownloadResponse> {
+    let restarted = manager.restart_download(&request.id).await;
+    Json(RestartDownloadResponse { restarted })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteVideoQuery {
+    pub id: Option<String>,
+    /// Position in the active playlist, used when `id` is absent
+    pub index: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteVideoResponse {
+    pub id: String,
+    /// Bytes removed from disk
+    pub freed_bytes: u64,
+}
+
+/// Purge a video the client doesn't want: cancel its download, delete its
+/// file and thumbnail, and forget it everywhere.
+///
+/// Example usage: DELETE /video?index=3 or DELETE /video?id=<video id>
+pub async fn delete_video(
+    State(state): State<Arc<AppState>>,
+    Extension(manager): Extension<Arc<DownloadManager>>,
+    Query(query): Query<DeleteVideoQuery>,
+) -> Result<Json<DeleteVideoResponse>, StatusCode> {
+    let id = match (query.id, query.index) {
+        (Some(id), _) => id,
+        (None, Some(index)) => {
+            let playlist = state.playlist().await;
+            playlist.get(index).ok_or(StatusCode::NOT_FOUND)?.id.clone()
+        }
+        (None, None) => return Err(StatusCode::BAD_REQUEST),
+    };
+    let freed_bytes = manager.delete_video(&id).await.ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(DeleteVideoResponse { id, freed_bytes }))
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    M3u,
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Link to the files on disk instead of this server's `/video.mp4`
+    #[serde(default)]
+    pub local: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportEntry {
+    pub id: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub npub: Option<String>,
+    pub length_seconds: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub published_at: Option<i64>,
+    /// Whether the file is on disk, i.e. `url` works right now
+    pub downloaded: bool,
+    /// Stream URL or local path; `None` for pending entries
+    pub url: Option<String>,
+}
+
+/// Export the active playlist for external players (VLC, mpv, ...).
+/// `format=m3u` (default) lists only downloaded videos; `format=json` lists
+/// every entry with `downloaded` marking which links work now.
+///
+/// Example usage: GET /export?format=m3u
+pub async fn export_playlist(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+    headers: HeaderMap,
+) -> Response {
+    // Links point back at whatever address the client reached us on.
+    let host = headers
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("127.0.0.1");
+    let base_url = format!("http://{}", host);
+
+    let ids: Vec<String> = state.playlist().await.as_vec().into_iter().map(|v| v.id).collect();
+    let entries: Vec<ExportEntry> = {
+        let videos = state.discovered_videos.read().await;
+        ids.iter()
+            .filter_map(|id| videos.get(id))
+            .map(|v| {
+                let downloaded = v.local_path.is_some() && !v.downloading;
+                let url = match (&v.local_path, query.local) {
+                    _ if !downloaded => None,
+                    (Some(path), true) => Some(path.display().to_string()),
+                    _ => Some(format!("{}/video.mp4?id={}", base_url, v.id)),
+                };
+                ExportEntry {
+                    id: v.id.clone(),
+                    title: v.nostr.title.clone(),
+                    author: v.nostr.user.name.clone(),
+                    npub: v.nostr.user.npub.clone(),
+                    length_seconds: v.length_seconds,
+                    width: v.width,
+                    height: v.height,
+                    published_at: v.nostr.published_at,
+                    downloaded,
+                    url,
+                }
+            })
+            .collect()
+    };
+
+    match query.format {
+        ExportFormat::Json => Json(entries).into_response(),
+        ExportFormat::M3u => {
+            let mut m3u = String::from("#EXTM3U\n");
+            for entry in entries.iter() {
+                let Some(url) = &entry.url else { continue };
+                let duration = entry.length_seconds.map_or(-1, |secs| secs.round() as i64);
+                let name = match &entry.author {
+                    Some(author) => format!("{} - {}", author, entry.title),
+                    None => entry.title.clone(),
+                };
+                // Line breaks in a title would start a new m3u line.
+                let name = name.replace(['\r', '\n'], " ");
+                m3u.push_str(&format!("#EXTINF:{},{}\n{}\n", duration, name, url));
+            }
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "audio/x-mpegurl")
+                .header(header::CONTENT_DISPOSITION, "attachment; filename=\"tokstr.m3u8\"")
+                .body(Body::from(m3u))
+                .unwrap()
+        }
+    }
+}
+
+/// Upper bound for `max_parallel_downloads` via `/config`.
+const MAX_PARALLEL_DOWNLOADS_LIMIT: usize = 32;
+
+/// Effective tunables. The first group can be changed with `PUT /config`;
+/// the rest are fixed at startup and shown for reference.
+#[derive(Debug, Serialize)]
+pub struct ConfigResponse {
+    pub max_parallel_downloads: usize,
+    pub max_storage_bytes: u64,
+    pub target_minutes_ahead: f64,
+    pub target_videos_ahead: usize,
+    pub max_behind_seconds: u64,
+    pub prefetch_next: usize,
+
+    pub max_discovered: usize,
+    pub metered: bool,
+    pub sort_by_published: bool,
+    pub assumed_bitrate_bps: u64,
+    pub warm_start_count: usize,
+}
+
+/// Partial update for `PUT /config`; omitted fields are left as they are.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    pub max_parallel_downloads: Option<usize>,
+    pub max_storage_bytes: Option<u64>,
+    pub target_minutes_ahead: Option<f64>,
+    pub target_videos_ahead: Option<usize>,
+    pub max_behind_seconds: Option<u64>,
+    pub prefetch_next: Option<usize>,
+}
+
+async fn current_config(state: &AppState) -> ConfigResponse {
+    ConfigResponse {
+        max_parallel_downloads: state.max_parallel_downloads.load(Ordering::Relaxed),
+        max_storage_bytes: state.max_storage_bytes.load(Ordering::Relaxed),
+        target_minutes_ahead: *state.target_minutes_ahead.lock().await,
+        target_videos_ahead: state.target_videos_ahead.load(Ordering::Relaxed),
+        max_behind_seconds: state.max_behind_seconds.load(Ordering::Relaxed),
+        prefetch_next: state.prefetch_next.load(Ordering::Relaxed),
+        max_discovered: state.max_discovered,
+        metered: state.metered,
+        sort_by_published: state.sort_by_published,
+        assumed_bitrate_bps: state.assumed_bitrate_bps,
+        warm_start_count: state.warm_start_count,
+    }
+}
+
+/// Current tunables.
+///
+/// Example usage: GET /config
+pub async fn get_config(State(state): State<Arc<AppState>>) -> Json<ConfigResponse> {
+    Json(current_config(&state).await)
+}
+
+/// Update some tunables without a restart. Everything is validated before
+/// anything is applied; returns the effective config.
+///
+/// Example usage: PUT /config {"max_parallel_downloads": 4, "target_minutes_ahead": 30}
+pub async fn update_config(
+    State(state): State<Arc<AppState>>,
+    Json(update): Json<ConfigUpdate>,
+) -> Result<Json<ConfigResponse>, (StatusCode, String)> {
+    let invalid = |msg: &str| Err((StatusCode::BAD_REQUEST, msg.to_string()));
+    if let Some(n) = update.max_parallel_downloads {
+        if n == 0 || n > MAX_PARALLEL_DOWNLOADS_LIMIT {
+            return invalid("max_parallel_downloads must be between 1 and 32");
+        }
+    }
+    if update.max_storage_bytes == Some(0) {
+        return invalid("max_storage_bytes must be greater than 0");
+    }
+    if let Some(minutes) = update.target_minutes_ahead {
+        if !minutes.is_finite() || minutes < 0.0 {
+            return invalid("target_minutes_ahead must be a non-negative number");
+        }
+    }
+
+    if let Some(n) = update.max_parallel_downloads {
+        state.max_parallel_downloads.store(n, Ordering::Relaxed);
+    }
+    if let Some(bytes) = update.max_storage_bytes {
+        state.max_storage_bytes.store(bytes, Ordering::Relaxed);
+    }
+    if let Some(minutes) = update.target_minutes_ahead {
+        *state.target_minutes_ahead.lock().await = minutes;
+    }
+    if let Some(n) = update.target_videos_ahead {
+        state.target_videos_ahead.store(n, Ordering::Relaxed);
+    }
+    if let Some(seconds) = update.max_behind_seconds {
+        state.max_behind_seconds.store(seconds, Ordering::Relaxed);
+    }
+    if let Some(n) = update.prefetch_next {
+        state.prefetch_next.store(n, Ordering::Relaxed);
+    }
+    // Let the manager re-plan with the new values right away.
+    state.manager_wake.notify_one();
+
+    Ok(Json(current_config(&state).await))
+}
+
 pub async fn dashboard(State(_state): State<Arc<AppState>>) -> impl IntoResponse {
     Html(include_str!("../dashboard/dashboard.html"))
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_start_end() {
+        assert_eq!(parse_range_header("bytes=0-1023", 2000), Ok(vec![(0, 1023)]));
+        assert_eq!(parse_range_header("bytes=5-5", 2000), Ok(vec![(5, 5)]));
+    }
+
+    #[test]
+    fn range_open_ended() {
+        assert_eq!(parse_range_header("bytes=100-", 2000), Ok(vec![(100, 1999)]));
+        assert_eq!(parse_range_header("bytes=1999-", 2000), Ok(vec![(1999, 1999)]));
+    }
+
+    #[test]
+    fn range_suffix() {
+        assert_eq!(parse_range_header("bytes=-500", 2000), Ok(vec![(1500, 1999)]));
+        // Longer than the file: the whole file.
+        assert_eq!(parse_range_header("bytes=-5000", 2000), Ok(vec![(0, 1999)]));
+        assert_eq!(parse_range_header("bytes=-0", 2000), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+    }
+
+    #[test]
+    fn range_multiple_in_request_order() {
+        assert_eq!(
+            parse_range_header("bytes=200-299, 0-99,-10", 2000),
+            Ok(vec![(200, 299), (0, 99), (1990, 1999)])
+        );
+        // One bad spec fails the whole header.
+        assert_eq!(parse_range_header("bytes=0-99,x-1", 2000), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn range_list_drops_unsatisfiable_ranges() {
+        assert_eq!(parse_range_header("bytes=0-99,5000-6000", 2000), Ok(vec![(0, 99)]));
+        assert_eq!(parse_range_header("bytes=5000-,-0,100-", 2000), Ok(vec![(100, 1999)]));
+        assert_eq!(parse_range_header("bytes=5000-6000,-0", 2000), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        // Syntax is checked before satisfiability, so these are 400s, not 416s.
+        assert_eq!(parse_range_header("bytes=5000-abc", 2000), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_range_header("bytes=0-99,5000-abc", 2000), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_range_header("bytes=5000-6000,-", 2000), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_range_header("bytes=abc", 0), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn range_reversed_or_out_of_range() {
+        assert_eq!(parse_range_header("bytes=10-5", 2000), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(parse_range_header("bytes=2000-", 2000), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert_eq!(parse_range_header("bytes=5000-6000", 2000), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        // An end past the file is left for the caller to clamp.
+        assert_eq!(parse_range_header("bytes=0-999999", 2000), Ok(vec![(0, 999999)]));
+    }
+
+    #[test]
+    fn range_on_empty_file() {
+        for header in ["bytes=0-", "bytes=0-0", "bytes=-1", "bytes=5-10"] {
+            assert_eq!(parse_range_header(header, 0), Err(StatusCode::RANGE_NOT_SATISFIABLE), "{header}");
+        }
+        assert!(clamp_ranges(vec![(0, 10)], 0).is_empty());
+    }
+
+    #[test]
+    fn open_range_on_empty_file_is_unsatisfiable() {
+        assert_eq!(parse_range_header("bytes=0-", 0), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert_eq!(parse_range_header("bytes=0-", 1), Ok(vec![(0, 0)]));
+    }
+
+    #[test]
+    fn seek_offset_stays_in_the_file() {
+        // 8 kbit/s is 1000 bytes per second.
+        assert_eq!(seek_offset(2.0, Some(8_000.0), 10_000), Some(2000));
+        assert_eq!(seek_offset(60.0, Some(8_000.0), 10_000), Some(9999));
+        assert_eq!(seek_offset(2.0, Some(8_000.0), 0), None);
+        assert_eq!(seek_offset(2.0, Some(8_000.0), 1), None);
+        assert_eq!(seek_offset(2.0, None, 10_000), None);
+        assert_eq!(seek_offset(-1.0, Some(8_000.0), 10_000), None);
+        assert_eq!(seek_offset(f64::NAN, Some(8_000.0), 10_000), None);
+    }
+
+    #[test]
+    fn range_bounds_at_u64_max() {
+        let max = u64::MAX;
+        assert_eq!(parse_range_header(&format!("bytes={max}-"), 2000), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert_eq!(parse_range_header(&format!("bytes={max}-{max}"), max), Err(StatusCode::RANGE_NOT_SATISFIABLE));
+        assert_eq!(parse_range_header(&format!("bytes=0-{max}"), 2000), Ok(vec![(0, max)]));
+        assert_eq!(parse_range_header(&format!("bytes=-{max}"), 2000), Ok(vec![(0, 1999)]));
+        assert_eq!(parse_range_header(&format!("bytes=0-{max}"), max), Ok(vec![(0, max)]));
+        // One past u64::MAX doesn't parse.
+        assert_eq!(parse_range_header("bytes=0-18446744073709551616", 2000), Err(StatusCode::BAD_REQUEST));
+
+        assert_eq!(clamp_ranges(vec![(0, max)], 2000), [(0, 1999)]);
+        assert_eq!(clamp_ranges(vec![(0, max)], max), [(0, max - 1)]);
+        assert_eq!(clamp_ranges(vec![(1500, max), (2500, max)], 2000), [(1500, 1999)]);
+    }
+
+    #[test]
+    fn oversized_range_end() {
+        assert!(!range_end_oversized(999, 1000));
+        assert!(!range_end_oversized(1000, 1000));
+        assert!(!range_end_oversized(2000, 1000));
+        assert!(range_end_oversized(2001, 1000));
+        assert!(range_end_oversized(999_999_999_999, 1000));
+        assert!(range_end_oversized(u64::MAX, u64::MAX / 2));
+        assert!(!range_end_oversized(u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn range_malformed() {
+        for header in ["items=0-1", "0-1", "bytes=", "bytes=-", "bytes=abc-1", "bytes=1-abc", "bytes=0-1-2", "bytes=1"] {
+            assert_eq!(parse_range_header(header, 2000), Err(StatusCode::BAD_REQUEST), "{header}");
+        }
+    }
+}
@@ -4,4 +4,7 @@ pub mod handlers;
 pub mod models;
 pub mod utils;
 pub mod download;
+pub mod storage;
 pub mod bridge;
+#[cfg(feature = "blocking")]
+pub mod blocking;
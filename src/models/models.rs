@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::SystemTime;
 use crate::discovery::models::NostrVideo;
+use crate::utils::utils::unix_now;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VideoDownload {
@@ -12,6 +14,13 @@ pub struct VideoDownload {
 
     /// Original URL of the video
     pub url: String,
+    /// Every URL this content hash has been published at, `url` first. Other
+    /// events referencing the same file add theirs here.
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// npubs of every author whose event referenced this file
+    #[serde(default)]
+    pub authors: Vec<String>,
 
     pub nostr: NostrVideo,
 
@@ -27,13 +36,22 @@ pub struct VideoDownload {
 
     /// Format (e.g., "H.264", "MPEG4", etc.), if known
     pub format: Option<String>,
+    /// Container MIME type: the imeta `m` field, replaced by what the
+    /// downloaded bytes turn out to be
+    #[serde(default)]
+    pub mime_type: Option<String>,
 
     /// Width/Height, if known
     pub width: Option<u32>,
     pub height: Option<u32>,
 
     pub downloaded_bytes: u64,
+    /// Bytes known to be flushed to disk; a consistent checkpoint for resuming.
+    pub synced_bytes: u64,
     pub content_length: Option<u64>,
+    /// Average bitrate, `content_length * 8 / length_seconds`, once both are known
+    #[serde(default)]
+    pub bitrate_bps: Option<f64>,
 
     // We'll store the current computed speed in bytes/second, updated every chunk or so.
     pub download_speed_bps: f64,
@@ -45,28 +63,330 @@ pub struct VideoDownload {
     pub last_speed_update_bytes: u64,
     pub thumbnail_path: Option<PathBuf>,
     pub last_played: Option<u64>,
+    /// Unix seconds when this video was first discovered
+    #[serde(default)]
+    pub discovered_at: u64,
+    /// Unix seconds when a relay last delivered this video
+    #[serde(default)]
+    pub last_seen: u64,
+    /// When the download finished; drives `max_file_age` eviction
+    #[serde(default)]
+    pub downloaded_at: Option<SystemTime>,
+    /// Deleted for being too far behind the current position; not downloaded
+    /// again unless it comes back up for watching
+    #[serde(default)]
+    pub evicted: bool,
 }
 
 impl VideoDownload {
     pub fn from_nostr_video(nostr: NostrVideo) -> Self {
+        let (width, height) = (nostr.width, nostr.height);
+        let (mime_type, length_seconds) = (nostr.mime_type.clone(), nostr.duration);
+        let now = unix_now();
         Self {
             id: nostr.id.clone(),
             score: 0.0,
             url: nostr.url.clone(),
+            urls: vec![nostr.url.clone()],
+            authors: nostr.user.npub.iter().cloned().collect(),
             nostr,
             local_path: None,
             downloading: false,
-            length_seconds: None,
+            // Announced by the event; replaced by the parsed `moov` value later.
+            length_seconds,
             format: None,
-            width: None,
-            height: None,
+            mime_type,
+            width,
+            height,
             downloaded_bytes: 0,
+            synced_bytes: 0,
             content_length: None,
+            bitrate_bps: None,
             download_speed_bps: 0.0,
             last_speed_update_instant: None,
             last_speed_update_bytes: 0,
             thumbnail_path: None,
             last_played: None,
+            discovered_at: now,
+            last_seen: now,
+            downloaded_at: None,
+            evicted: false,
+        }
+    }
+
+    /// Fold another event for the same content hash into this one: remember its
+    /// URLs, author and relays, keeping everything else (download state
+    /// included) as is.
+    pub fn merge_duplicate(&mut self, other: VideoDownload) {
+        self.last_seen = self.last_seen.max(other.last_seen);
+        for url in other.urls.into_iter().chain(other.nostr.fallbacks) {
+            if !self.urls.contains(&url) && !self.nostr.fallbacks.contains(&url) {
+                self.urls.push(url);
+            }
+        }
+        for author in other.authors {
+            if !self.authors.contains(&author) {
+                self.authors.push(author);
+            }
+        }
+        for relay in other.nostr.source_relays {
+            if !self.nostr.source_relays.contains(&relay) {
+                self.nostr.source_relays.push(relay);
+            }
+        }
+    }
+
+    /// Initial ranking from engagement: likes plus comments, which count
+    /// double. Counts that aren't plain numbers (or are missing) count as zero.
+    pub fn compute_score(&mut self) {
+        let count = |s: &str| s.trim().replace(',', "").parse::<f64>().unwrap_or(0.0).max(0.0);
+        self.score = count(&self.nostr.likes) + 2.0 * count(&self.nostr.comments);
+    }
+
+    /// Recompute `bitrate_bps` from `content_length` and `length_seconds`;
+    /// `None` unless both are known.
+    pub fn update_bitrate(&mut self) {
+        self.bitrate_bps = match (self.content_length, self.length_seconds) {
+            (Some(len), Some(secs)) if len > 0 && secs > 0.0 => Some(len as f64 * 8.0 / secs),
+            _ => None,
+        };
+    }
+
+    /// A file was started but isn't complete and nothing is writing to it:
+    /// a download interrupted by a restart or by running out of retries.
+    pub fn is_partial(&self) -> bool {
+        self.local_path.is_some()
+            && !self.downloading
+            && self.content_length.map_or(true, |len| self.downloaded_bytes < len)
+    }
+
+    /// Seconds of playback downloaded so far, assuming a constant bitrate:
+    /// `length_seconds * downloaded_bytes / content_length`. `None` unless
+    /// both are known.
+    pub fn downloaded_seconds(&self) -> Option<f64> {
+        match (self.length_seconds, self.content_length) {
+            (Some(secs), Some(len)) if len > 0 => {
+                Some(secs * (self.downloaded_bytes as f64 / len as f64).min(1.0))
+            }
+            _ => None,
         }
     }
-}
\ No newline at end of file
+
+    /// Content-Type to serve the file with: `mime_type`, else guessed from
+    /// the local file's extension, else `application/octet-stream`.
+    pub fn content_type(&self) -> String {
+        if let Some(mime) = self.mime_type.as_deref().filter(|m| m.contains('/')) {
+            return mime.to_string();
+        }
+        self.local_path
+            .as_ref()
+            .and_then(|p| p.extension())
+            .and_then(|ext| ext.to_str())
+            .and_then(mime_for_extension)
+            .unwrap_or("application/octet-stream")
+            .to_string()
+    }
+
+    /// URLs to try, in order: every published URL, then the imeta fallbacks.
+    pub fn download_urls(&self) -> Vec<String> {
+        let mut urls = self.urls.clone();
+        if urls.is_empty() {
+            urls.push(self.url.clone());
+        }
+        for url in &self.nostr.fallbacks {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+}
+
+/// A change pushed to `/events` and `/ws` listeners, see `AppState::events`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StateEvent {
+    /// A download's bytes, speed or running state changed.
+    Progress(ProgressEvent),
+    /// Videos seen for the first time.
+    VideosDiscovered { videos: Vec<VideoDownload> },
+    /// A playlist was created or switched to, or its items or position changed.
+    PlaylistChanged {
+        playlist: String,
+        len: usize,
+        position: Option<usize>,
+    },
+}
+
+/// Download progress of one video, as pushed to `/events` listeners.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressEvent {
+    pub id: String,
+    pub downloaded_bytes: u64,
+    /// Full size, if known
+    pub total_bytes: Option<u64>,
+    pub download_speed_bps: f64,
+    pub downloading: bool,
+}
+
+impl From<&VideoDownload> for ProgressEvent {
+    fn from(video: &VideoDownload) -> Self {
+        Self {
+            id: video.id.clone(),
+            downloaded_bytes: video.downloaded_bytes,
+            total_bytes: video.content_length,
+            download_speed_bps: video.download_speed_bps,
+            downloading: video.downloading,
+        }
+    }
+}
+
+/// MIME type for a video file extension, if it's one we store.
+pub fn mime_for_extension(extension: &str) -> Option<&'static str> {
+    match extension.to_ascii_lowercase().as_str() {
+        "mp4" | "m4v" => Some("video/mp4"),
+        "mov" => Some("video/quicktime"),
+        "webm" => Some("video/webm"),
+        "mkv" => Some("video/x-matroska"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+impl VideoDownload {
+    /// A bare video with the given id, for unit tests.
+    pub(crate) fn for_test(id: &str) -> Self {
+        use crate::discovery::models::UserData;
+        Self::from_nostr_video(NostrVideo {
+            id: id.to_string(),
+            event_id: id.to_string(),
+            user: UserData { npub: None, name: None, profile_picture: None },
+            title: String::new(),
+            song_name: String::new(),
+            likes: String::new(),
+            comments: String::new(),
+            url: format!("https://example.com/{id}.mp4"),
+            width: None,
+            height: None,
+            source_relays: Vec::new(),
+            service: None,
+            fallbacks: Vec::new(),
+            mime_type: None,
+            summary: None,
+            published_at: None,
+            duration: None,
+            hashtags: Vec::new(),
+            address: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_for(hash: &str, url: &str, author: &str, relay: &str) -> VideoDownload {
+        let mut video = VideoDownload::for_test(hash);
+        video.url = url.to_string();
+        video.urls = vec![url.to_string()];
+        video.authors = vec![author.to_string()];
+        video.nostr.url = url.to_string();
+        video.nostr.source_relays = vec![relay.to_string()];
+        video
+    }
+
+    #[test]
+    fn merge_keeps_every_url_author_and_relay() {
+        let mut first = event_for("h", "https://a.example/v.mp4", "npub1a", "wss://r1");
+        first.local_path = Some(PathBuf::from("h.mp4"));
+        first.downloaded_bytes = 500;
+        let mut second = event_for("h", "https://b.example/v.mp4", "npub1b", "wss://r2");
+        second.nostr.fallbacks = vec!["https://c.example/v.mp4".to_string()];
+        second.last_seen = first.last_seen + 10;
+
+        first.merge_duplicate(second.clone());
+        assert_eq!(first.urls, ["https://a.example/v.mp4", "https://b.example/v.mp4", "https://c.example/v.mp4"]);
+        assert_eq!(first.authors, ["npub1a", "npub1b"]);
+        assert_eq!(first.nostr.source_relays, ["wss://r1", "wss://r2"]);
+        assert_eq!(first.last_seen, second.last_seen);
+        // Download progress is the first entry's.
+        assert_eq!(first.local_path, Some(PathBuf::from("h.mp4")));
+        assert_eq!(first.downloaded_bytes, 500);
+
+        // Merging the same event again adds nothing.
+        first.merge_duplicate(second);
+        assert_eq!(first.urls.len(), 3);
+        assert_eq!(first.authors.len(), 2);
+        assert_eq!(first.nostr.source_relays.len(), 2);
+    }
+
+    #[test]
+    fn download_urls_then_fallbacks() {
+        let mut video = event_for("h", "https://a.example/v.mp4", "npub1a", "wss://r1");
+        video.urls.push("https://b.example/v.mp4".to_string());
+        video.nostr.fallbacks = vec!["https://b.example/v.mp4".to_string(), "https://c.example/v.mp4".to_string()];
+        assert_eq!(
+            video.download_urls(),
+            ["https://a.example/v.mp4", "https://b.example/v.mp4", "https://c.example/v.mp4"]
+        );
+
+        // Restored from a snapshot older than `urls`.
+        video.urls.clear();
+        assert_eq!(video.download_urls()[0], "https://a.example/v.mp4");
+    }
+
+    #[test]
+    fn partial_download_detection() {
+        let mut video = VideoDownload::for_test("a");
+        assert!(!video.is_partial());
+
+        video.local_path = Some(PathBuf::from("a.mp4"));
+        video.downloaded_bytes = 50;
+        video.content_length = Some(100);
+        assert!(video.is_partial());
+        // Still being written to.
+        video.downloading = true;
+        assert!(!video.is_partial());
+
+        video.downloading = false;
+        video.downloaded_bytes = 100;
+        assert!(!video.is_partial());
+        // Without a known length it can't be told complete.
+        video.content_length = None;
+        assert!(video.is_partial());
+    }
+
+    #[test]
+    fn downloaded_seconds_from_progress() {
+        let mut video = VideoDownload::for_test("a");
+        video.downloaded_bytes = 250;
+        assert_eq!(video.downloaded_seconds(), None);
+
+        video.length_seconds = Some(60.0);
+        assert_eq!(video.downloaded_seconds(), None);
+        video.content_length = Some(0);
+        assert_eq!(video.downloaded_seconds(), None);
+
+        video.content_length = Some(1000);
+        assert_eq!(video.downloaded_seconds(), Some(15.0));
+        // More bytes than announced caps at the full length.
+        video.downloaded_bytes = 2000;
+        assert_eq!(video.downloaded_seconds(), Some(60.0));
+    }
+
+    #[test]
+    fn bitrate_needs_size_and_length() {
+        let mut video = VideoDownload::for_test("a");
+        video.update_bitrate();
+        assert_eq!(video.bitrate_bps, None);
+
+        video.content_length = Some(1_000_000);
+        video.length_seconds = Some(8.0);
+        video.update_bitrate();
+        assert_eq!(video.bitrate_bps, Some(1_000_000.0));
+
+        video.length_seconds = Some(0.0);
+        video.update_bitrate();
+        assert_eq!(video.bitrate_bps, None);
+    }
+}
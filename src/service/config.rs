@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::service::state::{DEFAULT_TARGET_MINUTES_AHEAD, DEFAULT_TARGET_VIDEOS_AHEAD};
+
+/// Startup settings, read from a TOML file. Every field is optional; missing
+/// ones keep the values the server has always used.
+///
+/// ```toml
+/// relays = ["wss://relay.damus.io"]
+/// max_parallel_downloads = 4
+/// max_storage_bytes = 2147483648
+/// target_minutes_ahead = 30.0
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub relays: Vec<String>,
+    pub max_parallel_downloads: usize,
+    pub max_storage_bytes: u64,
+    /// Seconds of already-watched video kept behind the current one
+    pub max_behind_seconds: u64,
+    pub target_minutes_ahead: f64,
+    pub target_videos_ahead: usize,
+    /// See `AppState::set_download_dir`; `None` uses the temp dir
+    pub download_dir: Option<PathBuf>,
+    /// nsec or hex, for relays that require NIP-42 auth
+    pub secret_key: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            relays: vec![
+                "wss://relay.damus.io".to_string(),
+                "wss://relay.snort.social".to_string(),
+            ],
+            max_parallel_downloads: 10,
+            max_storage_bytes: 1024 * 1024 * 1024,
+            max_behind_seconds: 60,
+            target_minutes_ahead: DEFAULT_TARGET_MINUTES_AHEAD,
+            target_videos_ahead: DEFAULT_TARGET_VIDEOS_AHEAD,
+            download_dir: None,
+            secret_key: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parsing config {}", path.display()))
+    }
+
+    /// Load the file named by `--config <path>` (or `--config=<path>`), else by
+    /// `TOKSTR_CONFIG`; the defaults if neither is set.
+    pub fn from_args_or_env() -> Result<Self> {
+        match config_path() {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("TOKSTR_CONFIG").map(PathBuf::from)
+}
@@ -2,36 +2,41 @@ use std::net::{SocketAddr, TcpListener};
 use std::sync::Arc;
 use anyhow::Result;
 
-use axum::{Router};
-use axum::routing::{get, post};
-use nostr_sdk::Client;
+use axum::{middleware, Extension, Router};
+use axum::routing::{delete, get, post};
+use nostr_sdk::{Client, Keys};
 use tokio::sync::Mutex;
 use crate::discovery::fetchers::ContentDiscovery;
+use crate::discovery::filters::DiscoveryFilter;
 use crate::download::manager::DownloadManager;
+use crate::service::config::Config;
+use crate::service::request_log::log_requests;
 use crate::service::state::AppState;
 use tracing::{info};
-use crate::handlers::handlers::{dashboard, get_status, get_thumbnail, set_index, stream_video};
+use crate::handlers::handlers::{add_relay, create_playlist, dashboard, delete_video, export_playlist, get_config, get_preview, get_status, get_thumbnail, list_playlists, list_relays, pause_downloads, progress_events, refresh, remove_relay, reparse, restart_download, resume_downloads, set_index, stop_all_downloads, stop_download, stream_video, switch_playlist, update_config, video_info, ws_updates};
 use crate::utils::utils::find_available_port;
 
-pub async fn start_axum_server(max_parallel_downloads: usize, max_storage_bytes: u64) -> Result<(String, Arc<AppState>)> {
+/// The single entry point for running the server, used by the binary and the
+/// FFI bridge alike: connects to `config.relays` (answering NIP-42 AUTH with
+/// `config.secret_key`), starts the download manager, and serves the API on
+/// a free local port in the background.
+///
+/// Returns the bound address, the shared state and the download manager.
+pub async fn start_axum_server(
+    config: Config,
+) -> Result<(String, Arc<AppState>, Arc<DownloadManager>)> {
     let listener = find_available_port()?;
     let local_addr = listener.local_addr()?;
     info!("Starting server at {}", local_addr);
 
-    let relays = vec![
-        "wss://relay.damus.io".to_string(),
-        "wss://relay.snort.social".to_string(),
-    ];
+    let keys = config.secret_key.as_deref().map(Keys::parse).transpose()?;
     let client = Arc::new(Client::default());
-    let content_discovery = ContentDiscovery::new(relays, client).await?;
+    let content_discovery =
+        ContentDiscovery::new(config.relays.clone(), client, keys, DiscoveryFilter::default()).await?;
 
     // Create the global service state
-    let state = AppState::new(
-        content_discovery,
-        max_parallel_downloads,
-        60,
-        max_storage_bytes,
-    );
+    let state = AppState::from_config(content_discovery, &config);
+    state.ensure_download_dir().await?;
 
     // Wrap in an Arc
     let shared_state = Arc::new(state);
@@ -39,15 +44,7 @@ pub async fn start_axum_server(max_parallel_downloads: usize, max_storage_bytes:
 
     tokio::spawn(manager.clone().run());
 
-
-    // Build the router
-    let app = Router::new()
-        .route("/dashboard", get(dashboard))
-        .route("/video.mp4", get(stream_video))
-        .route("/status", get(get_status))
-        .route("/set_index", post(set_index))
-        .route("/thumbnail", get(get_thumbnail))
-        .with_state(shared_state.clone()); // shared_state is Arc<AppState>
+    let app = router(shared_state.clone(), manager.clone());
 
     // Spawn Axum server in the background
     tokio::spawn(async move {
@@ -57,6 +54,36 @@ pub async fn start_axum_server(max_parallel_downloads: usize, max_storage_bytes:
             .unwrap();
     });
 
-    // Return (the address, the state)
-    Ok((local_addr.to_string(), shared_state))
+    // Return (the address, the state, the manager)
+    Ok((local_addr.to_string(), shared_state, manager))
+}
+
+/// Every route of the HTTP API.
+pub fn router(state: Arc<AppState>, manager: Arc<DownloadManager>) -> Router {
+    Router::new()
+        .route("/dashboard", get(dashboard))
+        .route("/video.mp4", get(stream_video))
+        .route("/video", delete(delete_video))
+        .route("/video/info", get(video_info))
+        .route("/status", get(get_status))
+        .route("/events", get(progress_events))
+        .route("/ws", get(ws_updates))
+        .route("/set_index", post(set_index))
+        .route("/thumbnail", get(get_thumbnail))
+        .route("/preview", get(get_preview))
+        .route("/playlists", get(list_playlists).post(create_playlist))
+        .route("/playlists/switch", post(switch_playlist))
+        .route("/reparse", post(reparse))
+        .route("/refresh", post(refresh))
+        .route("/relays", get(list_relays).post(add_relay).delete(remove_relay))
+        .route("/stop_download", post(stop_download))
+        .route("/restart_download", post(restart_download))
+        .route("/pause", post(pause_downloads))
+        .route("/resume", post(resume_downloads))
+        .route("/downloads/stop_all", post(stop_all_downloads))
+        .route("/config", get(get_config).put(update_config))
+        .route("/export", get(export_playlist))
+        .layer(Extension(manager))
+        .layer(middleware::from_fn(log_requests))
+        .with_state(state) // state is Arc<AppState>
 }
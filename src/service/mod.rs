@@ -1,3 +1,6 @@
 pub mod state;
+pub mod config;
 pub mod main_axum;
-pub mod playlist;
\ No newline at end of file
+pub mod playlist;
+pub mod snapshot;
+pub mod request_log;
\ No newline at end of file
@@ -1,4 +1,22 @@
+use std::path::Path;
+use anyhow::Result;
+
+use crate::discovery::models::UserData;
 use crate::models::models::VideoDownload;
+use crate::service::snapshot::PlaylistSnapshot;
+use crate::storage::store::VideoStore;
+
+/// What `Playlist::next` and `Playlist::prev` do at either end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RepeatMode {
+    /// Stop: `next` at the last item and `prev` at the first return `None`.
+    #[default]
+    Off,
+    /// Wrap around to the other end.
+    All,
+    /// `next` keeps returning the current item.
+    One,
+}
 
 #[derive(Debug)]
 pub struct Playlist {
@@ -8,17 +26,108 @@ pub struct Playlist {
     last_sent_position: Option<usize>,
     items: Vec<VideoDownload>,
     items_by_id: std::collections::HashMap<String, usize>,
+    /// Whether `next`/`prev` follow `shuffle_order` instead of item order.
+    shuffle: bool,
+    /// Item indices in playback order while shuffling; empty otherwise.
+    shuffle_order: Vec<usize>,
+    shuffle_seed: u64,
+    repeat: RepeatMode,
 }
 
 impl Playlist {
     pub fn new() -> Self {
+        let id = uuid::Uuid::new_v4();
         Self {
-            id: uuid::Uuid::new_v4().to_string(),
+            id: id.to_string(),
             current_position: None,
             last_sent_position: None,
             items: Vec::new(),
             items_by_id: std::collections::HashMap::new(),
+            shuffle: false,
+            shuffle_order: Vec::new(),
+            shuffle_seed: id.as_u64_pair().0,
+            repeat: RepeatMode::Off,
+        }
+    }
+
+    /// Rebuild a playlist from persisted parts. `current_position` is clamped
+    /// to the restored items.
+    pub fn restore(id: String, items: Vec<VideoDownload>, current_position: Option<usize>) -> Self {
+        let items_by_id = items
+            .iter()
+            .enumerate()
+            .map(|(idx, v)| (v.id.clone(), idx))
+            .collect();
+        let current_position = match current_position {
+            Some(_) if items.is_empty() => None,
+            Some(pos) => Some(pos.min(items.len() - 1)),
+            None => None,
+        };
+        let shuffle_seed = uuid::Uuid::parse_str(&id).map_or(0, |u| u.as_u64_pair().0);
+        Self {
+            id,
+            current_position,
+            last_sent_position: None,
+            items,
+            items_by_id,
+            shuffle: false,
+            shuffle_order: Vec::new(),
+            shuffle_seed,
+            repeat: RepeatMode::Off,
+        }
+    }
+
+    /// The persisted form: id, items and current position.
+    pub fn snapshot(&self) -> PlaylistSnapshot {
+        PlaylistSnapshot {
+            id: self.id.clone(),
+            current_position: self.current_position,
+            items: self.items.clone(),
+        }
+    }
+
+    /// Write the playlist as JSON, see `PlaylistSnapshot::save`.
+    pub async fn save_to(&self, path: &Path) -> Result<()> {
+        self.snapshot().save(path).await
+    }
+
+    /// Read a playlist written by `save_to`, returning `None` if there is none
+    /// yet. Items whose downloaded file is no longer in `store` are dropped, and
+    /// the position moves to where the current item (or the one after it) is now.
+    pub async fn load_from(path: &Path, store: &dyn VideoStore) -> Result<Option<Self>> {
+        let snapshot: PlaylistSnapshot = match tokio::fs::read(path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut items = Vec::with_capacity(snapshot.items.len());
+        let mut current_position = None;
+        for (idx, mut video) in snapshot.items.into_iter().enumerate() {
+            if let Some(local_path) = &video.local_path {
+                if !store.exists(local_path).await {
+                    continue;
+                }
+            }
+            if current_position.is_none() && snapshot.current_position.is_some_and(|p| idx >= p) {
+                current_position = Some(items.len());
+            }
+            // Nothing is downloading right after a restart.
+            video.downloading = false;
+            video.download_speed_bps = 0.0;
+            items.push(video);
+        }
+        if snapshot.current_position.is_some() && current_position.is_none() {
+            current_position = items.len().checked_sub(1);
         }
+        Ok(Some(Self::restore(snapshot.id, items, current_position)))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
     }
 
     pub fn add(&mut self, video: VideoDownload) {
@@ -28,6 +137,43 @@ impl Playlist {
         let idx = self.items.len();
         self.items.push(video.clone());
         self.items_by_id.insert(video.id, idx);
+        if self.shuffle {
+            self.shuffle_order.push(idx);
+        }
+    }
+
+    /// Take `id` out of the playlist. Positions after it shift down by one, so
+    /// the current item stays current (or, if it was removed, the next one
+    /// takes its place). Returns whether it was there.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let Some(idx) = self.items_by_id.remove(id) else {
+            return false;
+        };
+        self.items.remove(idx);
+        for v in &self.items[idx..] {
+            if let Some(i) = self.items_by_id.get_mut(&v.id) {
+                *i -= 1;
+            }
+        }
+        self.shuffle_order.retain(|&i| i != idx);
+        for i in self.shuffle_order.iter_mut().filter(|i| **i > idx) {
+            *i -= 1;
+        }
+        self.current_position = match self.current_position {
+            _ if self.items.is_empty() => None,
+            Some(pos) if pos > idx => Some(pos - 1),
+            Some(pos) => Some(pos.min(self.items.len() - 1)),
+            None => None,
+        };
+        self.last_sent_position = match self.last_sent_position {
+            Some(pos) if pos >= idx => pos.checked_sub(1),
+            other => other,
+        };
+        true
+    }
+
+    pub fn get(&self, index: usize) -> Option<&VideoDownload> {
+        self.items.get(index)
     }
 
     pub fn current(&self) -> Option<&VideoDownload> {
@@ -37,37 +183,353 @@ impl Playlist {
         None
     }
 
+    /// Advance to the next item. At the end this wraps to the start under
+    /// `RepeatMode::All`; under `RepeatMode::One` the current item repeats.
     pub fn next(&mut self) -> Option<&VideoDownload> {
-        if let Some(pos) = self.current_position {
-            if pos + 1 < self.items.len() {
-                self.current_position = Some(pos + 1);
-                return Some(&self.items[pos + 1]);
-            }
+        if self.repeat == RepeatMode::One {
+            return self.current();
         }
-        None
+        self.step(1)
     }
 
+    /// Go back to the previous item, wrapping to the end under
+    /// `RepeatMode::All`.
     pub fn prev(&mut self) -> Option<&VideoDownload> {
-        if let Some(pos) = self.current_position {
-            if pos > 0 {
-                self.current_position = Some(pos - 1);
-                return Some(&self.items[pos - 1]);
+        self.step(-1)
+    }
+
+    /// Move `delta` steps from the current item, along `shuffle_order` when
+    /// shuffling and item order otherwise.
+    fn step(&mut self, delta: isize) -> Option<&VideoDownload> {
+        let pos = self.current_position?;
+        let (place, len) = if self.shuffle {
+            (self.shuffle_order.iter().position(|&i| i == pos)?, self.shuffle_order.len())
+        } else {
+            (pos, self.items.len())
+        };
+        let place = match place.checked_add_signed(delta).filter(|&p| p < len) {
+            Some(p) => p,
+            None if self.repeat == RepeatMode::All && len > 0 => {
+                (place as isize + delta).rem_euclid(len as isize) as usize
             }
+            None => return None,
+        };
+        let target = if self.shuffle { self.shuffle_order[place] } else { place };
+        self.current_position = Some(target);
+        self.items.get(target)
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: RepeatMode) {
+        self.repeat = repeat;
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    /// Turn shuffled playback on or off. Turning it on draws a new order
+    /// starting at the current item, followed by the others in a random order
+    /// derived from the shuffle seed. Turning it off continues sequentially
+    /// from whichever item is current.
+    pub fn set_shuffle(&mut self, shuffle: bool) {
+        self.shuffle = shuffle;
+        self.shuffle_order.clear();
+        if !shuffle {
+            return;
+        }
+        let mut rest: Vec<usize> = (0..self.items.len())
+            .filter(|&i| Some(i) != self.current_position)
+            .collect();
+        shuffle_in_place(&mut rest, self.shuffle_seed);
+        self.shuffle_order.extend(self.current_position.filter(|&p| p < self.items.len()));
+        self.shuffle_order.extend(rest);
+    }
+
+    /// Seed for the next shuffled order; the same seed and items always give
+    /// the same order. Takes effect the next time shuffle is turned on.
+    pub fn set_shuffle_seed(&mut self, seed: u64) {
+        self.shuffle_seed = seed;
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        self.current_position
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        self.current_position = Some(position);
+    }
+
+    /// Ids of up to `count` items played after `index`: along `shuffle_order`
+    /// when shuffling, in playlist order otherwise.
+    pub fn ids_after(&self, index: usize, count: usize) -> Vec<String> {
+        if self.shuffle {
+            let Some(place) = self.shuffle_order.iter().position(|&i| i == index) else {
+                return Vec::new();
+            };
+            return self.shuffle_order[place + 1..]
+                .iter()
+                .take(count)
+                .filter_map(|&i| self.items.get(i))
+                .map(|v| v.id.clone())
+                .collect();
+        }
+        self.items
+            .iter()
+            .skip(index.saturating_add(1))
+            .take(count)
+            .map(|v| v.id.clone())
+            .collect()
+    }
+
+    /// Refresh the author metadata on every item by that author.
+    pub fn update_user(&mut self, user: &UserData) {
+        for item in self.items.iter_mut().filter(|v| v.nostr.user.npub == user.npub) {
+            item.nostr.user = user.clone();
         }
-        None
     }
 
     pub fn as_vec(&self) -> Vec<VideoDownload> {
         self.items.clone()
     }
 
+    /// Items added since the previous call (every item on the first call),
+    /// marking them as sent. Empty when nothing is new.
     pub fn new_content(&mut self) -> Vec<VideoDownload> {
-        if let Some(pos) = self.last_sent_position {
-            if pos + 1 < self.items.len() {
-                return self.items[pos + 1..].to_vec();
-            }
-            self.last_sent_position = Some(pos);
+        let start = self.last_sent_position.map_or(0, |pos| pos + 1);
+        if start >= self.items.len() {
+            return Vec::new();
         }
-        self.items.clone()
+        self.last_sent_position = Some(self.items.len() - 1);
+        self.items[start..].to_vec()
+    }
+}
+
+/// Fisher-Yates shuffle driven by a splitmix64 generator, so a seed always
+/// produces the same permutation.
+fn shuffle_in_place(values: &mut [usize], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+    for i in (1..values.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        values.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(ids: &[&str]) -> Playlist {
+        let mut playlist = Playlist::new();
+        for id in ids {
+            playlist.add(VideoDownload::for_test(id));
+        }
+        playlist
+    }
+
+    fn current_id(playlist: &Playlist) -> Option<&str> {
+        playlist.current().map(|v| v.id.as_str())
+    }
+
+    fn shuffled(seed: u64) -> Playlist {
+        let mut playlist = playlist(&["a", "b", "c", "d", "e"]);
+        playlist.set_position(0);
+        playlist.set_shuffle_seed(seed);
+        playlist.set_shuffle(true);
+        playlist
+    }
+
+    #[test]
+    fn shuffle_order_is_fixed_by_seed() {
+        let mut playlist = shuffled(42);
+        assert_eq!(playlist.ids_after(0, 10), ["d", "b", "e", "c"]);
+        let played: Vec<String> = std::iter::from_fn(|| playlist.next().map(|v| v.id.clone())).collect();
+        assert_eq!(played, ["d", "b", "e", "c"]);
+
+        assert_eq!(shuffled(42).shuffle_order, shuffled(42).shuffle_order);
+        assert_ne!(shuffled(42).shuffle_order, shuffled(7).shuffle_order);
+    }
+
+    #[test]
+    fn ids_after_follows_shuffle_order() {
+        let mut playlist = shuffled(42);
+        assert_eq!(playlist.ids_after(0, 2), ["d", "b"]);
+        assert_eq!(playlist.ids_after(3, 2), ["b", "e"]);
+        assert!(playlist.ids_after(2, 2).is_empty());
+
+        playlist.set_shuffle(false);
+        assert_eq!(playlist.ids_after(0, 2), ["b", "c"]);
+    }
+
+    #[test]
+    fn add_appends_to_shuffle_order() {
+        let mut playlist = shuffled(42);
+        playlist.add(VideoDownload::for_test("f"));
+        assert_eq!(playlist.shuffle_order.last(), Some(&5));
+        assert_eq!(playlist.ids_after(0, 10), ["d", "b", "e", "c", "f"]);
+    }
+
+    #[test]
+    fn shuffle_off_continues_from_current() {
+        let mut playlist = shuffled(42);
+        playlist.next();
+        playlist.next();
+        assert_eq!(current_id(&playlist), Some("b"));
+
+        playlist.set_shuffle(false);
+        assert!(playlist.shuffle_order.is_empty());
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("c"));
+    }
+
+    #[test]
+    fn remove_keeps_indices_consistent() {
+        let mut playlist = shuffled(42);
+        assert!(playlist.remove("b"));
+        assert!(!playlist.remove("b"));
+
+        for (idx, video) in playlist.items.iter().enumerate() {
+            assert_eq!(playlist.items_by_id.get(&video.id), Some(&idx));
+        }
+        assert_eq!(playlist.items_by_id.len(), playlist.len());
+        let mut order = playlist.shuffle_order.clone();
+        order.sort_unstable();
+        assert_eq!(order, (0..playlist.len()).collect::<Vec<_>>());
+        assert_eq!(playlist.ids_after(0, 10), ["d", "e", "c"]);
+    }
+
+    fn at(ids: &[&str], position: usize, repeat: RepeatMode) -> Playlist {
+        let mut playlist = playlist(ids);
+        playlist.set_position(position);
+        playlist.set_repeat(repeat);
+        playlist
+    }
+
+    #[test]
+    fn repeat_off_stops_at_both_ends() {
+        let mut playlist = at(&["a", "b", "c"], 2, RepeatMode::Off);
+        assert!(playlist.next().is_none());
+        assert_eq!(current_id(&playlist), Some("c"));
+
+        let mut playlist = at(&["a", "b", "c"], 0, RepeatMode::Off);
+        assert!(playlist.prev().is_none());
+        assert_eq!(current_id(&playlist), Some("a"));
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("b"));
+    }
+
+    #[test]
+    fn repeat_all_wraps_at_both_ends() {
+        let mut playlist = at(&["a", "b", "c"], 2, RepeatMode::All);
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("a"));
+        assert_eq!(playlist.prev().map(|v| v.id.as_str()), Some("c"));
+
+        // The same along the shuffle order.
+        let mut playlist = shuffled(42);
+        playlist.set_repeat(RepeatMode::All);
+        assert_eq!(playlist.prev().map(|v| v.id.as_str()), Some("c"));
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("a"));
+    }
+
+    #[test]
+    fn repeat_one_keeps_current() {
+        let mut playlist = at(&["a", "b", "c"], 2, RepeatMode::One);
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("c"));
+        assert_eq!(playlist.next().map(|v| v.id.as_str()), Some("c"));
+        // Going back still moves.
+        assert_eq!(playlist.prev().map(|v| v.id.as_str()), Some("b"));
+
+        let mut playlist = at(&["a", "b", "c"], 0, RepeatMode::One);
+        assert!(playlist.prev().is_none());
+    }
+
+    #[test]
+    fn repeat_on_empty_playlist() {
+        for repeat in [RepeatMode::Off, RepeatMode::All, RepeatMode::One] {
+            let mut playlist = Playlist::new();
+            playlist.set_repeat(repeat);
+            assert!(playlist.next().is_none(), "{repeat:?}");
+            assert!(playlist.prev().is_none(), "{repeat:?}");
+        }
+    }
+
+    fn new_ids(playlist: &mut Playlist) -> Vec<String> {
+        playlist.new_content().into_iter().map(|v| v.id).collect()
+    }
+
+    #[test]
+    fn new_content_returns_only_new_items() {
+        let mut playlist = Playlist::new();
+        assert!(new_ids(&mut playlist).is_empty());
+
+        playlist.add(VideoDownload::for_test("a"));
+        playlist.add(VideoDownload::for_test("b"));
+        assert_eq!(new_ids(&mut playlist), ["a", "b"]);
+        assert!(new_ids(&mut playlist).is_empty());
+
+        playlist.add(VideoDownload::for_test("c"));
+        assert_eq!(new_ids(&mut playlist), ["c"]);
+        assert!(new_ids(&mut playlist).is_empty());
+    }
+
+    #[test]
+    fn new_content_after_removing_a_sent_item() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        assert_eq!(new_ids(&mut playlist), ["a", "b", "c"]);
+        playlist.remove("a");
+        playlist.add(VideoDownload::for_test("d"));
+        assert_eq!(new_ids(&mut playlist), ["d"]);
+
+        // The last one sent.
+        playlist.remove("d");
+        playlist.add(VideoDownload::for_test("e"));
+        assert_eq!(new_ids(&mut playlist), ["e"]);
+    }
+
+    #[test]
+    fn new_content_after_removing_an_unsent_item() {
+        let mut playlist = playlist(&["a", "b"]);
+        assert_eq!(new_ids(&mut playlist), ["a", "b"]);
+        playlist.add(VideoDownload::for_test("c"));
+        playlist.add(VideoDownload::for_test("d"));
+        playlist.remove("c");
+        assert_eq!(new_ids(&mut playlist), ["d"]);
+    }
+
+    #[tokio::test]
+    async fn load_drops_items_missing_from_the_store() {
+        use crate::storage::store::MemoryStore;
+        use tokio::io::AsyncWriteExt;
+
+        let store = MemoryStore::new();
+        let mut playlist = Playlist::new();
+        for id in ["a", "b", "c"] {
+            let mut video = VideoDownload::for_test(id);
+            video.local_path = Some(format!("{id}.mp4").into());
+            playlist.add(video);
+        }
+        playlist.set_position(1);
+        for key in ["a.mp4", "c.mp4"] {
+            store.put(Path::new(key)).await.unwrap().write_all(b"video").await.unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("tokstr_playlist_test_{}.json", std::process::id()));
+        playlist.save_to(&path).await.unwrap();
+        let loaded = Playlist::load_from(&path, &store).await;
+        let _ = tokio::fs::remove_file(&path).await;
+
+        // "b" is gone from the store, so the position moves on to "c".
+        let loaded = loaded.unwrap().unwrap();
+        let ids: Vec<_> = loaded.as_vec().into_iter().map(|v| v.id).collect();
+        assert_eq!(ids, ["a", "c"]);
+        assert_eq!(current_id(&loaded), Some("c"));
     }
 }
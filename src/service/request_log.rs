@@ -0,0 +1,35 @@
+use std::time::Instant;
+
+use axum::extract::Request;
+use axum::http::header::RANGE;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::debug;
+
+/// Log method, path, status and latency of every request at debug level.
+///
+/// Logged once the handler has produced the response headers, so for
+/// `/video.mp4` the latency covers opening the file but not streaming the body.
+/// The requested byte range is included when present.
+pub async fn log_requests(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let range = request
+        .headers()
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+
+    let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match range {
+        Some(range) => debug!(
+            %method, %path, range, status = response.status().as_u16(), latency_ms,
+            "request"
+        ),
+        None => debug!(%method, %path, status = response.status().as_u16(), latency_ms, "request"),
+    }
+    response
+}
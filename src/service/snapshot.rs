@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::path::Path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::models::models::VideoDownload;
+use crate::service::playlist::Playlist;
+use crate::service::state::AppState;
+
+/// On-disk form of a single playlist.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistSnapshot {
+    pub id: String,
+    pub current_position: Option<usize>,
+    pub items: Vec<VideoDownload>,
+}
+
+impl PlaylistSnapshot {
+    /// Write as JSON via temp file + rename, like `StateSnapshot::save`.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self)?;
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+/// Everything needed to put the session back the way it was after a restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub current_index: usize,
+    pub active_playlist: String,
+    pub playlists: HashMap<String, PlaylistSnapshot>,
+    pub discovered_videos: Vec<VideoDownload>,
+    #[serde(default)]
+    pub favorites: Vec<String>,
+}
+
+impl StateSnapshot {
+    /// Capture the current state.
+    pub async fn capture(state: &AppState) -> Self {
+        let discovered_videos = state.discovered_videos.read().await.values().cloned().collect();
+        let current_index = *state.current_index.lock().await;
+        let active_playlist = state.active_playlist.lock().await.clone();
+        let favorites = state.favorites.lock().await.iter().cloned().collect();
+        let playlists = state
+            .playlists
+            .lock()
+            .await
+            .iter()
+            .map(|(name, playlist)| (name.clone(), playlist.snapshot()))
+            .collect();
+
+        Self {
+            current_index,
+            active_playlist,
+            playlists,
+            discovered_videos,
+            favorites,
+        }
+    }
+
+    /// Write the snapshot as JSON. Goes through a temp file + rename so a crash
+    /// mid-write never leaves a truncated snapshot behind.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self)?;
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    /// Read a snapshot, returning `None` if there is none yet.
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Put the snapshot back into `state`. Positions are clamped to the
+    /// restored playlist lengths.
+    pub async fn restore(self, state: &AppState) {
+        let mut used_storage = 0u64;
+        {
+            let mut discovered = state.discovered_videos.write().await;
+            for mut video in self.discovered_videos {
+                // Nothing is downloading right after a restart.
+                video.downloading = false;
+                video.download_speed_bps = 0.0;
+                let exists = match &video.local_path {
+                    Some(path) => state.store.exists(path).await,
+                    None => false,
+                };
+                if exists {
+                    used_storage += video.downloaded_bytes;
+                } else {
+                    video.local_path = None;
+                    video.downloaded_bytes = 0;
+                    video.synced_bytes = 0;
+                    video.downloaded_at = None;
+                }
+                discovered.insert(video.id.clone(), video);
+            }
+        }
+        *state.current_storage_bytes.lock().await = used_storage;
+        state.favorites.lock().await.extend(self.favorites);
+
+        let mut active_len = None;
+        {
+            let mut playlists = state.playlists.lock().await;
+            for (name, snapshot) in self.playlists {
+                let playlist =
+                    Playlist::restore(snapshot.id, snapshot.items, snapshot.current_position);
+                if name == self.active_playlist {
+                    active_len = Some(playlist.len());
+                }
+                playlists.insert(name, playlist);
+            }
+        }
+
+        if let Some(len) = active_len {
+            *state.active_playlist.lock().await = self.active_playlist;
+            let index = if len == 0 { 0 } else { self.current_index.min(len - 1) };
+            *state.current_index.lock().await = index;
+            info!("Resumed playback position {} of {}", index, len);
+        } else if !self.active_playlist.is_empty() {
+            warn!("Snapshot's active playlist {:?} missing; keeping default", self.active_playlist);
+        }
+    }
+}
+
+/// One entry of the persisted download queue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedVideo {
+    pub id: String,
+    pub url: String,
+}
+
+/// The ordered download queue, kept in its own small file so a restart can
+/// resume downloading before discovery and sorting have run again.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub videos: Vec<QueuedVideo>,
+}
+
+impl QueueSnapshot {
+    pub fn capture(queue: &[VideoDownload]) -> Self {
+        Self {
+            videos: queue
+                .iter()
+                .map(|v| QueuedVideo { id: v.id.clone(), url: v.url.clone() })
+                .collect(),
+        }
+    }
+
+    /// Write as JSON via temp file + rename, like `StateSnapshot::save`.
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_vec(self)?;
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+
+    /// Read the queue file, returning `None` if there is none yet.
+    pub async fn load(path: &Path) -> Result<Option<Self>> {
+        match tokio::fs::read(path).await {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -1,28 +1,154 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use bytes::Bytes;
+use tokio::sync::{broadcast, MappedMutexGuard, Mutex, MutexGuard, Notify, RwLock};
 use crate::discovery::fetchers::ContentDiscovery;
-use crate::models::models::VideoDownload;
+use crate::download::rate_limit::RateLimiter;
+use crate::models::models::{ProgressEvent, StateEvent, VideoDownload};
+use crate::service::config::Config;
 use crate::service::playlist::Playlist;
+use crate::service::snapshot::StateSnapshot;
+use crate::storage::store::{LocalFsStore, VideoStore};
+use crate::storage::thumbnails::ThumbnailCache;
+use crate::utils::utils::ThumbFormat;
+use tracing::{info, warn};
+
+/// Name of the playlist discovery feeds into unless told otherwise.
+pub const DEFAULT_PLAYLIST: &str = "default";
+/// Defaults for the download sort's first phase, see `sort_videos_for_download`.
+pub const DEFAULT_TARGET_MINUTES_AHEAD: f64 = 60.0;
+pub const DEFAULT_TARGET_VIDEOS_AHEAD: usize = 15;
+/// Size cap of the thumbnail cache, separate from the video storage budget.
+const DEFAULT_MAX_THUMBNAIL_BYTES: u64 = 32 * 1024 * 1024;
+/// Events buffered per `/events` or `/ws` listener; a listener further behind
+/// skips ahead rather than holding up the sender.
+const STATE_EVENT_CAPACITY: usize = 256;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     /// List of videos in watch order
     pub content_discovery: Arc<ContentDiscovery>,
-    pub discovered_videos: Arc<Mutex<HashMap<String, VideoDownload>>>,
+    pub discovered_videos: Arc<RwLock<HashMap<String, VideoDownload>>>,
     /// The user's current watch index
     pub current_index: Arc<Mutex<usize>>,
-    pub playlist: Arc<Mutex<Playlist>>,
+    /// Named playlists ("default", "following", "trending", ...)
+    pub playlists: Arc<Mutex<HashMap<String, Playlist>>>,
+    /// Key into `playlists` of the playlist the user is currently watching
+    pub active_playlist: Arc<Mutex<String>>,
+    /// Video ids the user pinned: downloaded first and never evicted
+    pub favorites: Arc<Mutex<HashSet<String>>>,
+
+    /// Concurrency settings (adjustable at runtime through `/config`)
+    pub max_parallel_downloads: Arc<AtomicUsize>,
+    pub max_behind_seconds: Arc<AtomicU64>,
+    pub target_minutes_ahead: Arc<Mutex<f64>>,
+    pub target_videos_ahead: Arc<AtomicUsize>,
+    /// Always download the next this-many videos after `current_index`, in
+    /// playlist order, before anything the two-phase sort picks
+    pub prefetch_next: Arc<AtomicUsize>,
+    /// At most this many transfers from one host at once, on top of
+    /// `max_parallel_downloads`, so a single Blossom server isn't rate-limited
+    pub max_downloads_per_host: usize,
+    /// On launch, start this many known videos (favorites, then highest
+    /// scored) before the first discovery pass
+    pub warm_start_count: usize,
+
+    /// Cap on `discovered_videos`; least-recently-seen entries without a local
+    /// file are pruned beyond this
+    pub max_discovered: usize,
 
-    /// Concurrency settings
-    pub max_parallel_downloads: usize,
-    pub max_behind_seconds: u64,
-    pub target_minutes_ahead: f64,
-    pub target_videos_ahead: usize,
+    /// Resolution filter applied to newly discovered videos
+    pub min_width: u32,
+    pub min_height: u32,
+    /// Keep videos whose imeta carries no `dim`
+    pub allow_unknown_resolution: bool,
+
+    /// Length filter applied when building the download queue
+    pub min_length_seconds: Option<f64>,
+    pub max_length_seconds: Option<f64>,
+    /// Queue videos whose length isn't known yet
+    pub allow_unknown_length: bool,
+
+    /// On a metered connection: download only the lowest-bitrate variant of
+    /// each event
+    pub metered: bool,
+    /// Queue newest-published videos first (NIP-71 `published_at`) instead of
+    /// the size/score ordering
+    pub sort_by_published: bool,
+    /// Average bitrate (bits/s) assumed when a video's size is unknown (no
+    /// Content-Length) but its duration is, to estimate its size for sorting
+    pub assumed_bitrate_bps: u64,
 
     /// Storage
-    pub max_storage_bytes: u64,
+    /// Where downloaded videos are written; see `set_download_dir`
+    pub download_dir: PathBuf,
+    pub max_storage_bytes: Arc<AtomicU64>,
+    /// Don't start a download unless the disk would keep at least this much
+    /// free after it, whatever `max_storage_bytes` allows.
+    pub min_free_disk_bytes: u64,
     pub current_storage_bytes: Arc<Mutex<u64>>,
+    /// Backend holding the downloaded video bytes
+    pub store: Arc<dyn VideoStore>,
+    /// Wakes the download manager loop early (user scrolled, new content, ...)
+    pub manager_wake: Arc<Notify>,
+    /// Extract a first-frame thumbnail once a download's metadata is known
+    /// (no-op when built without the `ffmpeg` feature)
+    pub generate_thumbnails: bool,
+    /// Encoding for generated thumbnails
+    pub thumbnail_format: ThumbFormat,
+    /// Content-addressed thumbnail store with its own size cap
+    pub thumbnail_cache: ThumbnailCache,
+    /// Keep a video's thumbnail when its file is evicted, so the feed still
+    /// has a poster
+    pub keep_thumbnails_on_evict: bool,
+    /// On-demand frames for `/thumbnail?t=`, keyed by (video id, t in tenths of a second)
+    pub frame_cache: Arc<Mutex<HashMap<(String, u64), Bytes>>>,
+    /// Animated previews for `/preview`, keyed by (video id, seconds in tenths, fps)
+    pub preview_cache: Arc<Mutex<HashMap<(String, u64, u32), Bytes>>>,
+    /// Flush + fsync an in-progress download every this many bytes.
+    /// Lower is more durable, higher is less I/O.
+    pub fsync_every_bytes: u64,
+    /// Delete downloaded files older than this regardless of playback position;
+    /// `None` keeps them until the behind/storage limits remove them.
+    pub max_file_age: Option<Duration>,
+    /// Completed downloads smaller than this are treated as failures (an empty
+    /// or truncated body from a misbehaving host), deleted and retried.
+    pub min_valid_bytes: u64,
+    /// Abort a download if no bytes arrive for this long.
+    pub download_stall_timeout: Duration,
+    /// Total download bandwidth across all transfers, e.g. on a metered
+    /// connection; `None` is unlimited.
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Enforces `max_download_bytes_per_sec`, shared by every download.
+    pub download_limiter: Arc<RateLimiter>,
+    /// Retries of one URL after a transient failure (5xx, 429, network error,
+    /// stall), with exponential backoff, before moving on to the next URL.
+    pub max_retries: u32,
+    /// Command run after each completed download (post-processing, notifications, ...).
+    /// Receives the video id and file path as arguments, metadata as `TOKSTR_*` env vars.
+    pub on_complete: Option<PathBuf>,
+    /// Where the session (videos, playlists, position) is persisted; `None` disables it.
+    pub snapshot_path: Option<PathBuf>,
+    /// Sidecar holding just the ordered download queue, for a fast restart; `None` disables it.
+    pub queue_snapshot_path: Option<PathBuf>,
+    /// The active playlist and its position, also written on shutdown so the
+    /// place survives the app being killed; `None` disables it.
+    pub playlist_path: Option<PathBuf>,
+    /// Set once a relay is connected and the first discovery pass has run.
+    /// Until then an empty video list means "still connecting", not "no content".
+    pub ready: Arc<AtomicBool>,
+    /// Progress, discovery and playlist changes, streamed by `/events` and `/ws`.
+    pub events: broadcast::Sender<StateEvent>,
+
+    /// Go idle (relays disconnected, no new downloads) after this long without
+    /// user interaction; `None` never goes idle on its own.
+    pub idle_timeout: Arc<Mutex<Option<Duration>>>,
+    /// Last `touch()` from a user-facing handler
+    pub last_activity: Arc<Mutex<Instant>>,
+    pub idle: Arc<AtomicBool>,
 }
 
 impl AppState {
@@ -31,19 +157,382 @@ impl AppState {
         max_parallel_downloads: usize,
         max_behind_seconds: u64,
         max_storage_bytes: u64,
+        target_minutes_ahead: f64,
+        target_videos_ahead: usize,
     ) -> Self {
         Self {
             content_discovery: Arc::new(content_discovery),
-            discovered_videos: Arc::new(Mutex::new(HashMap::new())),
+            discovered_videos: Arc::new(RwLock::new(HashMap::new())),
             current_index: Arc::new(Mutex::new(0)),
-            playlist: Arc::new(Mutex::new(Playlist::new())),
-            max_parallel_downloads,
-            max_behind_seconds,
-            target_minutes_ahead: 60.0,
-            target_videos_ahead: 15,
-            max_storage_bytes,
+            playlists: Arc::new(Mutex::new(HashMap::from([(
+                DEFAULT_PLAYLIST.to_string(),
+                Playlist::new(),
+            )]))),
+            active_playlist: Arc::new(Mutex::new(DEFAULT_PLAYLIST.to_string())),
+            favorites: Arc::new(Mutex::new(HashSet::new())),
+            max_parallel_downloads: Arc::new(AtomicUsize::new(max_parallel_downloads)),
+            max_behind_seconds: Arc::new(AtomicU64::new(max_behind_seconds)),
+            target_minutes_ahead: Arc::new(Mutex::new(target_minutes_ahead)),
+            target_videos_ahead: Arc::new(AtomicUsize::new(target_videos_ahead)),
+            prefetch_next: Arc::new(AtomicUsize::new(3)),
+            max_downloads_per_host: 2,
+            warm_start_count: 3,
+            max_discovered: 5000,
+            min_width: 0,
+            min_height: 0,
+            allow_unknown_resolution: true,
+            min_length_seconds: None,
+            max_length_seconds: None,
+            allow_unknown_length: true,
+            metered: false,
+            sort_by_published: false,
+            assumed_bitrate_bps: 2_000_000,
+            download_dir: std::env::temp_dir(),
+            max_storage_bytes: Arc::new(AtomicU64::new(max_storage_bytes)),
+            min_free_disk_bytes: 100 * 1024 * 1024,
             current_storage_bytes: Arc::new(Mutex::new(0)),
+            store: Arc::new(LocalFsStore),
+            manager_wake: Arc::new(Notify::new()),
+            generate_thumbnails: true,
+            thumbnail_format: ThumbFormat::default(),
+            thumbnail_cache: ThumbnailCache::new(
+                std::env::temp_dir().join("tokstr_thumbnails"),
+                DEFAULT_MAX_THUMBNAIL_BYTES,
+            ),
+            keep_thumbnails_on_evict: true,
+            frame_cache: Arc::new(Mutex::new(HashMap::new())),
+            preview_cache: Arc::new(Mutex::new(HashMap::new())),
+            fsync_every_bytes: 4 * 1024 * 1024,
+            max_file_age: None,
+            min_valid_bytes: 4 * 1024,
+            download_stall_timeout: Duration::from_secs(30),
+            max_download_bytes_per_sec: None,
+            download_limiter: Arc::new(RateLimiter::new()),
+            max_retries: 3,
+            on_complete: None,
+            snapshot_path: Some(std::env::temp_dir().join("tokstr_snapshot.json")),
+            queue_snapshot_path: Some(std::env::temp_dir().join("tokstr_queue.json")),
+            playlist_path: Some(std::env::temp_dir().join("tokstr_playlist.json")),
+            ready: Arc::new(AtomicBool::new(false)),
+            events: broadcast::channel(STATE_EVENT_CAPACITY).0,
+            idle_timeout: Arc::new(Mutex::new(None)),
+            last_activity: Arc::new(Mutex::new(Instant::now())),
+            idle: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// State for `config`'s settings; everything it doesn't cover keeps the
+    /// defaults of `new`.
+    pub fn from_config(content_discovery: ContentDiscovery, config: &Config) -> Self {
+        let mut state = Self::new(
+            content_discovery,
+            config.max_parallel_downloads,
+            config.max_behind_seconds,
+            config.max_storage_bytes,
+            config.target_minutes_ahead,
+            config.target_videos_ahead,
+        );
+        if let Some(dir) = &config.download_dir {
+            state.set_download_dir(dir.clone());
+        }
+        state
+    }
+
+    /// Keep videos, thumbnails and the persisted session under `dir` instead
+    /// of the temp dir (which e.g. Android clears aggressively). Call before
+    /// the state is shared; the directory is created by `ensure_download_dir`.
+    pub fn set_download_dir(&mut self, dir: PathBuf) {
+        self.thumbnail_cache = ThumbnailCache::new(dir.join("tokstr_thumbnails"), DEFAULT_MAX_THUMBNAIL_BYTES);
+        self.snapshot_path = Some(dir.join("tokstr_snapshot.json"));
+        self.queue_snapshot_path = Some(dir.join("tokstr_queue.json"));
+        self.playlist_path = Some(dir.join("tokstr_playlist.json"));
+        self.download_dir = dir;
+    }
+
+    /// Create `download_dir` if it doesn't exist yet.
+    pub async fn ensure_download_dir(&self) -> std::io::Result<()> {
+        tokio::fs::create_dir_all(&self.download_dir).await
+    }
+
+    /// Tell listeners about `video`'s download progress. A no-op when nobody
+    /// is listening.
+    pub fn publish_progress(&self, video: &VideoDownload) {
+        self.publish(StateEvent::Progress(ProgressEvent::from(video)));
+    }
+
+    /// Send `event` to every `/events` and `/ws` listener, if any.
+    pub fn publish(&self, event: StateEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Tell listeners about the current length and position of `name`.
+    fn publish_playlist(&self, name: &str, playlist: &Playlist) {
+        self.publish(StateEvent::PlaylistChanged {
+            playlist: name.to_string(),
+            len: playlist.len(),
+            position: playlist.position(),
+        });
+    }
+
+    /// Persist the current session to `snapshot_path`.
+    pub async fn save_snapshot(&self) {
+        let Some(path) = &self.snapshot_path else {
+            return;
+        };
+        if let Err(e) = StateSnapshot::capture(self).await.save(path).await {
+            warn!("Failed to save state snapshot to {}: {e}", path.display());
+        }
+    }
+
+    /// Restore the previous session from `snapshot_path`, including the
+    /// playback position of every playlist, then the active playlist from
+    /// `playlist_path`. Called once when the download manager starts.
+    pub async fn resume_position(&self) {
+        if let Some(path) = &self.snapshot_path {
+            match StateSnapshot::load(path).await {
+                Ok(Some(snapshot)) => snapshot.restore(self).await,
+                Ok(None) => {}
+                Err(e) => warn!("Failed to load state snapshot from {}: {e}", path.display()),
+            }
+        }
+        self.load_playlist().await;
+    }
+
+    /// Persist the active playlist to `playlist_path`.
+    pub async fn save_playlist(&self) {
+        let Some(path) = &self.playlist_path else {
+            return;
+        };
+        let snapshot = self.playlist().await.snapshot();
+        if let Err(e) = snapshot.save(path).await {
+            warn!("Failed to save playlist to {}: {e}", path.display());
+        }
+    }
+
+    /// Load the playlist written by `save_playlist`. It replaces the playlist
+    /// with the same id, or else the active one, and being written at
+    /// shutdown it is never older than the session snapshot.
+    async fn load_playlist(&self) {
+        let Some(path) = &self.playlist_path else {
+            return;
+        };
+        let playlist = match Playlist::load_from(path, self.store.as_ref()).await {
+            Ok(Some(playlist)) => playlist,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to load playlist from {}: {e}", path.display());
+                return;
+            }
+        };
+        let active = self.active_playlist.lock().await.clone();
+        let mut playlists = self.playlists.lock().await;
+        let name = playlists
+            .iter()
+            .find(|(_, p)| p.id() == playlist.id())
+            .map_or(active.clone(), |(name, _)| name.clone());
+        if name == active {
+            *self.current_index.lock().await = playlist.position().unwrap_or(0);
+        }
+        info!("Loaded playlist {:?} with {} items", name, playlist.len());
+        playlists.insert(name, playlist);
+    }
+
+    /// Write everything that should survive a restart. Call before exiting.
+    pub async fn shutdown(&self) {
+        self.save_snapshot().await;
+        self.save_playlist().await;
+    }
+
+    /// Pin a video. Returns `false` if it already was.
+    pub async fn add_favorite(&self, id: &str) -> bool {
+        let added = self.favorites.lock().await.insert(id.to_string());
+        if added {
+            self.manager_wake.notify_one();
+        }
+        added
+    }
+
+    /// Unpin a video. Returns `false` if it wasn't pinned.
+    pub async fn remove_favorite(&self, id: &str) -> bool {
+        self.favorites.lock().await.remove(id)
+    }
+
+    /// Flip a video's pin; returns whether it is now a favorite.
+    pub async fn toggle_favorite(&self, id: &str) -> bool {
+        if self.remove_favorite(id).await {
+            false
+        } else {
+            self.add_favorite(id).await
+        }
+    }
+
+    pub async fn is_favorite(&self, id: &str) -> bool {
+        self.favorites.lock().await.contains(id)
+    }
+
+    /// Record user interaction. Resumes from idle if needed.
+    pub async fn touch(&self) {
+        *self.last_activity.lock().await = Instant::now();
+        if self.is_idle() {
+            self.go_active().await;
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle.load(Ordering::Relaxed)
+    }
+
+    /// Whether `idle_timeout` has passed since the last interaction.
+    pub async fn idle_timed_out(&self) -> bool {
+        match *self.idle_timeout.lock().await {
+            Some(timeout) => self.last_activity.lock().await.elapsed() >= timeout,
+            None => false,
+        }
+    }
+
+    /// Disconnect from the relays and stop starting downloads. Running
+    /// downloads are left to finish.
+    pub async fn go_idle(&self) {
+        if !self.idle.swap(true, Ordering::Relaxed) {
+            info!("Going idle: pausing discovery and downloads");
+            self.content_discovery.pause().await;
+        }
+    }
+
+    /// Reconnect and resume downloading. Also counts as interaction, so an
+    /// expired `idle_timeout` doesn't immediately send us back to idle.
+    pub async fn go_active(&self) {
+        *self.last_activity.lock().await = Instant::now();
+        if self.idle.swap(false, Ordering::Relaxed) {
+            info!("Resuming from idle");
+            self.content_discovery.resume().await;
+        }
+        self.manager_wake.notify_one();
+    }
+
+    /// Whether a video passes the `min_width`/`min_height` filter.
+    pub fn meets_min_resolution(&self, video: &VideoDownload) -> bool {
+        match (video.width, video.height) {
+            (Some(w), Some(h)) => w >= self.min_width && h >= self.min_height,
+            _ => self.allow_unknown_resolution,
+        }
+    }
+
+    /// Whether a video passes the `min_length_seconds`/`max_length_seconds` filter.
+    pub fn meets_length_limits(&self, video: &VideoDownload) -> bool {
+        match video.length_seconds {
+            Some(length) => {
+                self.min_length_seconds.map_or(true, |min| length >= min)
+                    && self.max_length_seconds.map_or(true, |max| length <= max)
+            }
+            None => self.allow_unknown_length,
+        }
+    }
+
+    /// Lock and return the active playlist. Convenience path for callers that
+    /// only care about "the" playlist.
+    pub async fn playlist(&self) -> MappedMutexGuard<'_, Playlist> {
+        let active = self.active_playlist.lock().await.clone();
+        MutexGuard::map(self.playlists.lock().await, |playlists| {
+            playlists.entry(active).or_insert_with(Playlist::new)
+        })
+    }
+
+    /// Move the watch position to `index`, keeping the active playlist's own
+    /// position (used by `current`/`next`/`prev`) in step. Returns `false`,
+    /// changing nothing, if the playlist has no item at `index`.
+    pub async fn set_index(&self, index: usize) -> bool {
+        let active = self.active_playlist.lock().await.clone();
+        let mut playlist = self.playlist().await;
+        if index >= playlist.len() {
+            return false;
+        }
+        playlist.set_position(index);
+        self.publish_playlist(&active, &playlist);
+        *self.current_index.lock().await = index;
+        true
+    }
+
+    /// Create an empty playlist under `name`. Returns `false` if it already exists.
+    pub async fn create_playlist(&self, name: &str) -> bool {
+        let mut playlists = self.playlists.lock().await;
+        if playlists.contains_key(name) {
+            return false;
+        }
+        let playlist = Playlist::new();
+        self.publish_playlist(name, &playlist);
+        playlists.insert(name.to_string(), playlist);
+        true
+    }
+
+    /// Make `name` the active playlist. The watch index follows the playlist's
+    /// own position. Returns `false` if no such playlist exists.
+    pub async fn switch_playlist(&self, name: &str) -> bool {
+        let position = {
+            let playlists = self.playlists.lock().await;
+            match playlists.get(name) {
+                Some(playlist) => {
+                    self.publish_playlist(name, playlist);
+                    playlist.position()
+                }
+                None => return false,
+            }
+        };
+        *self.active_playlist.lock().await = name.to_string();
+        *self.current_index.lock().await = position.unwrap_or(0);
+        true
+    }
+
+    /// Names of all playlists, sorted.
+    pub async fn playlist_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.playlists.lock().await.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Remove the given videos from every playlist, keeping the watch index
+    /// on the same item of the active one.
+    pub async fn remove_from_playlists(&self, ids: &[String]) {
+        let active = self.active_playlist.lock().await.clone();
+        let mut playlists = self.playlists.lock().await;
+        for (name, playlist) in playlists.iter_mut() {
+            let mut changed = false;
+            for id in ids {
+                changed |= playlist.remove(id);
+            }
+            if changed {
+                self.publish_playlist(name, playlist);
+            }
+        }
+        if let Some(playlist) = playlists.get(&active) {
+            *self.current_index.lock().await = playlist.position().unwrap_or(0);
+        }
+    }
+
+    /// Append a video to the named playlist, creating the playlist if needed.
+    pub async fn add_to_playlist(&self, name: &str, video: VideoDownload) {
+        let mut playlists = self.playlists.lock().await;
+        let playlist = playlists.entry(name.to_string()).or_insert_with(Playlist::new);
+        let len = playlist.len();
+        playlist.add(video);
+        if playlist.len() != len {
+            self.publish_playlist(name, playlist);
         }
     }
 }
 
+#[cfg(test)]
+impl AppState {
+    /// Offline state keeping its videos in `store`, with nothing persisted and
+    /// no thumbnails, for unit tests.
+    pub(crate) fn for_test(store: Arc<dyn VideoStore>) -> Self {
+        let mut state = Self::new(ContentDiscovery::offline(), 2, 60, 1 << 30, 0.0, 0);
+        state.store = store;
+        state.download_dir = PathBuf::from("downloads");
+        state.generate_thumbnails = false;
+        state.snapshot_path = None;
+        state.queue_snapshot_path = None;
+        state.playlist_path = None;
+        state
+    }
+}
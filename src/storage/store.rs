@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// A writer for an object being stored.
+#[async_trait]
+pub trait VideoWriter: AsyncWrite + Send + Unpin {
+    /// Make everything written so far durable.
+    async fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Where downloaded videos live. The download pipeline, the range handler and
+/// eviction all go through this, so the bytes can sit on local disk or in an
+/// object store. Objects are addressed by the `local_path` recorded on the video.
+#[async_trait]
+pub trait VideoStore: Send + Sync + std::fmt::Debug {
+    /// Create (or truncate) `key` and return a writer for it.
+    async fn put(&self, key: &Path) -> io::Result<Box<dyn VideoWriter>>;
+
+    /// Continue writing `key` at `offset`, dropping anything stored after it.
+    /// Used to resume an interrupted download from its last checkpoint.
+    async fn reopen(&self, key: &Path, offset: u64) -> io::Result<Box<dyn VideoWriter>>;
+
+    /// Read `len` bytes of `key` starting at `start`.
+    async fn get_range(
+        &self,
+        key: &Path,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>>;
+
+    /// Current size of `key` in bytes.
+    async fn len(&self, key: &Path) -> io::Result<u64>;
+
+    /// Remove `key`. Removing a missing object is not an error.
+    async fn delete(&self, key: &Path) -> io::Result<()>;
+
+    async fn exists(&self, key: &Path) -> bool;
+
+    /// When `key` was last written to, for `Last-Modified`/`If-Range`.
+    /// Stores that don't track it report `Unsupported`.
+    async fn modified(&self, _key: &Path) -> io::Result<SystemTime> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Free bytes where objects under `dir` are written. Stores without a
+    /// meaningful limit report `Unsupported`.
+    async fn available_space(&self, _dir: &Path) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Read all of `key` into memory.
+    async fn read_all(&self, key: &Path) -> io::Result<Vec<u8>> {
+        let len = self.len(key).await?;
+        let mut reader = self.get_range(key, 0, len).await?;
+        let mut data = Vec::with_capacity(len as usize);
+        reader.read_to_end(&mut data).await?;
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl VideoWriter for File {
+    async fn sync(&mut self) -> io::Result<()> {
+        self.flush().await?;
+        self.sync_data().await
+    }
+}
+
+/// The default store: plain files on the local filesystem.
+#[derive(Debug, Default, Clone)]
+pub struct LocalFsStore;
+
+#[async_trait]
+impl VideoStore for LocalFsStore {
+    async fn put(&self, key: &Path) -> io::Result<Box<dyn VideoWriter>> {
+        Ok(Box::new(File::create(key).await?))
+    }
+
+    async fn reopen(&self, key: &Path, offset: u64) -> io::Result<Box<dyn VideoWriter>> {
+        let mut file = fs::OpenOptions::new().write(true).open(key).await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn get_range(
+        &self,
+        key: &Path,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let mut file = File::open(key).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Box::new(file.take(len)))
+    }
+
+    async fn len(&self, key: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(key).await?.len())
+    }
+
+    async fn delete(&self, key: &Path) -> io::Result<()> {
+        match fs::remove_file(key).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    async fn exists(&self, key: &Path) -> bool {
+        fs::try_exists(key).await.unwrap_or(false)
+    }
+
+    async fn modified(&self, key: &Path) -> io::Result<SystemTime> {
+        fs::metadata(key).await?.modified()
+    }
+
+    async fn available_space(&self, dir: &Path) -> io::Result<u64> {
+        let dir = dir.to_path_buf();
+        tokio::task::spawn_blocking(move || fs2::available_space(dir))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// Keeps every object in memory. Nothing touches the filesystem, which makes
+/// the download loop (chunking, progress/speed accounting, budget enforcement)
+/// deterministic to exercise; swap it in via `AppState::store`.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    objects: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of everything written to `key` so far.
+    pub fn contents(&self, key: &Path) -> Option<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned()
+    }
+
+    /// Keys of every object stored.
+    pub fn keys(&self) -> Vec<PathBuf> {
+        self.objects.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+/// Appends straight into the owning `MemoryStore`'s buffer, so readers see
+/// bytes as soon as they are written, like a file being downloaded into.
+struct MemoryWriter {
+    key: PathBuf,
+    objects: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut objects = self.objects.lock().unwrap();
+        match objects.get_mut(&self.key) {
+            Some(data) => {
+                data.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            // Deleted while being written.
+            None => Poll::Ready(Err(io::ErrorKind::NotFound.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl VideoWriter for MemoryWriter {
+    async fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VideoStore for MemoryStore {
+    async fn put(&self, key: &Path) -> io::Result<Box<dyn VideoWriter>> {
+        self.objects.lock().unwrap().insert(key.to_path_buf(), Vec::new());
+        Ok(Box::new(MemoryWriter {
+            key: key.to_path_buf(),
+            objects: self.objects.clone(),
+        }))
+    }
+
+    async fn reopen(&self, key: &Path, offset: u64) -> io::Result<Box<dyn VideoWriter>> {
+        let mut objects = self.objects.lock().unwrap();
+        let data = objects.get_mut(key).ok_or(io::ErrorKind::NotFound)?;
+        data.truncate(offset as usize);
+        Ok(Box::new(MemoryWriter {
+            key: key.to_path_buf(),
+            objects: self.objects.clone(),
+        }))
+    }
+
+    async fn get_range(
+        &self,
+        key: &Path,
+        start: u64,
+        len: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let objects = self.objects.lock().unwrap();
+        let data = objects.get(key).ok_or(io::ErrorKind::NotFound)?;
+        let start = (start as usize).min(data.len());
+        let end = start.saturating_add(len as usize).min(data.len());
+        Ok(Box::new(Cursor::new(data[start..end].to_vec())))
+    }
+
+    async fn len(&self, key: &Path) -> io::Result<u64> {
+        let objects = self.objects.lock().unwrap();
+        let data = objects.get(key).ok_or(io::ErrorKind::NotFound)?;
+        Ok(data.len() as u64)
+    }
+
+    async fn delete(&self, key: &Path) -> io::Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn exists(&self, key: &Path) -> bool {
+        self.objects.lock().unwrap().contains_key(key)
+    }
+}
@@ -0,0 +1,81 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::fs;
+
+use crate::utils::utils::ThumbFormat;
+
+/// Generated thumbnails, stored under the video's content hash so they
+/// survive restarts and are shared by every entry for the same file. Has its
+/// own size cap, separate from the video storage budget.
+#[derive(Debug, Clone)]
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        Self { dir, max_bytes }
+    }
+
+    /// Where the thumbnail for `hash` in `format` lives (whether or not it exists yet).
+    pub fn path_for(&self, hash: &str, format: ThumbFormat) -> PathBuf {
+        self.dir.join(format!("{}.{}", hash, format.extension()))
+    }
+
+    /// An existing thumbnail for `hash`, preferring `format` but accepting any.
+    pub async fn find(&self, hash: &str, format: ThumbFormat) -> Option<PathBuf> {
+        for candidate in [format, ThumbFormat::default(), ThumbFormat::Png] {
+            let path = self.path_for(hash, candidate);
+            if fs::try_exists(&path).await.unwrap_or(false) {
+                return Some(path);
+            }
+        }
+        None
+    }
+
+    pub async fn ensure_dir(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.dir).await
+    }
+
+    /// Delete the least recently written thumbnails until the cache fits in
+    /// `max_bytes`. Returns the removed paths.
+    pub async fn enforce_cap(&self) -> io::Result<Vec<PathBuf>> {
+        let mut entries: Vec<(SystemTime, u64, PathBuf)> = Vec::new();
+        let mut total = 0u64;
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total += metadata.len();
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((modified, metadata.len(), entry.path()));
+        }
+        if total <= self.max_bytes {
+            return Ok(Vec::new());
+        }
+
+        entries.sort_by_key(|(modified, _, _)| *modified);
+        let mut removed = Vec::new();
+        for (_, len, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            remove(&path).await?;
+            total = total.saturating_sub(len);
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+
+/// Remove one thumbnail. A missing file is not an error.
+pub async fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
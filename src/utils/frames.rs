@@ -0,0 +1,94 @@
+//! Thin wrappers over `ffmpeg-extractor` so the rest of the crate compiles with
+//! or without the `ffmpeg` feature.
+
+#[cfg(feature = "ffmpeg")]
+pub use ffmpeg_extractor::ExtractedFrame;
+
+/// A JPEG-encoded frame and its dimensions (mirrors `ffmpeg_extractor::ExtractedFrame`).
+#[cfg(not(feature = "ffmpeg"))]
+#[derive(Debug, Clone)]
+pub struct ExtractedFrame {
+    pub jpeg: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: i32,
+}
+
+#[cfg(feature = "ffmpeg")]
+pub fn extract_first_frame_to_jpeg(video_data: &[u8]) -> Result<ExtractedFrame, String> {
+    ffmpeg_extractor::extract_first_frame_to_jpeg(video_data)
+}
+
+/// `extract_first_frame_to_jpeg` on tokio's blocking pool, so the decode
+/// doesn't stall an async worker.
+pub async fn extract_first_frame_to_jpeg_async(video_data: Vec<u8>) -> Result<ExtractedFrame, String> {
+    tokio::task::spawn_blocking(move || extract_first_frame_to_jpeg(&video_data))
+        .await
+        .map_err(|join_err| format!("spawn_blocking join error: {join_err}"))?
+}
+
+#[cfg(feature = "ffmpeg")]
+pub fn extract_frame_at_timestamp(video_data: &[u8], seconds: f64) -> Result<Vec<u8>, String> {
+    ffmpeg_extractor::extract_frame_at_timestamp(video_data, seconds)
+}
+
+/// Animated GIF of the first `seconds` of the video at `fps`.
+#[cfg(feature = "ffmpeg")]
+pub fn extract_animated_preview(video_data: &[u8], seconds: f64, fps: u32) -> Result<Vec<u8>, String> {
+    ffmpeg_extractor::extract_animated_preview(video_data, seconds, fps)
+}
+
+/// Scrub-bar sprite sheet: `cols * rows` frames at evenly spaced timestamps,
+/// each scaled to `cell_width` pixels wide (keeping the aspect ratio), laid out
+/// row by row in one JPEG. Fails if the video has fewer frames than cells.
+#[cfg(feature = "ffmpeg")]
+pub fn extract_sprite_sheet(video_data: &[u8], cols: u32, rows: u32, cell_width: u32) -> Result<Vec<u8>, String> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::RgbImage;
+
+    if cols == 0 || rows == 0 || cell_width == 0 {
+        return Err("cols, rows and cell_width must be positive".into());
+    }
+    let cells = cols.checked_mul(rows).ok_or("too many cells")?;
+    let info = ffmpeg_extractor::probe_video(video_data)?;
+    if info.duration_seconds <= 0.0 {
+        return Err("video length unknown".into());
+    }
+    if info.frame_count > 0 && info.frame_count < cells as u64 {
+        return Err(format!("video has {} frames, fewer than {} cells", info.frame_count, cells));
+    }
+
+    // Middle of each slice, so the last cell isn't past the final frame.
+    let timestamps: Vec<f64> = (0..cells)
+        .map(|i| info.duration_seconds * (i as f64 + 0.5) / cells as f64)
+        .collect();
+    // One decoding pass fills every cell, already scaled, in raw pixels.
+    let raw = ffmpeg_extractor::extract_sprite_sheet(video_data, &timestamps, cols, rows, cell_width)?;
+    let sheet = RgbImage::from_raw(raw.width, raw.height, raw.pixels).ok_or("sprite sheet size mismatch")?;
+
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, 80)
+        .encode_image(&sheet)
+        .map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn extract_first_frame_to_jpeg(_video_data: &[u8]) -> Result<ExtractedFrame, String> {
+    Err("built without the `ffmpeg` feature".into())
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn extract_frame_at_timestamp(_video_data: &[u8], _seconds: f64) -> Result<Vec<u8>, String> {
+    Err("built without the `ffmpeg` feature".into())
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn extract_animated_preview(_video_data: &[u8], _seconds: f64, _fps: u32) -> Result<Vec<u8>, String> {
+    Err("built without the `ffmpeg` feature".into())
+}
+
+#[cfg(not(feature = "ffmpeg"))]
+pub fn extract_sprite_sheet(_video_data: &[u8], _cols: u32, _rows: u32, _cell_width: u32) -> Result<Vec<u8>, String> {
+    Err("built without the `ffmpeg` feature".into())
+}
@@ -1,2 +1,3 @@
 pub mod utils;
 pub mod log;
+pub mod frames;
@@ -4,33 +4,86 @@ use std::io::BufWriter;
 use std::net::TcpListener;
 use std::path::Path;
 use image::codecs::jpeg::JpegEncoder;
-use image::{ColorType, ExtendedColorType};
+use image::codecs::png::PngEncoder;
+use image::{ColorType, ExtendedColorType, ImageEncoder};
 use log::error;
 
-pub(crate) fn write_image_to_jpeg(
+/// Encoding used for stored thumbnails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThumbFormat {
+    /// JPEG at the given quality (1-100).
+    Jpeg(u8),
+    /// Lossless PNG; better for sharp text/overlays, keeps transparency.
+    Png,
+}
+
+impl Default for ThumbFormat {
+    fn default() -> Self {
+        ThumbFormat::Jpeg(80)
+    }
+}
+
+impl ThumbFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg(_) => "jpg",
+            ThumbFormat::Png => "png",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ThumbFormat::Jpeg(_) => "image/jpeg",
+            ThumbFormat::Png => "image/png",
+        }
+    }
+
+    /// Guess the format of a stored thumbnail from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(ThumbFormat::default()),
+            "png" => Some(ThumbFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+/// Decode `image_data` and write it to `output_path` encoded as `format`.
+/// The caller picks the path; use `format.extension()` to match it.
+pub(crate) fn write_image(
     image_data: &[u8],
     output_path: &Path,
+    format: ThumbFormat,
 ) -> Result<()> {
     // Decode the `image_data` into a DynamicImage:
     let dynamic_image = image::load_from_memory(image_data)?;
 
-    // Convert DynamicImage to RGB8:
-    let rgb_image = dynamic_image.to_rgb8();
-
     // Create output file/buffer:
     let file = File::create(output_path)?;
     let buf_writer = BufWriter::new(file);
 
-    // Initialize JPEG encoder with quality = 80:
-    let mut encoder = JpegEncoder::new_with_quality(buf_writer, 80);
-
-    // Encode the RGB image into the file:
-    encoder.encode(
-        &rgb_image,
-        rgb_image.width(),
-        rgb_image.height(),
-        ExtendedColorType::from(ColorType::Rgb8),
-    )?;
+    match format {
+        ThumbFormat::Jpeg(quality) => {
+            // JPEG has no alpha channel:
+            let rgb_image = dynamic_image.to_rgb8();
+            let mut encoder = JpegEncoder::new_with_quality(buf_writer, quality);
+            encoder.encode(
+                &rgb_image,
+                rgb_image.width(),
+                rgb_image.height(),
+                ExtendedColorType::from(ColorType::Rgb8),
+            )?;
+        }
+        ThumbFormat::Png => {
+            let rgba_image = dynamic_image.to_rgba8();
+            PngEncoder::new(buf_writer).write_image(
+                &rgba_image,
+                rgba_image.width(),
+                rgba_image.height(),
+                ExtendedColorType::from(ColorType::Rgba8),
+            )?;
+        }
+    }
 
     Ok(())
 }
@@ -52,4 +105,12 @@ pub fn find_available_port() -> Result<TcpListener> {
         }
     }
     Err(anyhow::anyhow!("No available ports found"))
-}
\ No newline at end of file
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}